@@ -0,0 +1,62 @@
+// the C api of brainfuck-optimiser, built as libbrainfuck_optimiser.a or .so by `cargo build --release`
+// linking the static library also needs -lpthread -ldl -lm
+#ifndef BRAINFUCK_OPTIMISER_H
+#define BRAINFUCK_OPTIMISER_H
+
+#include <stdint.h>
+
+#ifdef __cplusplus
+extern "C" {
+#endif
+
+typedef enum bf_status {
+	BF_OK = 0,
+	BF_NULL_POINTER = 1,
+	BF_INVALID_UTF8 = 2,
+	BF_PARSE_ERROR = 3,
+	BF_NON_HALTING = 4, // the optimised program aborted a loop that would never halt
+	BF_PANIC = 5
+} bf_status;
+
+// opaque handles, each freed by its own function
+typedef struct BFProgram bf_program;
+typedef struct BFOptimised bf_optimised;
+typedef struct BFTape bf_tape;
+
+// input and output, each given the user data passed along with them
+typedef uint8_t (*bf_ask)(void *user_data);
+typedef void (*bf_put)(void *user_data, uint8_t x);
+
+// parses a nul-terminated program into *program
+// on BF_PARSE_ERROR, *error (unless error is null) is set to a message to free with bf_string_free
+bf_status bf_parse(const char *source, bf_program **program, char **error);
+void bf_program_free(bf_program *program);
+
+// optimises at level 0, 1, or 2 and above, as -O0, -O1 and -O2
+bf_status bf_optimise(const bf_program *program, uint8_t level, bf_optimised **optimised);
+void bf_optimised_free(bf_optimised *optimised);
+
+// the bytecode as the REPL prints it, to free with bf_string_free
+char *bf_optimised_pretty(const bf_optimised *optimised);
+void bf_string_free(char *s);
+
+// the tape and head a program runs on, kept between runs, starting blank with the head at 0
+// reading a null tape gives 0, and changing one does nothing
+bf_tape *bf_tape_new(void);
+void bf_tape_free(bf_tape *tape);
+uint8_t bf_tape_get(const bf_tape *tape, int32_t reg);
+void bf_tape_set(bf_tape *tape, int32_t reg, uint8_t x);
+int32_t bf_tape_head(const bf_tape *tape);
+void bf_tape_move(bf_tape *tape, int32_t reg);
+void bf_tape_clear(bf_tape *tape); // leaves the head where it is
+
+// runs a program without optimising it
+bf_status bf_run(const bf_program *program, bf_tape *tape, bf_ask ask, bf_put put, void *user_data);
+// runs optimised bytecode, giving BF_NON_HALTING when it aborts a loop that would never halt
+bf_status bf_run_optimised(const bf_optimised *optimised, bf_tape *tape, bf_ask ask, bf_put put, void *user_data);
+
+#ifdef __cplusplus
+}
+#endif
+
+#endif
@@ -0,0 +1,109 @@
+// exercises the C api, exiting with 1 and a message on the first thing that's wrong
+#include <stdio.h>
+#include <stdlib.h>
+#include <string.h>
+
+#include "brainfuck_optimiser.h"
+
+#define CHECK(condition) do { \
+	if (!(condition)) { \
+		fprintf(stderr, "%s:%d: %s\n", __FILE__, __LINE__, #condition); \
+		exit(1); \
+	} \
+} while (0)
+
+typedef struct io {
+	const uint8_t *input;
+	size_t input_length, read;
+	uint8_t output[256];
+	size_t written;
+} io;
+
+static uint8_t ask(void *user_data) {
+	io *io = user_data;
+	return io->read < io->input_length ? io->input[io->read++] : 0;
+}
+
+static void put(void *user_data, uint8_t x) {
+	io *io = user_data;
+	if (io->written < sizeof io->output) io->output[io->written++] = x;
+}
+
+// parses, optimises at every level and runs the source on a fresh tape, checking each gives the same output
+static void check_output(const char *source, const char *input, const char *expected) {
+	bf_program *program = NULL;
+	CHECK(bf_parse(source, &program, NULL) == BF_OK);
+	for (int level = -1; level <= 2; level++) {
+		io io = { (const uint8_t *)input, strlen(input), 0, { 0 }, 0 };
+		bf_tape *tape = bf_tape_new();
+		if (level < 0) {
+			CHECK(bf_run(program, tape, ask, put, &io) == BF_OK);
+		} else {
+			bf_optimised *optimised = NULL;
+			CHECK(bf_optimise(program, (uint8_t)level, &optimised) == BF_OK);
+			CHECK(bf_run_optimised(optimised, tape, ask, put, &io) == BF_OK);
+			bf_optimised_free(optimised);
+		}
+		CHECK(io.written == strlen(expected) && memcmp(io.output, expected, io.written) == 0);
+		bf_tape_free(tape);
+	}
+	bf_program_free(program);
+}
+
+int main(void) {
+	check_output("++++++++[>++++[>++>+++>+++>+<<<<-]>+>+>->>+[<]<-]>>.>---.+++++++..+++.>>.<-.<.+++.------.--------.>>+.>++.", "", "Hello World!\n");
+	check_output(",[.,]", "echo", "echo");
+
+	// the tape and head are kept between runs, and can be read and changed
+	bf_program *program = NULL;
+	CHECK(bf_parse(",[->+++<]>", &program, NULL) == BF_OK);
+	bf_optimised *optimised = NULL;
+	CHECK(bf_optimise(program, 2, &optimised) == BF_OK);
+	bf_tape *tape = bf_tape_new();
+	io io = { (const uint8_t *)"\x05", 1, 0, { 0 }, 0 };
+	CHECK(bf_run_optimised(optimised, tape, ask, put, &io) == BF_OK);
+	CHECK(bf_tape_head(tape) == 1);
+	CHECK(bf_tape_get(tape, 0) == 0 && bf_tape_get(tape, 1) == 15);
+	bf_tape_set(tape, 1, 7);
+	bf_tape_move(tape, 0);
+	io.read = 0;
+	CHECK(bf_run_optimised(optimised, tape, ask, put, &io) == BF_OK);
+	CHECK(bf_tape_get(tape, 1) == 22);
+	bf_tape_clear(tape);
+	CHECK(bf_tape_get(tape, 1) == 0 && bf_tape_head(tape) == 1);
+
+	char *pretty = bf_optimised_pretty(optimised);
+	CHECK(pretty != NULL && strstr(pretty, "ask") != NULL);
+	bf_string_free(pretty);
+	bf_optimised_free(optimised);
+	bf_program_free(program);
+
+	// a loop taking an even step off an odd cell never halts
+	CHECK(bf_parse("+.,[-->+<]>.", &program, NULL) == BF_OK);
+	CHECK(bf_optimise(program, 2, &optimised) == BF_OK);
+	bf_tape_clear(tape);
+	io = (struct io){ (const uint8_t *)"\x09", 1, 0, { 0 }, 0 };
+	CHECK(bf_run_optimised(optimised, tape, ask, put, &io) == BF_NON_HALTING);
+	CHECK(io.written == 1 && io.output[0] == 1);
+	bf_optimised_free(optimised);
+	bf_program_free(program);
+	bf_tape_free(tape);
+
+	// errors
+	char *error = NULL;
+	program = NULL;
+	CHECK(bf_parse("+[>+", &program, &error) == BF_PARSE_ERROR);
+	CHECK(program == NULL && error != NULL && strlen(error) > 0);
+	bf_string_free(error);
+	CHECK(bf_parse("\xff", &program, NULL) == BF_INVALID_UTF8);
+	CHECK(bf_parse(NULL, &program, NULL) == BF_NULL_POINTER);
+	CHECK(bf_optimise(NULL, 2, &optimised) == BF_NULL_POINTER);
+	CHECK(bf_run_optimised(NULL, NULL, ask, put, NULL) == BF_NULL_POINTER);
+	bf_tape_set(NULL, 0, 1);
+	bf_tape_move(NULL, 1);
+	bf_tape_clear(NULL);
+	CHECK(bf_tape_get(NULL, 0) == 0 && bf_tape_head(NULL) == 0);
+
+	puts("ok");
+	return 0;
+}
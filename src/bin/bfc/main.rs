@@ -0,0 +1,373 @@
+use std::{cell::{Cell, RefCell}, collections::HashMap, fmt, io::{Read, Write}, process::ExitCode};
+
+use nibbler::errors::show_error;
+use brainfuck_optimiser::{ BFRaw, parser::*, interpreter::*, optimised::{ *, interpreter::*, passes::*, backends::* } };
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn args(s: &str) -> Result<Options, String> {
+        parse_args(s.split_whitespace().map(|arg| arg.to_string()))
+    }
+
+    #[test]
+    fn arguments() {
+        let options = args("program.bf").unwrap();
+        assert_eq!((options.mode, options.level, options.cell_width, options.tape_size, options.eof), (Mode::Run, 2, 8, None, Eof::Zero));
+        let options = args("--emit c -O1 -o out.c program.bf").unwrap();
+        assert_eq!((options.mode, options.level, options.output), (Mode::Emit(Backend::C), 1, Some("out.c".to_string())));
+        let options = args("--run --tape-size 30000 --eof keep --cell-width 16 program.bf").unwrap();
+        assert_eq!((options.mode, options.tape_size, options.eof, options.cell_width), (Mode::Run, Some(30000), Eof::Keep, 16));
+        assert_eq!(args("--help").unwrap().mode, Mode::Help);
+        for bad in [
+            "", "a.bf b.bf", "--emit cobol a.bf", "-O a.bf", "-O9 a.bf", "--cell-width 12 a.bf", "--tape-size 0 a.bf", "--eof never a.bf", "--frobnicate a.bf", "a.bf -o",
+            "--emit c --cell-width 16 a.bf", "--ir --eof max a.bf", "--emit c --tape-size 100 a.bf"
+        ] {
+            assert!(args(bad).is_err(), "{bad} was accepted");
+        }
+    }
+
+    fn run_with(s: &str, options: &str, input: &[u8]) -> (Vec<u8>, bool) {
+        let options = args(& format!("{options} program.bf")).unwrap();
+        let mut output = vec![];
+        let is = parse_program()(&mut TextIter{ iter: s.chars(), line: 0, index: 0 }).ok().unwrap();
+        let halted = run(& is, & options, &mut & input[..], &mut output).unwrap();
+        (output, halted)
+    }
+
+    #[test]
+    fn running() {
+        assert_eq!(run_with(",[.,]", "", b"echo"), (b"echo".to_vec(), true));
+        assert_eq!(run_with("+.,[-->+<]>.", "", &[9]), (vec![1], false));
+        // end of input
+        assert_eq!(run_with("+,.", "", b""), (vec![0], true));
+        assert_eq!(run_with("+,.", "--eof max", b""), (vec![255], true));
+        assert_eq!(run_with("+,.", "--eof keep", b""), (vec![1], true));
+        assert_eq!(run_with("+,.", "--eof keep --cell-width 32", b""), (vec![1], true));
+        // wider cells don't wrap at 256, and output their low byte
+        assert_eq!(run_with("-.+>++++++++[<++++++++++++++++++++++++++++++++>-]<[>+<[-]]>.", "--cell-width 16", b""), (vec![255, 1], true));
+        assert_eq!(run_with("-.+>++++++++[<++++++++++++++++++++++++++++++++>-]<[>+<[-]]>.", "", b""), (vec![255, 0], true));
+        // a small tape wraps around
+        assert_eq!(run_with("+++<.", "--tape-size 1", b""), (vec![3], true));
+        assert_eq!(run_with("+++<.", "--tape-size 1 --cell-width 16", b""), (vec![3], true));
+        assert_eq!(run_with("+++<.", "", b""), (vec![0], true));
+        // one effect would touch registers that share a cell
+        assert_eq!(run_with("+>+<.", "--tape-size 1", b""), (vec![2], true));
+        assert_eq!(run_with("+>+>+>+<<<.", "--tape-size 3", b""), (vec![2], true));
+        let bs = optimising_convert_with(parse_program()(&mut TextIter{ iter: "+>+>+>+<<<.".chars(), line: 0, index: 0 }).ok().unwrap(), & PassManager::with_level(2));
+        assert_eq!(widest_effect(& bs), 3);
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Backend {
+    C,
+    Rust,
+    Asm,
+    Llvm,
+    Wat,
+    Js,
+    Bf
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Mode {
+    Run,
+    Ir,
+    Emit(Backend),
+    Help
+}
+
+// what ',' stores once the input has run out
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Eof {
+    Zero,
+    Max, // every bit set, -1 in the cell's width
+    Keep // the cell is left as it was
+}
+
+#[derive(Debug, Clone)]
+struct Options {
+    mode: Mode,
+    file: Option<String>,
+    output: Option<String>,
+    level: u8,
+    cell_width: u32,
+    tape_size: Option<usize>,
+    eof: Eof
+}
+
+const USAGE: &str = "usage: bfc [options] <file.bf>
+
+  --run                run the program with stdin and stdout (the default)
+  --ir                 print the optimised bytecode
+  --emit <backend>     print the program compiled for a backend: c, rust, asm, llvm, wat, js or bf (minified, ignoring -O)
+  -o <file>            write the bytecode or compiled program to a file instead of stdout
+  -O<level>            optimisation level 0, 1 or 2 (the default)
+  --cell-width <bits>  8 (the default), 16 or 32; wider cells are only run, without optimising
+  --tape-size <cells>  wrap the head around after this many cells, when running (without optimising) or emitting rust
+  --eof <policy>       what ',' stores at the end of input when running: zero (the default), max or keep
+  -h, --help           print this
+
+exit codes: 0 success, 1 bad arguments or i/o error, 2 parse error, 3 aborted non-halting loop";
+
+fn parse_args(
+    mut args: impl Iterator<Item = String>
+)
+    -> Result<Options, String>
+{
+    let mut options = Options{ mode: Mode::Run, file: None, output: None, level: 2, cell_width: 8, tape_size: None, eof: Eof::Zero };
+    while let Some(arg) = args.next() {
+        let mut value = |name: &str| args.next().ok_or(format!("{name} needs a value"));
+        match arg.as_str() {
+            "-h" | "--help" => options.mode = Mode::Help,
+            "--run" => options.mode = Mode::Run,
+            "--ir" => options.mode = Mode::Ir,
+            "--emit" => options.mode = Mode::Emit(match value("--emit")?.as_str() {
+                "c" => Backend::C,
+                "rust" => Backend::Rust,
+                "asm" => Backend::Asm,
+                "llvm" => Backend::Llvm,
+                "wat" => Backend::Wat,
+                "js" => Backend::Js,
+                "bf" => Backend::Bf,
+                other => return Err(format!("unknown backend '{other}'"))
+            }),
+            "-o" => options.output = Some(value("-o")?),
+            "--cell-width" => options.cell_width = match value("--cell-width")?.as_str() {
+                "8" => 8,
+                "16" => 16,
+                "32" => 32,
+                other => return Err(format!("cells can be 8, 16 or 32 bits wide, not '{other}'"))
+            },
+            "--tape-size" => options.tape_size = match value("--tape-size")?.parse::<usize>() {
+                Ok(n) if n > 0 && n <= i32::MAX as usize => Some(n),
+                _ => return Err("the tape size must be a positive number of cells".to_string())
+            },
+            "--eof" => options.eof = match value("--eof")?.as_str() {
+                "zero" => Eof::Zero,
+                "max" => Eof::Max,
+                "keep" => Eof::Keep,
+                other => return Err(format!("unknown eof policy '{other}'"))
+            },
+            level if level.starts_with("-O") => options.level = match level[2..].parse::<u8>() {
+                Ok(n) if n <= 2 => n,
+                _ => return Err(format!("unknown optimisation level '{level}'"))
+            },
+            flag if flag.starts_with('-') => return Err(format!("unknown option '{flag}'")),
+            file => match options.file {
+                None => options.file = Some(file.to_string()),
+                Some(_) => return Err("only one file can be given".to_string())
+            }
+        }
+    }
+    if options.mode == Mode::Help { return Ok(options); }
+    if options.file.is_none() { return Err("no file given".to_string()); }
+    if options.cell_width != 8 && options.mode != Mode::Run {
+        return Err("only 8 bit cells can be optimised or compiled".to_string());
+    }
+    if options.tape_size.is_some() && !matches!(options.mode, Mode::Run | Mode::Emit(Backend::Rust)) {
+        return Err("the tape size can only be set when running or emitting rust".to_string());
+    }
+    if options.eof != Eof::Zero && options.mode != Mode::Run {
+        return Err("compiled programs always store 0 at the end of input".to_string());
+    }
+    Ok(options)
+}
+
+enum Error {
+    Usage(String),
+    Io(String, std::io::Error),
+    Parse(String),
+    NonHalting
+}
+
+impl fmt::Display for Error {
+
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Error::Usage(message) => write!(f, "{message}\n\n{USAGE}"),
+            Error::Io(context, err) => write!(f, "{context}: {err}"),
+            Error::Parse(message) => write!(f, "{message}"),
+            Error::NonHalting => write!(f, "aborted non-halting loop")
+        }
+    }
+}
+
+impl Error {
+    fn exit_code(&self) -> u8 {
+        match self {
+            Error::Usage(_) | Error::Io(..) => 1,
+            Error::Parse(_) => 2,
+            Error::NonHalting => 3
+        }
+    }
+}
+
+// runs the program, giving whether it halted
+// cells of 8 bits run optimised, wider ones and wrapping tapes as they are, as an atomic effect on a tape smaller than
+// the registers it spans would have two registers in one cell
+fn run(
+    is: & Vec<BFRaw>,
+    options: & Options,
+    input: &mut impl Read,
+    output: &mut impl Write
+)
+    -> std::io::Result<bool>
+{
+    let mask = u32::MAX >> (32 - options.cell_width);
+    let address = |i: i32| match options.tape_size {
+        Some(n) => i.rem_euclid(n as i32),
+        None => i
+    };
+    let error = RefCell::new(None);
+    // output is flushed before each read, so prompts show up
+    let output = RefCell::new(output);
+    let mut read = || -> Option<u8> {
+        if let Err(err) = output.borrow_mut().flush() { error.borrow_mut().get_or_insert(err); }
+        let mut byte = [0];
+        match input.read(&mut byte) {
+            Ok(0) => None,
+            Ok(_) => Some(byte[0]),
+            Err(err) => { error.borrow_mut().get_or_insert(err); None }
+        }
+    };
+    let write = |x: u8| if let Err(err) = output.borrow_mut().write_all(& [x]) { error.borrow_mut().get_or_insert(err); };
+    let halted = if options.cell_width == 8 && options.tape_size.is_none() {
+        let memory = RefCell::new(HashMap::<i32, u8>::new());
+        // the interpreters store what ask gives straight away, so keeping the cell is skipping that store
+        let skip_store = Cell::new(false);
+        let mut ctx = BFCtx{
+            index: 0,
+            ask: || read().unwrap_or_else(|| {
+                skip_store.set(options.eof == Eof::Keep);
+                if options.eof == Eof::Max { 255 } else { 0 }
+            }),
+            put: |x| write(x),
+            get: |i| *memory.borrow().get(& i).unwrap_or(&0),
+            set: |i, x| if !skip_store.replace(false) { memory.borrow_mut().insert(i, x); },
+            clear: || memory.borrow_mut().clear()
+        };
+        run_bfoptimised(&mut ctx, optimising_convert_with(is.clone(), & PassManager::with_level(options.level)))
+    } else {
+        // runs everything but loops, which are run here
+        type Step<'a> = dyn FnMut(&mut HashMap<i32, u32>, &mut i32, & BFRaw) -> () + 'a;
+        fn go(is: & Vec<BFRaw>, tape: &mut HashMap<i32, u32>, head: &mut i32, step: &mut Step) -> () {
+            for i in is {
+                match i {
+                    BFRaw::Loop(body) => while *tape.get(head).unwrap_or(&0) != 0 { go(body, tape, head, step) },
+                    _ => step(tape, head, i)
+                }
+            }
+        }
+        let (mut tape, mut head) = (HashMap::<i32, u32>::new(), 0);
+        go(is, &mut tape, &mut head, &mut |tape, head, i| {
+            let cell = tape.entry(*head).or_insert(0);
+            match i {
+                BFRaw::Lft => *head = address(*head - 1),
+                BFRaw::Rgh => *head = address(*head + 1),
+                BFRaw::Inc => *cell = cell.wrapping_add(1) & mask,
+                BFRaw::Dec => *cell = cell.wrapping_sub(1) & mask,
+                BFRaw::Ask => match (read(), options.eof) {
+                    (Some(x), _) => *cell = x as u32,
+                    (None, Eof::Zero) => *cell = 0,
+                    (None, Eof::Max) => *cell = mask,
+                    (None, Eof::Keep) => ()
+                },
+                BFRaw::Put => write(*cell as u8),
+                BFRaw::Loop(_) => unreachable!()
+            }
+        });
+        true
+    };
+    output.borrow_mut().flush()?;
+    match error.into_inner() {
+        Some(err) => Err(err),
+        None => Ok(halted)
+    }
+}
+
+// how many cells apart the furthest two registers one atomic effect touches are
+// a wrapping tape must be wider than this, or the effect would have two registers in one cell
+fn widest_effect(
+    bs: & Vec<OptimisedBlock>
+)
+    -> i32
+{
+    fn extent(expr: & ProcExpr, (lo, hi): (i32, i32)) -> (i32, i32) {
+        match expr {
+            ProcExpr::Lit(_) => (lo, hi),
+            ProcExpr::Reg(r) => (lo.min(*r), hi.max(*r)),
+            ProcExpr::Add(a, b) | ProcExpr::Mul(a, b) | ProcExpr::Into(a, b) => extent(b, extent(a, (lo, hi))),
+            ProcExpr::Choose(a, _) | ProcExpr::IsZero(a) => extent(a, (lo, hi)),
+            ProcExpr::If(c, a, b) => extent(b, extent(a, extent(c, (lo, hi))))
+        }
+    }
+    bs.iter().map(|b| match b {
+        OptimisedBlock::AtomicEffect(lines, _) => lines.iter()
+            .fold(None, |span: Option<(i32, i32)>, (r, expr)| {
+                let (lo, hi) = span.unwrap_or((*r, *r));
+                Some(extent(expr, (lo.min(*r), hi.max(*r))))
+            })
+            .map_or(0, |(lo, hi)| hi - lo),
+        OptimisedBlock::Loop(_, bs_, _) | OptimisedBlock::If(_, bs_) => widest_effect(bs_),
+        OptimisedBlock::Ask(_) | OptimisedBlock::Put(_) => 0
+    }).max().unwrap_or(0)
+}
+
+fn compile(
+    options: & Options
+)
+    -> Result<(), Error>
+{
+    let file = options.file.clone().unwrap_or_default();
+    let source = std::fs::read_to_string(& file).map_err(|err| Error::Io(format!("couldn't read {file}"), err))?;
+    let is = parse_program()(&mut TextIter{ iter: source.chars(), line: 0, index: 0 })
+        .map_err(|err| Error::Parse(format!("{}\n...whilst parsing {file}", show_error("".to_string(), & |info: TextInfo| format!("at {}:{}", info.line, info.index), err))))?;
+
+    let text = match options.mode {
+        Mode::Run => {
+            let halted = run(& is, options, &mut std::io::stdin().lock(), &mut std::io::BufWriter::new(std::io::stdout().lock()))
+                .map_err(|err| Error::Io("couldn't run".to_string(), err))?;
+            return if halted { Ok(()) } else { Err(Error::NonHalting) };
+        },
+        Mode::Help => unreachable!(),
+        Mode::Ir => byte_code_pretty(& optimising_convert_with(is, & PassManager::with_level(options.level))) + "\n",
+        // minifying works from the program itself, optimising it piece by piece
+        Mode::Emit(Backend::Bf) => bf::minify(& is) + "\n",
+        Mode::Emit(backend) => {
+            let bs = optimising_convert_with(is, & PassManager::with_level(options.level));
+            if let Some(size) = options.tape_size.filter(|size| *size as i64 <= widest_effect(& bs) as i64) {
+                return Err(Error::Usage(format!("a tape of {size} cells is too small for this program, which needs more than {} once optimised", widest_effect(& bs))));
+            }
+            match backend {
+                Backend::C => c::emit_c(& bs),
+                Backend::Rust => rust::emit_rust_main(& bs, & options.tape_size.map_or(rust::RustTape::Unbounded, rust::RustTape::Wrapping)),
+                Backend::Asm => gas::emit_gas(& bs),
+                Backend::Llvm => llvm::emit_llvm(& bs),
+                Backend::Wat => wat::emit_wat(& bs),
+                Backend::Js => js::emit_js(& bs),
+                Backend::Bf => unreachable!()
+            }
+        }
+    };
+    match & options.output {
+        Some(path) => std::fs::write(path, text).map_err(|err| Error::Io(format!("couldn't write {path}"), err)),
+        None => std::io::stdout().write_all(text.as_bytes()).map_err(|err| Error::Io("couldn't write".to_string(), err))
+    }
+}
+
+pub fn main() -> ExitCode {
+    let result = parse_args(std::env::args().skip(1)).map_err(Error::Usage).and_then(|options| match options.mode {
+        Mode::Help => writeln!(std::io::stdout(), "{USAGE}").map_err(|err| Error::Io("couldn't write".to_string(), err)),
+        _ => compile(& options)
+    });
+    match result {
+        Ok(()) => ExitCode::SUCCESS,
+        Err(err) => {
+            eprintln!("bfc: {err}");
+            ExitCode::from(err.exit_code())
+        }
+    }
+}
@@ -1,7 +1,7 @@
 use std::{collections::HashMap, sync::Mutex};
 use std::io::Write;
 
-use brainfuck_optimiser::optimised::{ *, interpreter::*, repl::* };
+use brainfuck_optimiser::optimised::{ *, interpreter::*, repl::*, statistics::* };
 
 macro_rules! readln {
     ($s:expr) => {{
@@ -29,6 +29,7 @@ pub fn main() {
         println!(":h           \t\tdisplays this help text");
         println!(":f           \t\tfinds the register of the head");
         println!(":m <register>\t\tmoves the head to a specific register");
+        println!(":s           \t\tdisplays how the last input was optimised");
         println!("<input>      \t\truns the brainfuck code (':' is not a valid comment char)");
         println!("");
     }
@@ -37,7 +38,8 @@ pub fn main() {
         writeln: |s| println!("{s}"),
         write_errln: |s| eprintln!("{s}"),
         display_help: display_help,
-        display_optimisation: |bs: &Vec<OptimisedBlock>| println!("{}", byte_code_pretty(bs))
+        display_optimisation: |bs: &Vec<OptimisedBlock>| println!("{}", byte_code_pretty(bs)),
+        statistics: Statistics::default()
     };
     let memory = Mutex::new(HashMap::<i32, u8>::new());
     let mut ctx = BFCtx{
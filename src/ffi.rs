@@ -0,0 +1,296 @@
+use std::{cell::RefCell, collections::HashMap, ffi::{c_char, c_void, CStr, CString}, panic::{catch_unwind, AssertUnwindSafe}};
+
+use nibbler::errors::show_error;
+use crate::{ BFRaw, parser::*, interpreter::*, optimised::{ OptimisedBlock, byte_code_pretty, passes::*, interpreter::run_bfoptimised } };
+
+#[cfg(test)]
+mod tests {
+    use std::process::{Command, Stdio};
+
+    // builds the static library and ffi/test.c against it, and runs it
+    #[test]
+    fn c_program() {
+        if Command::new("cc").arg("--version").stdout(Stdio::null()).status().is_err() { eprintln!("no C compiler, skipping"); return; }
+        let root = std::path::Path::new(env!("CARGO_MANIFEST_DIR"));
+        // the tests run from <target>/debug/deps, and the library is built into its own target directory so as not to wait on this one
+        let target = std::env::current_exe().unwrap().parent().unwrap().parent().unwrap().parent().unwrap().join("ffi");
+        let status = Command::new(env!("CARGO"))
+            .arg("build").arg("--lib").arg("--offline")
+            .arg("--manifest-path").arg(root.join("Cargo.toml"))
+            .arg("--target-dir").arg(& target)
+            .status().unwrap();
+        assert!(status.success(), "the library didn't build");
+        let library = target.join("debug/libbrainfuck_optimiser.a");
+        let exe = std::env::temp_dir().join(format!("bf-ffi-{}", std::process::id()));
+        let status = Command::new("cc")
+            .arg("-Wall").arg("-Werror")
+            .arg("-I").arg(root.join("ffi"))
+            .arg("-o").arg(& exe)
+            .arg(root.join("ffi/test.c"))
+            .arg(& library)
+            .args(["-lpthread", "-ldl", "-lm"])
+            .status().unwrap();
+        assert!(status.success(), "ffi/test.c didn't compile");
+        let output = Command::new(& exe).output().unwrap();
+        let _ = std::fs::remove_file(& exe);
+        assert!(output.status.success(), "ffi/test.c failed:\n{}", String::from_utf8_lossy(& output.stderr));
+    }
+}
+
+// the results of the C api, mirrored by `bf_status` in ffi/brainfuck_optimiser.h
+#[repr(C)]
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum BFStatus {
+    Ok = 0,
+    NullPointer = 1,
+    InvalidUtf8 = 2,
+    ParseError = 3,
+    NonHalting = 4, // the optimised program aborted a loop that would never halt
+    Panic = 5
+}
+
+// callbacks mirroring `BFCtx`'s ask and put, each given the user data passed along with them
+pub type BFAsk = extern "C" fn(user_data: *mut c_void) -> u8;
+pub type BFPut = extern "C" fn(user_data: *mut c_void, x: u8) -> ();
+
+pub struct BFProgram(Vec<BFRaw>);
+
+pub struct BFOptimised(Vec<OptimisedBlock>);
+
+// the tape and head a program runs on, kept between runs as in the REPL
+pub struct BFTape {
+    index: i32,
+    memory: HashMap<i32, u8>
+}
+
+enum Runnable<'a> {
+    Raw(&'a Vec<BFRaw>),
+    Optimised(&'a Vec<OptimisedBlock>)
+}
+
+// runs a program on the tape, returning whether it halted
+fn run_on_tape(
+    tape: &mut BFTape,
+    ask: BFAsk,
+    put: BFPut,
+    user_data: *mut c_void,
+    program: Runnable
+)
+    -> bool
+{
+    let memory = RefCell::new(std::mem::take(&mut tape.memory));
+    let mut ctx = BFCtx{
+        index: tape.index,
+        ask: || ask(user_data),
+        put: |x| put(user_data, x),
+        get: |i| *memory.borrow().get(& i).unwrap_or(&0),
+        set: |i, x| { memory.borrow_mut().insert(i, x); },
+        clear: || memory.borrow_mut().clear()
+    };
+    let halted = match program {
+        Runnable::Raw(is) => { run_bfraw(&mut ctx, is); true },
+        Runnable::Optimised(bs) => run_bfoptimised(&mut ctx, bs.clone())
+    };
+    tape.index = ctx.index;
+    tape.memory = memory.into_inner();
+    halted
+}
+
+// a panic can't unwind into C, so it is reported instead
+fn guard(
+    f: impl FnOnce() -> BFStatus
+)
+    -> BFStatus
+{
+    catch_unwind(AssertUnwindSafe(f)).unwrap_or(BFStatus::Panic)
+}
+
+/// Parses the nul-terminated `source` into a program stored in `*program`, to be freed with `bf_program_free`.
+/// On a parse error `*error`, unless `error` is null, is set to a message to be freed with `bf_string_free`.
+///
+/// # Safety
+/// `source` must be a nul-terminated string, and `program` and `error` valid to write to or null.
+#[no_mangle]
+pub unsafe extern "C" fn bf_parse(
+    source: *const c_char,
+    program: *mut *mut BFProgram,
+    error: *mut *mut c_char
+)
+    -> BFStatus
+{
+    if source.is_null() || program.is_null() { return BFStatus::NullPointer; }
+    guard(|| {
+        let Ok(s) = unsafe { CStr::from_ptr(source) }.to_str() else { return BFStatus::InvalidUtf8 };
+        let mut iter = TextIter{ iter: s.chars(), line: 0, index: 0 };
+        match parse_program()(&mut iter) {
+            Ok(is) => {
+                unsafe { *program = Box::into_raw(Box::new(BFProgram(is))); }
+                BFStatus::Ok
+            },
+            Err(err) => {
+                if !error.is_null() {
+                    let message = show_error("".to_string(), & |info: TextInfo| format!("at {}:{}", info.line, info.index), err);
+                    unsafe { *error = CString::new(message.replace('\0', "")).unwrap().into_raw(); }
+                }
+                BFStatus::ParseError
+            }
+        }
+    })
+}
+
+/// # Safety
+/// `program` must have come from `bf_parse` and not been freed, or be null.
+#[no_mangle]
+pub unsafe extern "C" fn bf_program_free(program: *mut BFProgram) -> () {
+    if !program.is_null() { drop(unsafe { Box::from_raw(program) }); }
+}
+
+/// Optimises a program at `level` (0, 1, or 2 and above, as `PassManager::with_level`) into `*optimised`,
+/// to be freed with `bf_optimised_free`.
+///
+/// # Safety
+/// `program` must be a live program or null, and `optimised` valid to write to or null.
+#[no_mangle]
+pub unsafe extern "C" fn bf_optimise(
+    program: *const BFProgram,
+    level: u8,
+    optimised: *mut *mut BFOptimised
+)
+    -> BFStatus
+{
+    if program.is_null() || optimised.is_null() { return BFStatus::NullPointer; }
+    guard(|| {
+        let bs = optimising_convert_with(unsafe { &*program }.0.clone(), & PassManager::with_level(level));
+        unsafe { *optimised = Box::into_raw(Box::new(BFOptimised(bs))); }
+        BFStatus::Ok
+    })
+}
+
+/// # Safety
+/// `optimised` must have come from `bf_optimise` and not been freed, or be null.
+#[no_mangle]
+pub unsafe extern "C" fn bf_optimised_free(optimised: *mut BFOptimised) -> () {
+    if !optimised.is_null() { drop(unsafe { Box::from_raw(optimised) }); }
+}
+
+/// The optimised bytecode as the REPL prints it, to be freed with `bf_string_free`, or null when `optimised` is.
+///
+/// # Safety
+/// `optimised` must be a live optimised program or null.
+#[no_mangle]
+pub unsafe extern "C" fn bf_optimised_pretty(optimised: *const BFOptimised) -> *mut c_char {
+    if optimised.is_null() { return std::ptr::null_mut(); }
+    CString::new(byte_code_pretty(& unsafe { &*optimised }.0)).map(CString::into_raw).unwrap_or(std::ptr::null_mut())
+}
+
+/// # Safety
+/// `s` must have come from this library and not been freed, or be null.
+#[no_mangle]
+pub unsafe extern "C" fn bf_string_free(s: *mut c_char) -> () {
+    if !s.is_null() { drop(unsafe { CString::from_raw(s) }); }
+}
+
+/// A blank tape with its head at 0, to be freed with `bf_tape_free`.
+#[no_mangle]
+pub extern "C" fn bf_tape_new() -> *mut BFTape {
+    Box::into_raw(Box::new(BFTape{ index: 0, memory: HashMap::new() }))
+}
+
+/// # Safety
+/// `tape` must have come from `bf_tape_new` and not been freed, or be null.
+#[no_mangle]
+pub unsafe extern "C" fn bf_tape_free(tape: *mut BFTape) -> () {
+    if !tape.is_null() { drop(unsafe { Box::from_raw(tape) }); }
+}
+
+/// The cell `register`, or 0 when `tape` is null.
+///
+/// # Safety
+/// `tape` must be a live tape or null.
+#[no_mangle]
+pub unsafe extern "C" fn bf_tape_get(tape: *const BFTape, register: i32) -> u8 {
+    if tape.is_null() { return 0; }
+    *unsafe { &*tape }.memory.get(& register).unwrap_or(&0)
+}
+
+/// Does nothing when `tape` is null, as do `bf_tape_move` and `bf_tape_clear`.
+///
+/// # Safety
+/// `tape` must be a live tape or null.
+#[no_mangle]
+pub unsafe extern "C" fn bf_tape_set(tape: *mut BFTape, register: i32, x: u8) -> () {
+    if tape.is_null() { return; }
+    unsafe { &mut *tape }.memory.insert(register, x);
+}
+
+/// Where the head is, or 0 when `tape` is null.
+///
+/// # Safety
+/// `tape` must be a live tape or null.
+#[no_mangle]
+pub unsafe extern "C" fn bf_tape_head(tape: *const BFTape) -> i32 {
+    if tape.is_null() { return 0; }
+    unsafe { &*tape }.index
+}
+
+/// # Safety
+/// `tape` must be a live tape or null.
+#[no_mangle]
+pub unsafe extern "C" fn bf_tape_move(tape: *mut BFTape, register: i32) -> () {
+    if tape.is_null() { return; }
+    unsafe { &mut *tape }.index = register;
+}
+
+/// Sets every cell back to 0, leaving the head where it is.
+///
+/// # Safety
+/// `tape` must be a live tape or null.
+#[no_mangle]
+pub unsafe extern "C" fn bf_tape_clear(tape: *mut BFTape) -> () {
+    if tape.is_null() { return; }
+    unsafe { &mut *tape }.memory.clear();
+}
+
+/// Runs a program on the tape without optimising it, calling `ask` and `put` with `user_data` for input and output.
+///
+/// # Safety
+/// `program` and `tape` must be live or null, and the callbacks safe to call with `user_data`.
+#[no_mangle]
+pub unsafe extern "C" fn bf_run(
+    program: *const BFProgram,
+    tape: *mut BFTape,
+    ask: Option<BFAsk>,
+    put: Option<BFPut>,
+    user_data: *mut c_void
+)
+    -> BFStatus
+{
+    let (Some(ask), Some(put)) = (ask, put) else { return BFStatus::NullPointer };
+    if program.is_null() || tape.is_null() { return BFStatus::NullPointer; }
+    guard(|| {
+        run_on_tape(unsafe { &mut *tape }, ask, put, user_data, Runnable::Raw(& unsafe { &*program }.0));
+        BFStatus::Ok
+    })
+}
+
+/// Runs optimised bytecode on the tape, as `bf_run`, giving `BF_NON_HALTING` when it aborts a loop that would never halt.
+///
+/// # Safety
+/// `optimised` and `tape` must be live or null, and the callbacks safe to call with `user_data`.
+#[no_mangle]
+pub unsafe extern "C" fn bf_run_optimised(
+    optimised: *const BFOptimised,
+    tape: *mut BFTape,
+    ask: Option<BFAsk>,
+    put: Option<BFPut>,
+    user_data: *mut c_void
+)
+    -> BFStatus
+{
+    let (Some(ask), Some(put)) = (ask, put) else { return BFStatus::NullPointer };
+    if optimised.is_null() || tape.is_null() { return BFStatus::NullPointer; }
+    guard(|| match run_on_tape(unsafe { &mut *tape }, ask, put, user_data, Runnable::Optimised(& unsafe { &*optimised }.0)) {
+        true => BFStatus::Ok,
+        false => BFStatus::NonHalting
+    })
+}
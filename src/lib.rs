@@ -2,8 +2,9 @@ pub mod parser;
 pub mod interpreter;
 pub mod repl;
 pub mod optimised;
+pub mod ffi;
 
-#[derive(Debug)]
+#[derive(Debug, Clone)]
 pub enum BFRaw {
     Lft,
     Rgh,
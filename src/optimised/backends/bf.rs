@@ -0,0 +1,408 @@
+use std::{fmt, collections::{BTreeMap, BTreeSet}};
+
+use crate::*;
+use super::*;
+
+#[cfg(test)]
+mod tests {
+    use std::cell::RefCell;
+
+    use crate::interpreter::*;
+    use super::*;
+    use super::super::{ tests::*, super::optimiser::tests::* };
+
+    fn run_raw(s: &str, input: &[u8]) -> Vec<u8> {
+        let input = RefCell::new(input.iter());
+        let output = RefCell::new(vec![]);
+        let memory = RefCell::new(std::collections::HashMap::<i32, u8>::new());
+        let mut ctx = BFCtx{
+            index: 0,
+            ask: || input.borrow_mut().next().cloned().unwrap_or(0),
+            put: |x| output.borrow_mut().push(x),
+            get: |i| *memory.borrow().get(& i).unwrap_or(&0),
+            set: |i, x| { memory.borrow_mut().insert(i, x); },
+            clear: || memory.borrow_mut().clear()
+        };
+        run_bfraw(&mut ctx, & raw(s));
+        output.into_inner()
+    }
+
+    #[test]
+    fn equivalent() {
+        for (s, input) in PROGRAMS.iter().cloned().chain([
+            ("[->+>+<<]>>[-<<+>>]<<++++[->>+++<<]>>.", &[][..]),
+            (">>,[<<+>+>-]<<[>>+<<-]>[>>++<<-]>>.<.", &[9]),
+            ("+++++[>+++++[>+++++<-]<-]>>.", &[]),
+            (",[>>+<<[-]]>>[<+>-]<.", &[3])
+        ]) {
+            if !interpret(s, input).1 { continue; } // it would never halt as plain brainfuck
+            let minified = minify(& raw(s));
+            assert_eq!(run_raw(& minified, input), run_raw(s, input), "{s} minified to {minified}");
+            assert!(minified.len() <= s.len(), "{s} minified to the longer {minified}");
+        }
+    }
+
+    #[test]
+    fn short() {
+        assert_eq!(minify(& raw("this is a comment [ with +- in it ] ++.")), "++.");
+        assert_eq!(minify(& raw(& format!("{}.", "+".repeat(65)))), ">++++++++[<++++++++>-]<+.");
+        assert_eq!(minify(& raw(">>><<<----------")), "----------");
+        assert_eq!(minify(& raw(",>>+<-<[->>+<<][-]>>.")), ",[->>+<<]>->+.");
+        assert_eq!(minify(& raw("[->+>+<<]>>[-<<+>>]<<++++[->>+++<<]>>.")), ">>++++++++++++.");
+        // a loop that can't be solved keeps its brackets, and has its body minified
+        assert_eq!(minify(& raw("++++++++[>++++++++<-]>[.->+<<>-+]")), "++++++++[->++++++++<]>[.->+<]");
+    }
+
+    #[test]
+    fn unemittable() {
+        let square = Expr::new(ProcExpr::Mul(Expr::new(ProcExpr::Reg(0)), Expr::new(ProcExpr::Reg(0))));
+        assert_eq!(
+            emit_bf(& vec![OptimisedBlock::AtomicEffect(BTreeMap::from([(1, square.clone())]), 0)]),
+            Err(Unemittable::Expression(1, square))
+        );
+        let copy = BTreeMap::from([(1, Expr::new(ProcExpr::Add(Expr::new(ProcExpr::Reg(0)), Expr::new(ProcExpr::Reg(1)))))]);
+        assert_eq!(emit_bf(& vec![OptimisedBlock::AtomicEffect(copy, 0)]), Err(Unemittable::Copy(0)));
+        assert_eq!(emit_bf(& optimising_convert(raw(",[->+<]>[-<++>]"))), Err(Unemittable::Scale(0, 2)));
+    }
+}
+
+// why an effect can't be written as brainfuck without a spare cell
+#[derive(Debug, Clone, PartialEq)]
+pub enum Unemittable {
+    Expression(i32, Expr), // not a sum of constant multiples of registers
+    Copy(i32), // a register that is read by other lines, but not cleared by its own
+    Scale(i32, u8), // a register multiplied by something other than 0 or 1
+    Cycle // registers that are each added into the other
+}
+
+impl fmt::Display for Unemittable {
+
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Unemittable::Expression(register, expr) => write!(f, "~#{register} = {expr} isn't linear"),
+            Unemittable::Copy(register) => write!(f, "~#{register} is copied"),
+            Unemittable::Scale(register, k) => write!(f, "~#{register} is multiplied by {k}"),
+            Unemittable::Cycle => write!(f, "registers are added into each other")
+        }
+    }
+}
+
+// what is known of the tape, relative to the head
+#[derive(Debug, Clone)]
+enum Known {
+    All(BTreeMap<i32, u8>), // every cell, with those missing being 0
+    Some(BTreeMap<i32, u8>) // just these cells
+}
+
+impl Known {
+    fn get(&self, register: i32) -> Option<u8> {
+        match self {
+            Known::All(cells) => Some(*cells.get(& register).unwrap_or(& 0)),
+            Known::Some(cells) => cells.get(& register).cloned()
+        }
+    }
+
+    fn set(&mut self, register: i32, value: Option<u8>) -> () {
+        match (self, value) {
+            (Known::All(cells), Some(x)) | (Known::Some(cells), Some(x)) => { cells.insert(register, x); },
+            (Known::Some(cells), None) => { cells.remove(& register); },
+            (known, None) => {
+                let Known::All(cells) = known else { unreachable!() };
+                // every other cell is still known, but that can't be written down, so only those near the head are kept
+                let mut near: BTreeMap<i32, u8> = (register - 8..=register + 8).map(|r| (r, *cells.get(& r).unwrap_or(& 0))).collect();
+                near.extend(cells.iter().map(|(r, x)| (*r, *x)));
+                near.remove(& register);
+                *known = Known::Some(near);
+            }
+        }
+    }
+
+    fn shift(&mut self, offset: i32) -> () {
+        let (Known::All(cells) | Known::Some(cells)) = self;
+        *cells = cells.iter().map(|(r, x)| (r - offset, *x)).collect();
+    }
+}
+
+// an expression's value, when every register in it is known
+fn evaluate(
+    expr: & ProcExpr,
+    known: & Known
+)
+    -> Option<u8>
+{
+    match expr {
+        ProcExpr::Lit(x) => Some(*x),
+        ProcExpr::Reg(r) => known.get(*r),
+        ProcExpr::Add(a, b) => Some(evaluate(a, known)?.wrapping_add(evaluate(b, known)?)),
+        ProcExpr::Mul(a, b) => Some(evaluate(a, known)?.wrapping_mul(evaluate(b, known)?)),
+        ProcExpr::Into(a, b) => div_u8(evaluate(b, known)?, evaluate(a, known)?),
+        ProcExpr::Choose(a, k) => Some(choose_u8(evaluate(a, known)?, *k)),
+        ProcExpr::If(c, a, b) => if evaluate(c, known)? != 0 { evaluate(a, known) } else { evaluate(b, known) },
+        ProcExpr::IsZero(a) => Some((evaluate(a, known)? == 0) as u8)
+    }
+}
+
+// an expression as a constant plus multiples of the registers that aren't known
+fn affine(
+    expr: & ProcExpr,
+    known: & Known
+)
+    -> Option<(u8, BTreeMap<i32, u8>)>
+{
+    if let Some(x) = evaluate(expr, known) { return Some((x, BTreeMap::new())); }
+    match expr {
+        ProcExpr::Reg(r) => Some((0, BTreeMap::from([(*r, 1)]))),
+        ProcExpr::Add(a, b) => {
+            let (x, mut xs) = affine(a, known)?;
+            let (y, ys) = affine(b, known)?;
+            for (r, k) in ys {
+                let sum = xs.get(& r).unwrap_or(& 0).wrapping_add(k);
+                if sum == 0 { xs.remove(& r); } else { xs.insert(r, sum); }
+            }
+            Some((x.wrapping_add(y), xs))
+        },
+        ProcExpr::Mul(a, b) => {
+            let ((x, xs), (y, ys)) = (affine(a, known)?, affine(b, known)?);
+            let (scale, (c, terms)) = if xs.is_empty() { (x, (y, ys)) } else if ys.is_empty() { (y, (x, xs)) } else { return None };
+            Some((c.wrapping_mul(scale), terms.into_iter().map(|(r, k)| (r, k.wrapping_mul(scale))).filter(|(_, k)| *k != 0).collect()))
+        },
+        _ => None
+    }
+}
+
+#[derive(Debug, Clone)]
+struct Writer {
+    out: String,
+    offset: i32, // where the head of the brainfuck is, relative to the head of the bytecode
+    known: Known
+}
+
+impl Writer {
+    fn goto(&mut self, register: i32) -> () {
+        let (c, n) = if register > self.offset { ('>', register - self.offset) } else { ('<', self.offset - register) };
+        self.out.extend(std::iter::repeat_n(c, n as usize));
+        self.offset = register;
+    }
+
+    fn add(&mut self, register: i32, x: u8) -> () {
+        if x == 0 { return; }
+        self.goto(register);
+        let (c, n) = if x < 128 { ('+', x) } else { ('-', x.wrapping_neg()) };
+        self.out.extend(std::iter::repeat_n(c, n as usize));
+    }
+
+    // adds to a cell, using a loop on a cell known to be 0 when that's shorter
+    fn add_constant(&mut self, register: i32, x: u8, lines: & BTreeMap<i32, (u8, BTreeMap<i32, u8>)>) -> () {
+        let cost = |x: u8| x.min(x.wrapping_neg()) as usize;
+        let zero = (1..=3).flat_map(|d| [register + d, register - d])
+            .find(|z| self.known.get(*z) == Some(0) && !lines.contains_key(z));
+        if let Some(z) = zero {
+            let distance = (z - register).unsigned_abs() as usize;
+            let best = (2..=16u8).flat_map(|a| (1..=127u8).flat_map(move |b| [(a, b), (a, b.wrapping_neg())]))
+                .map(|(a, b)| (a, b, x.wrapping_sub(a.wrapping_mul(b))))
+                .min_by_key(|(a, b, rest)| *a as usize + cost(*b) + cost(*rest) + 3 * distance + 3);
+            if let Some((a, b, rest)) = best {
+                if a as usize + cost(b) + cost(rest) + 3 * distance + 3 < cost(x) {
+                    self.add(z, a);
+                    self.out.push('[');
+                    self.add(register, b);
+                    self.goto(z);
+                    self.out.push_str("-]");
+                    self.add(register, rest);
+                    return;
+                }
+            }
+        }
+        self.add(register, x);
+    }
+
+    fn clear(&mut self, register: i32) -> () {
+        self.goto(register);
+        self.out.push_str("[-]");
+    }
+
+    fn effect(&mut self, lines: & BTreeMap<i32, Expr>, offset: i32) -> Result<(), Unemittable> {
+        let lines: BTreeMap<i32, (u8, BTreeMap<i32, u8>)> = lines.iter()
+            .map(|(r, expr)| affine(expr, & self.known).map(|line| (*r, line)).ok_or(Unemittable::Expression(*r, expr.clone())))
+            .collect::<Result<_, _>>()?;
+
+        // registers added into other lines are counted down to 0 by a loop, so they have to be cleared by their own line
+        let sources: BTreeSet<i32> = lines.iter().flat_map(|(r, (_, terms))| terms.keys().filter(move |s| *s != r).cloned()).collect();
+        for s in sources.iter() {
+            match lines.get(s) {
+                Some((_, terms)) if !terms.contains_key(s) => (),
+                _ => return Err(Unemittable::Copy(*s))
+            }
+        }
+        for (r, (_, terms)) in lines.iter() {
+            match terms.get(r) {
+                Some(k) if *k != 1 => return Err(Unemittable::Scale(*r, *k)),
+                _ => ()
+            }
+        }
+
+        // what each cell holds before its constant is added, None until it is known
+        let mut base: BTreeMap<i32, Option<u8>> = lines.iter().map(|(r, (_, terms))| (*r, match terms.get(r) {
+            Some(_) => Some(0), // it keeps its old value, and only its constant and other registers are added
+            None => self.known.get(*r)
+        })).collect();
+        // those that something is added to, but whose old value isn't known, are cleared first
+        for (r, (_, terms)) in lines.iter() {
+            if base[r].is_none() && !sources.contains(r) && terms.keys().any(|s| s != r) {
+                self.clear(*r);
+                base.insert(*r, Some(0));
+            }
+        }
+
+        // a register can be counted down once nothing still to be counted down is added to
+        let mut pending = sources.clone();
+        while !pending.is_empty() {
+            let targets = |s: i32| lines.iter().filter_map(move |(r, (_, terms))| terms.get(& s).filter(|_| *r != s).map(|k| (*r, *k)));
+            let s = *pending.iter()
+                .filter(|s| targets(**s).all(|(r, _)| !pending.contains(& r)))
+                .min_by_key(|s| (**s - self.offset).abs())
+                .ok_or(Unemittable::Cycle)?;
+            pending.remove(& s);
+            self.goto(s);
+            self.out.push_str("[-");
+            for (r, k) in targets(s) { self.add(r, k); }
+            self.goto(s);
+            self.out.push(']');
+            base.insert(s, Some(0));
+        }
+
+        // then the constants, from whichever end is nearer
+        let mut order: Vec<i32> = lines.keys().cloned().collect();
+        if (order.last().unwrap_or(& 0) - self.offset).abs() < (order.first().unwrap_or(& 0) - self.offset).abs() { order.reverse(); }
+        for r in order {
+            let (x, terms) = & lines[& r];
+            match base[& r] {
+                Some(b) => self.add_constant(r, x.wrapping_sub(b), & lines),
+                None => {
+                    self.clear(r);
+                    self.known.set(r, Some(0));
+                    self.add_constant(r, *x, & lines);
+                }
+            }
+            self.known.set(r, terms.is_empty().then_some(*x));
+        }
+
+        self.known.shift(offset);
+        self.offset -= offset;
+        Ok(())
+    }
+
+    fn block(&mut self, b: & OptimisedBlock) -> Result<(), Unemittable> {
+        match b {
+            OptimisedBlock::Ask(register) => {
+                self.goto(*register);
+                self.out.push(',');
+                self.known.set(*register, None);
+            },
+            OptimisedBlock::Put(register) => {
+                self.goto(*register);
+                self.out.push('.');
+            },
+            OptimisedBlock::AtomicEffect(lines, offset) => self.effect(lines, *offset)?,
+            OptimisedBlock::Loop(register, bs, _) | OptimisedBlock::If(register, bs) => {
+                // a loop on a cell known to be 0 never runs
+                if self.known.get(*register) == Some(0) { return Ok(()); }
+                // an if-block always leaves its cell at 0, so it can be written as a loop
+                self.goto(*register);
+                self.out.push('[');
+                self.known = Known::Some(BTreeMap::new());
+                for b_ in bs { self.block(b_)?; }
+                self.goto(*register);
+                self.out.push(']');
+                self.known = Known::Some(BTreeMap::from([(*register, 0)]));
+            }
+        }
+        Ok(())
+    }
+
+    fn blocks(&mut self, bs: & Vec<OptimisedBlock>) -> Result<(), Unemittable> {
+        for b in bs { self.block(b)?; }
+        Ok(())
+    }
+
+    // the instructions just as they are
+    fn literal(&mut self, raw: & Vec<BFRaw>) -> () {
+        self.goto(0);
+        for i in raw {
+            match i {
+                BFRaw::Lft => self.out.push('<'),
+                BFRaw::Rgh => self.out.push('>'),
+                BFRaw::Inc => self.out.push('+'),
+                BFRaw::Dec => self.out.push('-'),
+                BFRaw::Ask => self.out.push(','),
+                BFRaw::Put => self.out.push('.'),
+                BFRaw::Loop(body) => {
+                    self.out.push('[');
+                    self.literal(body);
+                    self.out.push(']');
+                }
+            }
+        }
+        self.known = Known::Some(BTreeMap::new());
+    }
+
+    // a loop as it is, with its body minified
+    fn kept_loop(&mut self, body: & Vec<BFRaw>) -> () {
+        if self.known.get(0) == Some(0) { return; }
+        self.goto(0);
+        self.out.push('[');
+        self.known = Known::Some(BTreeMap::new());
+        self.raw(body);
+        self.goto(0);
+        self.out.push(']');
+        self.known = Known::Some(BTreeMap::from([(0, 0)]));
+    }
+
+    // the raw instructions, optimised as a whole, or in runs with some loops kept between them, or as they are, whichever is shortest
+    fn raw(&mut self, raw: & Vec<BFRaw>) -> () {
+        let mut literal = self.clone();
+        literal.literal(raw);
+        let mut whole = self.clone();
+        let whole = whole.blocks(& optimising_convert(raw.clone())).is_ok().then_some(whole);
+        // the run can always be written, as it was checked along with what came before it, or is straight-line code, which only adds constants
+        let mut run = vec![];
+        for i in raw {
+            let BFRaw::Loop(body) = i else { run.push(i.clone()); continue; };
+            let mut kept = self.clone();
+            kept.blocks(& optimising_convert(run.clone())).unwrap();
+            kept.kept_loop(body);
+            run.push(i.clone());
+            let mut solved = self.clone();
+            if solved.blocks(& optimising_convert(run.clone())).is_err() || kept.out.len() < solved.out.len() {
+                *self = kept;
+                run.clear();
+            }
+        }
+        self.blocks(& optimising_convert(run)).unwrap();
+        if let Some(whole) = whole.filter(|whole| whole.out.len() <= self.out.len()) { *self = whole; }
+        if literal.out.len() < self.out.len() { *self = literal; }
+    }
+}
+
+// the bytecode as plain brainfuck, which works from any tape
+pub fn emit_bf(
+    bs: & Vec<OptimisedBlock>
+)
+    -> Result<String, Unemittable>
+{
+    let mut writer = Writer{ out: String::new(), offset: 0, known: Known::Some(BTreeMap::new()) };
+    writer.blocks(bs)?;
+    Ok(writer.out)
+}
+
+// a program rewritten as shorter brainfuck with the same output, starting from a blank tape
+// loops whose solution can't be written back stay as loops, with their bodies minified
+pub fn minify(
+    raw: & Vec<BFRaw>
+)
+    -> String
+{
+    let mut writer = Writer{ out: String::new(), offset: 0, known: Known::All(BTreeMap::new()) };
+    writer.raw(raw);
+    writer.out
+}
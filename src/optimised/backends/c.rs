@@ -0,0 +1,176 @@
+use std::collections::HashMap;
+
+use super::*;
+
+#[cfg(test)]
+mod tests {
+    use std::process::Command;
+
+    use super::*;
+    use super::super::{ tests::*, super::optimiser::tests::* };
+
+    fn compile(dir: & std::path::Path, name: &str, source: String) -> std::path::PathBuf {
+        let (file, exe) = (dir.join(format!("{name}.c")), dir.join(name));
+        std::fs::write(& file, source).unwrap();
+        let status = Command::new("cc").arg("-O1").arg("-o").arg(& exe).arg(& file).status().unwrap();
+        assert!(status.success(), "{} didn't compile", file.display());
+        exe
+    }
+
+    #[test]
+    fn compiled_programs() {
+        if !command_exists("cc") { eprintln!("no C compiler, skipping"); return; }
+        let dir = scratch_dir("c");
+        for (i, (s, input)) in PROGRAMS.iter().enumerate() {
+            let exe = compile(& dir, & format!("program{i}"), emit_c(& optimising_convert(raw(s))));
+            assert_eq!(run_with_input(&mut Command::new(& exe), input), interpret(s, input), "{s} diverged");
+        }
+    }
+
+    #[test]
+    fn division() {
+        if !command_exists("cc") { eprintln!("no C compiler, skipping"); return; }
+        let dir = scratch_dir("c-division");
+        // every division, printed as its quotient or "-" when it never halts
+        let source = emit_c(& vec![]).replace(
+            "int main(void) {",
+            "int main(void) {\n\tfor (int x = 0; x < 256; x++) for (int y = 0; y < 256; y++) {\n\t\tuint8_t q;\n\t\tif (bf_divide(x, y, &q)) printf(\"%d \", q); else printf(\"- \");\n\t}"
+        );
+        let (output, halted) = run_with_input(&mut Command::new(compile(& dir, "division", source)), b"");
+        assert!(halted);
+        let expected: String = (0..256).flat_map(|x| (0..256).map(move |y| match div_u8(x as u8, y as u8) {
+            Some(q) => format!("{q} "),
+            None => "- ".to_string()
+        })).collect();
+        assert_eq!(String::from_utf8(output).unwrap(), expected);
+    }
+}
+
+pub const TAPE_SIZE: usize = 1 << 16; // the tape wraps around, so this must be a power of two
+
+const PRELUDE: &str = r#"// generated from optimised brainfuck bytecode
+#include <stdint.h>
+#include <stdio.h>
+#include <stdlib.h>
+
+#define TAPE_SIZE TAPE_SIZE_VALUE
+#define CELL(r) tape[(head + (size_t)(r)) & (TAPE_SIZE - 1)]
+
+static uint8_t tape[TAPE_SIZE];
+static size_t head = 0;
+
+static inline void bf_abort(void) {
+	fflush(stdout);
+	fputs("aborted non-halting loop\n", stderr);
+	exit(1);
+}
+
+static inline uint8_t bf_ask(void) {
+	int c = getchar();
+	return c == EOF ? 0 : (uint8_t)c;
+}
+
+static inline void bf_put(uint8_t x) {
+	putchar(x);
+}
+
+static inline int bf_divide(uint8_t x, uint8_t y, uint8_t *q) {
+	if (x == 0) { *q = 0; return 1; }
+	if (y == 0) return 0;
+	unsigned shift = 0;
+	while (!(y & 1)) {
+		if (x & 1) return 0;
+		x >>= 1;
+		y >>= 1;
+		shift++;
+	}
+	uint8_t inverse = y;
+	for (int i = 0; i < 3; i++) inverse = (uint8_t)(inverse * (uint8_t)(2 - y * inverse));
+	*q = (uint8_t)(x * inverse) & (uint8_t)(0xff >> shift);
+	return 1;
+}
+
+static inline uint8_t bf_into(uint8_t a, uint8_t b) {
+	uint8_t q;
+	if (!bf_divide(b, a, &q)) bf_abort();
+	return q;
+}
+
+static inline uint8_t bf_choose(uint8_t x, uint8_t k) {
+	uint8_t row[256] = { 1 };
+	for (unsigned i = 0; i < x; i++) {
+		for (unsigned j = k; j > 0; j--) row[j] = (uint8_t)(row[j] + row[j - 1]);
+	}
+	return row[k];
+}
+"#;
+
+fn c_expr(
+    expr: & ProcExpr,
+    temporaries: & HashMap<Expr, usize>
+)
+    -> String
+{
+    let show = |x: & Expr| match temporaries.get(x) {
+        Some(i) => format!("t{i}"),
+        None => c_expr(x, temporaries)
+    };
+    match expr {
+        ProcExpr::Lit(x) => format!("{x}"),
+        ProcExpr::Reg(r) => format!("CELL({r})"),
+        ProcExpr::Add(a, b) => format!("(uint8_t)({} + {})", show(a), show(b)),
+        ProcExpr::Mul(a, b) => format!("(uint8_t)({} * {})", show(a), show(b)),
+        ProcExpr::Into(a, b) => format!("bf_into({}, {})", show(a), show(b)),
+        ProcExpr::Choose(a, k) => format!("bf_choose({}, {k})", show(a)),
+        ProcExpr::If(c, a, b) => format!("({} ? {} : {})", show(c), show(a), show(b)),
+        ProcExpr::IsZero(a) => format!("({} == 0)", show(a)),
+    }
+}
+
+fn c_block(
+    b: & OptimisedBlock
+)
+    -> String
+{
+    match b {
+        OptimisedBlock::Ask(register) => format!("CELL({register}) = bf_ask();"),
+        OptimisedBlock::Put(register) => format!("bf_put(CELL({register}));"),
+        OptimisedBlock::AtomicEffect(lines, effect) if lines.is_empty() => format!("head += (size_t)({effect});"),
+        OptimisedBlock::AtomicEffect(lines, effect) => {
+            let shared = effect_temporaries(lines);
+            let temporaries: HashMap<Expr, usize> = shared.iter().enumerate().map(|(i, expr)| (expr.clone(), i)).collect();
+            let statements = shared.iter().enumerate().map(|(i, expr)| format!("uint8_t t{i} = {};", c_expr(expr, & temporaries)))
+                .chain(lines.values().enumerate().map(|(i, expr)| match temporaries.get(expr) {
+                    Some(j) => format!("uint8_t v{i} = t{j};"),
+                    None => format!("uint8_t v{i} = {};", c_expr(expr, & temporaries))
+                }))
+                .chain(lines.keys().enumerate().map(|(i, register)| format!("CELL({register}) = v{i};")))
+                .chain((*effect != 0).then(|| format!("head += (size_t)({effect});")));
+            format!("{{\n{}\n}}", indent_string(join_strings(statements)))
+        },
+        OptimisedBlock::Loop(register, bs, _) => format!("while (CELL({register})) {{\n{}\n}}", indent_string(c_blocks(bs))),
+        OptimisedBlock::If(register, bs) => format!("if (CELL({register})) {{\n{}\n}}", indent_string(c_blocks(bs)))
+    }
+}
+
+fn c_blocks(
+    bs: & Vec<OptimisedBlock>
+)
+    -> String
+{
+    join_strings(bs.iter().map(c_block))
+}
+
+// a self-contained C program, reading input from stdin and writing output to stdout
+// it exits with status 1 when the bytecode aborts a loop that would never halt
+pub fn emit_c(
+    bs: & Vec<OptimisedBlock>
+)
+    -> String
+{
+    format!(
+        "{}\nint main(void) {{\n{}\n\tfflush(stdout);\n\treturn 0;\n}}\n",
+        PRELUDE.replace("TAPE_SIZE_VALUE", & TAPE_SIZE.to_string()),
+        indent_string(c_blocks(bs))
+    )
+}
@@ -0,0 +1,341 @@
+use std::collections::HashMap;
+
+use super::*;
+
+#[cfg(test)]
+mod tests {
+    use std::process::Command;
+
+    use super::*;
+    use super::super::{ tests::*, super::optimiser::tests::* };
+
+    fn assemble(dir: & std::path::Path, name: &str, s: &str) -> std::path::PathBuf {
+        let (source, object, exe) = (dir.join(format!("{name}.s")), dir.join(format!("{name}.o")), dir.join(name));
+        std::fs::write(& source, emit_gas(& optimising_convert(raw(s)))).unwrap();
+        assert!(Command::new("as").arg("-o").arg(& object).arg(& source).status().unwrap().success(), "{s} didn't assemble");
+        assert!(Command::new("ld").arg("-o").arg(& exe).arg(& object).status().unwrap().success(), "{s} didn't link");
+        exe
+    }
+
+    #[test]
+    fn assembled_programs() {
+        if !command_exists("as") || !command_exists("ld") { eprintln!("no assembler or linker, skipping"); return; }
+        let dir = scratch_dir("gas");
+        for (i, (s, input)) in PROGRAMS.iter().enumerate() {
+            let exe = assemble(& dir, & format!("program{i}"), s);
+            assert_eq!(run_with_input(&mut Command::new(& exe), input), interpret(s, input), "{s} diverged");
+        }
+    }
+
+    #[test]
+    fn long_output() {
+        if !command_exists("as") || !command_exists("ld") { eprintln!("no assembler or linker, skipping"); return; }
+        let dir = scratch_dir("gas-long");
+        // more than the output buffer holds, and some of it after reading input
+        let s = "++++++++++[>+++++<-]>[>++++++++++[>++++++++++<-]>[>+.<-]<<-],.";
+        let exe = assemble(& dir, "long", s);
+        let (output, halted) = run_with_input(&mut Command::new(& exe), b"x");
+        assert_eq!(output.len(), 5001);
+        assert_eq!((output, halted), interpret(s, b"x"));
+    }
+}
+
+pub const TAPE_SIZE: usize = 1 << 16; // the tape wraps around, so this must be a power of two
+const OUTPUT_BUFFER: usize = 4096;
+
+// helpers the generated code calls, taking their arguments in edi and esi and answering in eax
+const RUNTIME: &str = r#"
+# a in edi, b in esi, aborting when there is no quotient
+bf_into:
+	movzx edi, dil
+	movzx esi, sil
+	xor eax, eax
+	test esi, esi
+	jz .Linto_done
+	test edi, edi
+	jz bf_abort
+	bsf ecx, edi
+	bsf edx, esi
+	cmp edx, ecx
+	jb bf_abort
+	shr edi, cl
+	shr esi, cl
+	mov eax, edi
+	mov r8d, 3
+.Linto_newton:
+	mov edx, edi
+	imul edx, eax
+	neg edx
+	add edx, 2
+	imul eax, edx
+	dec r8d
+	jnz .Linto_newton
+	imul eax, esi
+	mov edx, 255
+	shr edx, cl
+	and eax, edx
+.Linto_done:
+	ret
+
+# x in edi, k in esi
+bf_choose:
+	movzx edi, dil
+	movzx esi, sil
+	lea r8, [rip + choose_row]
+	xor ecx, ecx
+.Lchoose_clear:
+	mov byte ptr [r8 + rcx], 0
+	inc ecx
+	cmp ecx, esi
+	jbe .Lchoose_clear
+	mov byte ptr [r8], 1
+.Lchoose_row:
+	test edi, edi
+	jz .Lchoose_done
+	mov ecx, esi
+.Lchoose_column:
+	test ecx, ecx
+	jz .Lchoose_next
+	mov al, byte ptr [r8 + rcx - 1]
+	add byte ptr [r8 + rcx], al
+	dec ecx
+	jmp .Lchoose_column
+.Lchoose_next:
+	dec edi
+	jmp .Lchoose_row
+.Lchoose_done:
+	movzx eax, byte ptr [r8 + rsi]
+	ret
+
+# reads a byte into eax, 0 at the end of input, after flushing any output so far
+bf_ask:
+	call bf_flush
+	mov byte ptr [rip + input_byte], 0
+	xor eax, eax
+	xor edi, edi
+	lea rsi, [rip + input_byte]
+	mov edx, 1
+	syscall
+	movzx eax, byte ptr [rip + input_byte]
+	ret
+
+# buffers the byte in dil
+bf_put:
+	mov eax, dword ptr [rip + output_length]
+	lea rdx, [rip + output_buffer]
+	mov byte ptr [rdx + rax], dil
+	inc eax
+	mov dword ptr [rip + output_length], eax
+	cmp eax, OUTPUT_BUFFER
+	je bf_flush
+	ret
+
+bf_flush:
+	lea rsi, [rip + output_buffer]
+	mov edx, dword ptr [rip + output_length]
+.Lflush_write:
+	test edx, edx
+	jz .Lflush_done
+	mov eax, 1
+	mov edi, 1
+	syscall
+	test rax, rax
+	jle .Lflush_done
+	add rsi, rax
+	sub edx, eax
+	jmp .Lflush_write
+.Lflush_done:
+	mov dword ptr [rip + output_length], 0
+	ret
+
+bf_exit:
+	call bf_flush
+	mov eax, 60
+	xor edi, edi
+	syscall
+
+bf_abort:
+	call bf_flush
+	mov eax, 1
+	mov edi, 2
+	lea rsi, [rip + abort_message]
+	mov edx, abort_message_length
+	syscall
+	mov eax, 60
+	mov edi, 1
+	syscall
+
+.section .rodata
+abort_message:
+	.ascii "aborted non-halting loop\n"
+	abort_message_length = . - abort_message
+"#;
+
+// registers kept by the generated code:
+//   rbx is the tape, r12 the head, which wraps around the tape when used, and r13 scratch space for an effect's temporaries and results
+struct Writer {
+    lines: Vec<String>,
+    labels: usize,
+    scratch: usize // bytes of scratch space the code needs
+}
+
+impl Writer {
+    fn emit(&mut self, line: String) -> () {
+        self.lines.push(format!("\t{line}"));
+    }
+
+    fn label(&mut self) -> String {
+        self.labels += 1;
+        format!(".Lblock{}", self.labels)
+    }
+
+    fn place(&mut self, label: & String) -> () {
+        self.lines.push(format!("{label}:"));
+    }
+
+    // leaves the address of the cell in rbx + rdx
+    fn address(&mut self, register: i32) -> () {
+        self.emit(format!("lea rdx, [r12 + {register}]"));
+        self.emit(format!("and edx, {}", TAPE_SIZE - 1));
+    }
+
+    // leaves the expression's value in al
+    fn expr(&mut self, expr: & Expr, temporaries: & HashMap<Expr, usize>) -> () {
+        if let Some(i) = temporaries.get(expr) {
+            return self.emit(format!("movzx eax, byte ptr [r13 + {i}]"));
+        }
+        match expr.as_ref() {
+            ProcExpr::Lit(x) => self.emit(format!("mov eax, {x}")),
+            ProcExpr::Reg(r) => {
+                self.address(*r);
+                self.emit("movzx eax, byte ptr [rbx + rdx]".to_string());
+            },
+            ProcExpr::Add(a, b) | ProcExpr::Mul(a, b) | ProcExpr::Into(a, b) => {
+                self.expr(a, temporaries);
+                self.emit("push rax".to_string());
+                self.expr(b, temporaries);
+                self.emit("pop rcx".to_string());
+                match expr.as_ref() {
+                    ProcExpr::Add(..) => self.emit("add eax, ecx".to_string()),
+                    ProcExpr::Mul(..) => self.emit("imul eax, ecx".to_string()),
+                    _ => {
+                        self.emit("mov esi, eax".to_string());
+                        self.emit("mov edi, ecx".to_string());
+                        self.emit("call bf_into".to_string());
+                    }
+                }
+            },
+            ProcExpr::Choose(a, k) => {
+                self.expr(a, temporaries);
+                self.emit("mov edi, eax".to_string());
+                self.emit(format!("mov esi, {k}"));
+                self.emit("call bf_choose".to_string());
+            },
+            ProcExpr::If(c, a, b) => {
+                let (otherwise, end) = (self.label(), self.label());
+                self.expr(c, temporaries);
+                self.emit("test al, al".to_string());
+                self.emit(format!("jz {otherwise}"));
+                self.expr(a, temporaries);
+                self.emit(format!("jmp {end}"));
+                self.place(& otherwise);
+                self.expr(b, temporaries);
+                self.place(& end);
+            },
+            ProcExpr::IsZero(a) => {
+                self.expr(a, temporaries);
+                self.emit("test al, al".to_string());
+                self.emit("sete al".to_string());
+            }
+        }
+    }
+
+    fn block(&mut self, b: & OptimisedBlock) -> () {
+        match b {
+            OptimisedBlock::Ask(register) => {
+                self.emit("call bf_ask".to_string());
+                self.address(*register);
+                self.emit("mov byte ptr [rbx + rdx], al".to_string());
+            },
+            OptimisedBlock::Put(register) => {
+                self.address(*register);
+                self.emit("movzx edi, byte ptr [rbx + rdx]".to_string());
+                self.emit("call bf_put".to_string());
+            },
+            OptimisedBlock::AtomicEffect(lines, effect) => {
+                let shared = effect_temporaries(lines);
+                let mut temporaries = HashMap::new();
+                for (i, expr) in shared.iter().enumerate() {
+                    self.expr(expr, & temporaries);
+                    self.emit(format!("mov byte ptr [r13 + {i}], al"));
+                    temporaries.insert(expr.clone(), i);
+                }
+                for (i, expr) in lines.values().enumerate() {
+                    self.expr(expr, & temporaries);
+                    self.emit(format!("mov byte ptr [r13 + {}], al", shared.len() + i));
+                }
+                for (i, register) in lines.keys().enumerate() {
+                    self.emit(format!("movzx eax, byte ptr [r13 + {}]", shared.len() + i));
+                    self.address(*register);
+                    self.emit("mov byte ptr [rbx + rdx], al".to_string());
+                }
+                self.scratch = self.scratch.max(shared.len() + lines.len());
+                if *effect != 0 { self.emit(format!("add r12, {effect}")); }
+            },
+            OptimisedBlock::Loop(register, bs, _) => {
+                let (top, end) = (self.label(), self.label());
+                self.place(& top);
+                self.address(*register);
+                self.emit("cmp byte ptr [rbx + rdx], 0".to_string());
+                self.emit(format!("je {end}"));
+                for b_ in bs { self.block(b_); }
+                self.emit(format!("jmp {top}"));
+                self.place(& end);
+            },
+            OptimisedBlock::If(register, bs) => {
+                let end = self.label();
+                self.address(*register);
+                self.emit("cmp byte ptr [rbx + rdx], 0".to_string());
+                self.emit(format!("je {end}"));
+                for b_ in bs { self.block(b_); }
+                self.place(& end);
+            }
+        }
+    }
+}
+
+// a complete x86-64 linux program in gas syntax, using syscalls for input and output, that needs no C runtime:
+//   as -o program.o program.s && ld -o program program.o
+// it exits with status 1 when the bytecode aborts a loop that would never halt
+pub fn emit_gas(
+    bs: & Vec<OptimisedBlock>
+)
+    -> String
+{
+    let mut writer = Writer{ lines: vec![], labels: 0, scratch: 0 };
+    for b in bs { writer.block(b); }
+    format!(
+        "# generated from optimised brainfuck bytecode\n\
+        .intel_syntax noprefix\n\
+        OUTPUT_BUFFER = {OUTPUT_BUFFER}\n\n\
+        .section .bss\n\
+        .align 16\n\
+        tape: .zero {TAPE_SIZE}\n\
+        scratch: .zero {}\n\
+        output_buffer: .zero OUTPUT_BUFFER\n\
+        output_length: .zero 4\n\
+        input_byte: .zero 1\n\
+        choose_row: .zero 256\n\n\
+        .section .text\n\
+        .globl _start\n\
+        _start:\n\
+        \tlea rbx, [rip + tape]\n\
+        \txor r12, r12\n\
+        \tlea r13, [rip + scratch]\n\
+        {}\n\
+        \tjmp bf_exit\n\
+        {RUNTIME}",
+        writer.scratch.max(1),
+        writer.lines.join("\n")
+    )
+}
@@ -0,0 +1,473 @@
+use std::{collections::BTreeSet, ffi::c_void, io};
+
+use super::{ *, super::interpreter::* };
+
+#[cfg(test)]
+mod tests {
+    use std::{cell::RefCell, collections::HashMap};
+
+    use super::*;
+    use super::super::{ tests::*, super::{ passes::*, optimiser::tests::* } };
+
+    // runs the program from the given tape and input with the jit, returning the final tape, head and output, and whether it halted
+    fn run_jit(bs: Vec<OptimisedBlock>, tape: &[(i32, u8)], input: &[u8]) -> (Vec<(i32, u8)>, i32, Vec<u8>, bool) {
+        let memory = RefCell::new(tape.iter().cloned().collect::<HashMap<i32, u8>>());
+        let input = RefCell::new(input.iter());
+        let output = RefCell::new(vec![]);
+        let mut ctx = BFCtx{
+            index: 0,
+            ask: || input.borrow_mut().next().cloned().unwrap_or(0),
+            put: |x| output.borrow_mut().push(x),
+            get: |i| *memory.borrow().get(& i).unwrap_or(&0),
+            set: |i, x| { memory.borrow_mut().insert(i, x); },
+            clear: || memory.borrow_mut().clear()
+        };
+        let halted = JitProgram::compile(& bs).unwrap().run(&mut ctx).unwrap();
+        let index = ctx.index;
+        let mut cells: Vec<(i32, u8)> = memory.into_inner().into_iter().filter(|(_, v)| *v != 0).collect();
+        cells.sort();
+        (cells, index, output.into_inner(), halted)
+    }
+
+    #[test]
+    fn programs() {
+        for (s, input) in PROGRAMS {
+            let (_, _, output, halted) = run_jit(optimising_convert(raw(s)), &[], input);
+            assert_eq!((output, halted), interpret(s, input), "{s} diverged");
+        }
+    }
+
+    #[test]
+    fn existing_tape() {
+        // the jit has to pick up the tape where the last run left it, as the repl keeps it between inputs
+        for s in [
+            "[>+[>+<-]<-]",
+            ",[.>[-]++++++++++.<,]",
+            ">,[>.<-]>>>.<<[>.<-]",
+            "++++[>+++<-]>[<+>>[-]<-]<.",
+            "<<<[>>>+<<<-]>>>[->+>+<<]>>[<<<<+>>>>-]"
+        ] {
+            for level in 0..=2 {
+                let bs = PassManager::with_level(level).run(convert(raw(s)));
+                let (tape, index, output, halted) = run_jit(bs, &[(-3, 5), (0, 2), (1, 7)], b"ab");
+                assert!(halted);
+                assert_eq!((tape, index, output), run_both(s, &[(-3, 5), (0, 2), (1, 7)], b"ab").0, "{s} diverged at -O{level}");
+            }
+        }
+    }
+
+    #[test]
+    fn aborts() {
+        let (tape, index, output, halted) = run_jit(optimising_convert(raw(">+.<+++[-->+<]")), &[], b"");
+        assert!(!halted);
+        // the blocks before the aborted effect still happened, but the effect itself stores nothing
+        assert_eq!((tape, index, output), (vec![(1, 1)], 0, vec![1]));
+    }
+
+    #[test]
+    fn unsolved_loops() {
+        // the inner loop is solved, but the outer one has to run for real
+        let s = "++++++++[->++++++++<]>[>++++++++[>++++<-]>[>+>+<<-]>[-<+>]<<<-]>>>>.";
+        let (_, _, output, halted) = run_jit(optimising_convert(raw(s)), &[], b"");
+        assert_eq!((output, halted), interpret(s, b""));
+    }
+}
+
+// linux's, as there's no libc crate to get them from
+const PROT_READ: i32 = 1;
+const PROT_WRITE: i32 = 2;
+const PROT_EXEC: i32 = 4;
+const MAP_PRIVATE: i32 = 0x2;
+const MAP_ANONYMOUS: i32 = 0x20;
+const MAP_NORESERVE: i32 = 0x4000;
+
+extern "C" {
+    fn mmap(addr: *mut c_void, len: usize, prot: i32, flags: i32, fd: i32, offset: i64) -> *mut c_void;
+    fn mprotect(addr: *mut c_void, len: usize, prot: i32) -> i32;
+    fn munmap(addr: *mut c_void, len: usize) -> i32;
+}
+
+// anonymous memory, only backed by pages once they are touched
+struct Mapping {
+    ptr: *mut u8,
+    len: usize
+}
+
+impl Mapping {
+    fn new(len: usize) -> io::Result<Mapping> {
+        let ptr = unsafe { mmap(std::ptr::null_mut(), len, PROT_READ | PROT_WRITE, MAP_PRIVATE | MAP_ANONYMOUS | MAP_NORESERVE, -1, 0) };
+        if ptr as isize == -1 {
+            return Err(io::Error::last_os_error());
+        }
+        Ok(Mapping{ ptr: ptr as *mut u8, len })
+    }
+}
+
+impl Drop for Mapping {
+    fn drop(&mut self) {
+        unsafe { munmap(self.ptr as *mut c_void, self.len); }
+    }
+}
+
+// every i32 is a valid cell index, so the tape covers all of them, with cell 0 in the middle
+const TAPE_LEN: usize = 1 << 32;
+const TAPE_MIDDLE: usize = 1 << 31;
+
+// what the compiled code is given, #[repr(C)] so that it can write `head` at offset 0 as it returns
+#[repr(C)]
+struct Env<'a> {
+    head: *mut u8, // the cell under the head when the code returned
+    tape: *mut u8, // cell 0
+    loaded: *mut u8, // cell 0 of a map, parallel to the tape, of the cells read from the context so far
+    ask: &'a mut dyn FnMut() -> u8,
+    put: &'a mut dyn FnMut(u8) -> (),
+    get: &'a mut dyn FnMut(i32) -> u8,
+    touched: Vec<(i32, u8)> // every cell loaded, with its value at the time
+}
+
+// the first time the code uses a cell, it is loaded from the context
+extern "C" fn jit_load(env: *mut Env, cell: *mut u8) -> () {
+    let env = unsafe { &mut *env };
+    let offset = unsafe { cell.offset_from(env.tape) };
+    let value = (env.get)(offset as i32);
+    unsafe {
+        *cell = value;
+        *env.loaded.offset(offset) = 1;
+    }
+    env.touched.push((offset as i32, value));
+}
+
+extern "C" fn jit_ask(env: *mut Env) -> u32 {
+    let env = unsafe { &mut *env };
+    (env.ask)() as u32
+}
+
+extern "C" fn jit_put(env: *mut Env, x: u32) -> () {
+    let env = unsafe { &mut *env };
+    (env.put)(x as u8)
+}
+
+// 256 when the division would never halt
+extern "C" fn jit_into(a: u32, b: u32) -> u32 {
+    div_u8(b as u8, a as u8).map(|q| q as u32).unwrap_or(256)
+}
+
+extern "C" fn jit_choose(x: u32, k: u32) -> u32 {
+    choose_u8(x as u8, k as u8) as u32
+}
+
+// registers kept by the compiled code, all callee-saved:
+//   rbx is the cell under the head, r12 the same place in the loaded map,
+//   r13 scratch space for an effect's temporaries and results, r14 the environment
+struct Assembler {
+    code: Vec<u8>,
+    scratch: usize, // bytes of scratch space the code needs
+    abort: Vec<usize> // jumps to patch to the abort path
+}
+
+impl Assembler {
+    fn emit(&mut self, bytes: &[u8]) -> () {
+        self.code.extend_from_slice(bytes);
+    }
+
+    fn emit_i32(&mut self, x: i32) -> () {
+        self.code.extend_from_slice(& x.to_le_bytes());
+    }
+
+    // a rel32 jump to be patched later, returning where its offset is
+    fn jump(&mut self, opcode: &[u8]) -> usize {
+        self.emit(opcode);
+        self.emit_i32(0);
+        self.code.len() - 4
+    }
+
+    fn patch(&mut self, at: usize, target: usize) -> () {
+        let rel = target as i32 - (at as i32 + 4);
+        self.code[at..at + 4].copy_from_slice(& rel.to_le_bytes());
+    }
+
+    // calls a helper, keeping the stack 16-byte aligned as the abi needs, with `depth` values pushed since it last was
+    fn call(&mut self, f: *const (), depth: usize) -> () {
+        if depth % 2 == 1 { self.emit(&[0x48, 0x83, 0xec, 0x08]); } // sub rsp, 8
+        self.emit(&[0x48, 0xb8]); // mov rax, f
+        self.code.extend_from_slice(& (f as u64).to_le_bytes());
+        self.emit(&[0xff, 0xd0]); // call rax
+        if depth % 2 == 1 { self.emit(&[0x48, 0x83, 0xc4, 0x08]); } // add rsp, 8
+    }
+
+    // makes sure a cell has been loaded from the context
+    fn touch(&mut self, register: i32) -> () {
+        self.emit(&[0x41, 0x80, 0xbc, 0x24]); // cmp byte [r12 + register], 0
+        self.emit_i32(register);
+        self.emit(&[0x00]);
+        let skip = self.jump(&[0x0f, 0x85]); // jne skip
+        self.emit(&[0x4c, 0x89, 0xf7]); // mov rdi, r14
+        self.emit(&[0x48, 0x8d, 0xb3]); // lea rsi, [rbx + register]
+        self.emit_i32(register);
+        self.call(jit_load as *const (), 0);
+        let here = self.code.len();
+        self.patch(skip, here);
+    }
+
+    fn load_cell(&mut self, register: i32) -> () {
+        self.emit(&[0x0f, 0xb6, 0x83]); // movzx eax, byte [rbx + register]
+        self.emit_i32(register);
+    }
+
+    fn store_cell(&mut self, register: i32) -> () {
+        self.emit(&[0x88, 0x83]); // mov [rbx + register], al
+        self.emit_i32(register);
+    }
+
+    fn load_scratch(&mut self, i: usize) -> () {
+        self.emit(&[0x41, 0x0f, 0xb6, 0x85]); // movzx eax, byte [r13 + i]
+        self.emit_i32(i as i32);
+    }
+
+    fn store_scratch(&mut self, i: usize) -> () {
+        self.emit(&[0x41, 0x88, 0x85]); // mov [r13 + i], al
+        self.emit_i32(i as i32);
+    }
+
+    // leaves the expression's value in al
+    fn expr(&mut self, expr: & Expr, temporaries: & HashMap<Expr, usize>, depth: usize) -> () {
+        if let Some(i) = temporaries.get(expr) {
+            return self.load_scratch(*i);
+        }
+        match expr.as_ref() {
+            ProcExpr::Lit(x) => { self.emit(&[0xb8]); self.emit_i32(*x as i32); }, // mov eax, x
+            ProcExpr::Reg(r) => self.load_cell(*r),
+            ProcExpr::Add(a, b) | ProcExpr::Mul(a, b) => {
+                self.expr(a, temporaries, depth);
+                self.emit(&[0x50]); // push rax
+                self.expr(b, temporaries, depth + 1);
+                self.emit(&[0x59]); // pop rcx
+                match expr.as_ref() {
+                    ProcExpr::Add(..) => self.emit(&[0x01, 0xc8]), // add eax, ecx
+                    _ => self.emit(&[0x0f, 0xaf, 0xc1]) // imul eax, ecx
+                }
+            },
+            ProcExpr::Into(a, b) => {
+                self.expr(a, temporaries, depth);
+                self.emit(&[0x50]); // push rax
+                self.expr(b, temporaries, depth + 1);
+                self.emit(&[0x0f, 0xb6, 0xf0]); // movzx esi, al
+                self.emit(&[0x59]); // pop rcx
+                self.emit(&[0x0f, 0xb6, 0xf9]); // movzx edi, cl
+                self.call(jit_into as *const (), depth);
+                self.emit(&[0x3d]); // cmp eax, 256
+                self.emit_i32(256);
+                let abort = self.jump(&[0x0f, 0x84]); // je abort
+                self.abort.push(abort);
+            },
+            ProcExpr::Choose(a, k) => {
+                self.expr(a, temporaries, depth);
+                self.emit(&[0x0f, 0xb6, 0xf8]); // movzx edi, al
+                self.emit(&[0xbe]); // mov esi, k
+                self.emit_i32(*k as i32);
+                self.call(jit_choose as *const (), depth);
+            },
+            ProcExpr::If(c, a, b) => {
+                self.expr(c, temporaries, depth);
+                self.emit(&[0x84, 0xc0]); // test al, al
+                let otherwise = self.jump(&[0x0f, 0x84]); // je otherwise
+                self.expr(a, temporaries, depth);
+                let end = self.jump(&[0xe9]); // jmp end
+                let here = self.code.len();
+                self.patch(otherwise, here);
+                self.expr(b, temporaries, depth);
+                let here = self.code.len();
+                self.patch(end, here);
+            },
+            ProcExpr::IsZero(a) => {
+                self.expr(a, temporaries, depth);
+                self.emit(&[0x84, 0xc0]); // test al, al
+                self.emit(&[0x0f, 0x94, 0xc0]); // sete al
+            }
+        }
+    }
+
+    fn shift(&mut self, offset: i32) -> () {
+        self.emit(&[0x48, 0x81, 0xc3]); // add rbx, offset
+        self.emit_i32(offset);
+        self.emit(&[0x49, 0x81, 0xc4]); // add r12, offset
+        self.emit_i32(offset);
+    }
+
+    fn block(&mut self, b: & OptimisedBlock) -> () {
+        match b {
+            OptimisedBlock::Ask(register) => {
+                self.touch(*register);
+                self.emit(&[0x4c, 0x89, 0xf7]); // mov rdi, r14
+                self.call(jit_ask as *const (), 0);
+                self.store_cell(*register);
+            },
+            OptimisedBlock::Put(register) => {
+                self.touch(*register);
+                self.load_cell(*register);
+                self.emit(&[0x0f, 0xb6, 0xf0]); // movzx esi, al
+                self.emit(&[0x4c, 0x89, 0xf7]); // mov rdi, r14
+                self.call(jit_put as *const (), 0);
+            },
+            OptimisedBlock::AtomicEffect(lines, effect) => {
+                let mut registers = BTreeSet::new();
+                fn used(expr: & ProcExpr, registers: &mut BTreeSet<i32>) -> () {
+                    match expr {
+                        ProcExpr::Lit(_) => (),
+                        ProcExpr::Reg(r) => { registers.insert(*r); },
+                        ProcExpr::Add(a, b) | ProcExpr::Mul(a, b) | ProcExpr::Into(a, b) => { used(a, registers); used(b, registers); },
+                        ProcExpr::Choose(a, _) | ProcExpr::IsZero(a) => used(a, registers),
+                        ProcExpr::If(c, a, b) => { used(c, registers); used(a, registers); used(b, registers); }
+                    }
+                }
+                for (register, expr) in lines {
+                    registers.insert(*register);
+                    used(expr, &mut registers);
+                }
+                for register in registers {
+                    self.touch(register);
+                }
+
+                let shared = effect_temporaries(lines);
+                let mut temporaries = HashMap::new();
+                for (i, expr) in shared.iter().enumerate() {
+                    self.expr(expr, & temporaries, 0);
+                    self.store_scratch(i);
+                    temporaries.insert(expr.clone(), i);
+                }
+                for (i, expr) in lines.values().enumerate() {
+                    self.expr(expr, & temporaries, 0);
+                    self.store_scratch(shared.len() + i);
+                }
+                for (i, register) in lines.keys().enumerate() {
+                    self.load_scratch(shared.len() + i);
+                    self.store_cell(*register);
+                }
+                self.scratch = self.scratch.max(shared.len() + lines.len());
+                if *effect != 0 { self.shift(*effect); }
+            },
+            OptimisedBlock::Loop(register, bs, _) => {
+                let top = self.code.len();
+                self.touch(*register);
+                self.emit(&[0x80, 0xbb]); // cmp byte [rbx + register], 0
+                self.emit_i32(*register);
+                self.emit(&[0x00]);
+                let end = self.jump(&[0x0f, 0x84]); // je end
+                for b_ in bs { self.block(b_); }
+                let back = self.jump(&[0xe9]); // jmp top
+                self.patch(back, top);
+                let here = self.code.len();
+                self.patch(end, here);
+            },
+            OptimisedBlock::If(register, bs) => {
+                self.touch(*register);
+                self.emit(&[0x80, 0xbb]); // cmp byte [rbx + register], 0
+                self.emit_i32(*register);
+                self.emit(&[0x00]);
+                let end = self.jump(&[0x0f, 0x84]); // je end
+                for b_ in bs { self.block(b_); }
+                let here = self.code.len();
+                self.patch(end, here);
+            }
+        }
+    }
+}
+
+// optimised bytecode compiled to x86-64 machine code, which can be run any number of times
+pub struct JitProgram {
+    code: Mapping,
+    scratch: usize
+}
+
+impl JitProgram {
+    pub fn compile(bs: & Vec<OptimisedBlock>) -> io::Result<JitProgram> {
+        let mut assembler = Assembler{ code: vec![], scratch: 0, abort: vec![] };
+        // fn(env: rdi, head: rsi, loaded head: rdx, scratch: rcx) -> eax, 1 when it halted and 0 when it aborted
+        assembler.emit(&[0x55, 0x48, 0x89, 0xe5]); // push rbp; mov rbp, rsp
+        assembler.emit(&[0x53, 0x41, 0x54, 0x41, 0x55, 0x41, 0x56, 0x41, 0x57]); // push rbx, r12, r13, r14, r15
+        assembler.emit(&[0x48, 0x83, 0xec, 0x08]); // sub rsp, 8, to align the stack
+        assembler.emit(&[0x49, 0x89, 0xfe, 0x48, 0x89, 0xf3, 0x49, 0x89, 0xd4, 0x49, 0x89, 0xcd]); // mov r14, rdi; mov rbx, rsi; mov r12, rdx; mov r13, rcx
+        for b in bs { assembler.block(b); }
+        assembler.emit(&[0xb8]); // mov eax, 1
+        assembler.emit_i32(1);
+        let done = assembler.jump(&[0xe9]); // jmp done
+        let abort = assembler.code.len();
+        assembler.emit(&[0x31, 0xc0]); // xor eax, eax
+        let here = assembler.code.len();
+        assembler.patch(done, here);
+        assembler.emit(&[0x49, 0x89, 0x1e]); // mov [r14], rbx
+        assembler.emit(&[0x48, 0x8d, 0x65, 0xd8]); // lea rsp, [rbp - 40]
+        assembler.emit(&[0x41, 0x5f, 0x41, 0x5e, 0x41, 0x5d, 0x41, 0x5c, 0x5b, 0x5d, 0xc3]); // pop r15, r14, r13, r12, rbx, rbp; ret
+        for at in std::mem::take(&mut assembler.abort) {
+            assembler.patch(at, abort);
+        }
+
+        let code = Mapping::new(assembler.code.len())?;
+        unsafe {
+            std::ptr::copy_nonoverlapping(assembler.code.as_ptr(), code.ptr, assembler.code.len());
+            if mprotect(code.ptr as *mut c_void, code.len, PROT_READ | PROT_EXEC) != 0 {
+                return Err(io::Error::last_os_error());
+            }
+        }
+        Ok(JitProgram{ code, scratch: assembler.scratch })
+    }
+
+    // runs the program on the context like `run_bfoptimised`, returning whether it halted
+    // fails without running anything if the tape can't be mapped
+    pub fn run<
+        Ask: FnMut() -> u8,
+        Put: FnMut(u8) -> (),
+        Get: FnMut(i32) -> u8,
+        Set: FnMut(i32, u8) -> (),
+        Clear: Fn() -> ()
+    >(
+        &self,
+        ctx: &mut BFCtx<Ask, Put, Get, Set, Clear>
+    )
+        -> io::Result<bool>
+    {
+        let tape = Mapping::new(TAPE_LEN)?;
+        let loaded = Mapping::new(TAPE_LEN)?;
+        let mut scratch = vec![0u8; self.scratch.max(1)];
+        let (tape_0, loaded_0) = unsafe { (tape.ptr.add(TAPE_MIDDLE), loaded.ptr.add(TAPE_MIDDLE)) };
+        let mut env = Env{
+            head: std::ptr::null_mut(),
+            tape: tape_0,
+            loaded: loaded_0,
+            ask: &mut ctx.ask,
+            put: &mut ctx.put,
+            get: &mut ctx.get,
+            touched: vec![]
+        };
+        let halted = unsafe {
+            let f: extern "C" fn(*mut Env, *mut u8, *mut u8, *mut u8) -> u32 = std::mem::transmute(self.code.ptr);
+            f(&mut env, tape_0.offset(ctx.index as isize), loaded_0.offset(ctx.index as isize), scratch.as_mut_ptr())
+        } != 0;
+        let index = unsafe { env.head.offset_from(tape_0) } as i32;
+        for (i, before) in std::mem::take(&mut env.touched) {
+            let after = unsafe { *tape_0.offset(i as isize) };
+            if after != before { (ctx.set)(i, after); }
+        }
+        ctx.index = index;
+        Ok(halted)
+    }
+}
+
+// a drop-in alternative to `run_bfoptimised`, falling back to it when the jit can't get the memory it needs
+pub fn jit_run_bfoptimised<
+    Ask: FnMut() -> u8,
+    Put: FnMut(u8) -> (),
+    Get: FnMut(i32) -> u8,
+    Set: FnMut(i32, u8) -> (),
+    Clear: Fn() -> ()
+>(
+    ctx: &mut BFCtx<Ask, Put, Get, Set, Clear>,
+    bs: Vec<OptimisedBlock>
+)
+    -> bool
+{
+    match JitProgram::compile(& bs).and_then(|program| program.run(ctx)) {
+        Ok(halted) => halted,
+        Err(_) => run_bfoptimised(ctx, bs)
+    }
+}
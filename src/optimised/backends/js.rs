@@ -0,0 +1,183 @@
+use std::collections::HashMap;
+
+use super::*;
+
+#[cfg(test)]
+mod tests {
+    use std::process::Command;
+
+    use super::*;
+    use super::super::{ tests::*, super::optimiser::tests::* };
+
+    // a script that runs the module on stdin, writing its output to stdout, and exiting with 1 when it aborts
+    const RUNNER: &str = r#"import { readFileSync } from "node:fs";
+import { run, NonHaltingError } from "./program.mjs";
+try {
+	process.stdout.write(run(new Uint8Array(readFileSync(0))));
+} catch (error) {
+	if (!(error instanceof NonHaltingError)) throw error;
+	process.stdout.write(error.output);
+	process.exitCode = 1;
+}
+"#;
+
+    #[test]
+    fn node_programs() {
+        if !command_exists("node") { eprintln!("no node, skipping"); return; }
+        for (i, (s, input)) in PROGRAMS.iter().enumerate() {
+            let dir = scratch_dir(& format!("js{i}"));
+            std::fs::write(dir.join("program.mjs"), emit_js(& optimising_convert(raw(s)))).unwrap();
+            std::fs::write(dir.join("runner.mjs"), RUNNER).unwrap();
+            assert_eq!(run_with_input(Command::new("node").arg(dir.join("runner.mjs")), input), interpret(s, input), "{s} diverged");
+        }
+    }
+
+    #[test]
+    fn stable() {
+        let bs = optimising_convert(raw(",[->++<]>."));
+        assert_eq!(emit_js(& bs), emit_js(& bs.clone()));
+        assert_eq!(
+            emit_js(& bs).split("\tlet head = 0;\n").nth(1).unwrap(),
+            r#"	tape[(head + 0) & 65535] = ask();
+	{
+		const v0 = 0;
+		const v1 = ((((2 * tape[(head + 0) & 65535]) & 255) + tape[(head + 1) & 65535]) & 255);
+		tape[(head + 0) & 65535] = v0;
+		tape[(head + 1) & 65535] = v1;
+	}
+	output.push(tape[(head + 1) & 65535]);
+	head = (head + 1) & 65535;
+	return Uint8Array.from(output);
+}
+"#
+        );
+    }
+}
+
+pub const TAPE_SIZE: usize = 1 << 16; // the tape wraps around, so this must be a power of two
+
+const PRELUDE: &str = r#"// generated from optimised brainfuck bytecode
+
+export const TAPE_SIZE = TAPE_SIZE_VALUE;
+
+// thrown when the program runs a loop that would never halt, carrying the output written before it
+export class NonHaltingError extends Error {
+	constructor(output) {
+		super("aborted non-halting loop");
+		this.name = "NonHaltingError";
+		this.output = output;
+	}
+}
+
+function into(a, b, output) {
+	if (b === 0) return 0;
+	if (a === 0) throw new NonHaltingError(Uint8Array.from(output));
+	let shift = 0;
+	while (!(a & 1)) {
+		if (b & 1) throw new NonHaltingError(Uint8Array.from(output));
+		a >>= 1;
+		b >>= 1;
+		shift++;
+	}
+	let inverse = a;
+	for (let i = 0; i < 3; i++) inverse = (inverse * ((2 - a * inverse) & 255)) & 255;
+	return ((b * inverse) & 255) & (255 >> shift);
+}
+
+function choose(x, k) {
+	const row = new Uint8Array(k + 1);
+	row[0] = 1;
+	for (let i = 0; i < x; i++) {
+		for (let j = k; j > 0; j--) row[j] = row[j] + row[j - 1];
+	}
+	return row[k];
+}
+"#;
+
+fn js_cell(
+    register: i32
+)
+    -> String
+{
+    format!("tape[(head + {register}) & {}]", TAPE_SIZE - 1)
+}
+
+fn js_expr(
+    expr: & ProcExpr,
+    temporaries: & HashMap<Expr, usize>
+)
+    -> String
+{
+    let show = |x: & Expr| match temporaries.get(x) {
+        Some(i) => format!("t{i}"),
+        None => js_expr(x, temporaries)
+    };
+    match expr {
+        ProcExpr::Lit(x) => format!("{x}"),
+        ProcExpr::Reg(r) => js_cell(*r),
+        // parenthesised whole, as `&` binds more loosely than `+`
+        ProcExpr::Add(a, b) => format!("(({} + {}) & 255)", show(a), show(b)),
+        ProcExpr::Mul(a, b) => format!("(({} * {}) & 255)", show(a), show(b)),
+        ProcExpr::Into(a, b) => format!("into({}, {}, output)", show(a), show(b)),
+        ProcExpr::Choose(a, k) => format!("choose({}, {k})", show(a)),
+        ProcExpr::If(c, a, b) => format!("({} !== 0 ? {} : {})", show(c), show(a), show(b)),
+        ProcExpr::IsZero(a) => format!("({} === 0 ? 1 : 0)", show(a)),
+    }
+}
+
+fn js_block(
+    b: & OptimisedBlock
+)
+    -> String
+{
+    match b {
+        OptimisedBlock::Ask(register) => format!("{} = ask();", js_cell(*register)),
+        OptimisedBlock::Put(register) => format!("output.push({});", js_cell(*register)),
+        OptimisedBlock::AtomicEffect(lines, effect) if lines.is_empty() => format!("head = (head + {effect}) & {};", TAPE_SIZE - 1),
+        OptimisedBlock::AtomicEffect(lines, effect) => {
+            let shared = effect_temporaries(lines);
+            let temporaries: HashMap<Expr, usize> = shared.iter().enumerate().map(|(i, expr)| (expr.clone(), i)).collect();
+            let statements = shared.iter().enumerate().map(|(i, expr)| format!("const t{i} = {};", js_expr(expr, & temporaries)))
+                .chain(lines.values().enumerate().map(|(i, expr)| match temporaries.get(expr) {
+                    Some(j) => format!("const v{i} = t{j};"),
+                    None => format!("const v{i} = {};", js_expr(expr, & temporaries))
+                }))
+                .chain(lines.keys().enumerate().map(|(i, register)| format!("{} = v{i};", js_cell(*register))))
+                .chain((*effect != 0).then(|| format!("head = (head + {effect}) & {};", TAPE_SIZE - 1)));
+            format!("{{\n{}\n}}", indent_string(join_strings(statements)))
+        },
+        OptimisedBlock::Loop(register, bs, _) => format!("while ({} !== 0) {{\n{}\n}}", js_cell(*register), indent_string(js_blocks(bs))),
+        OptimisedBlock::If(register, bs) => format!("if ({} !== 0) {{\n{}\n}}", js_cell(*register), indent_string(js_blocks(bs)))
+    }
+}
+
+fn js_blocks(
+    bs: & Vec<OptimisedBlock>
+)
+    -> String
+{
+    join_strings(bs.iter().map(js_block))
+}
+
+// an es module exporting `run(input: Uint8Array) => Uint8Array`, which runs the program on a fresh tape, giving 0 past the end of the input
+// it throws a `NonHaltingError` with the output so far when the bytecode aborts a loop that would never halt
+// names are numbered in the order they appear, so the same bytecode always gives the same module
+pub fn emit_js(
+    bs: & Vec<OptimisedBlock>
+)
+    -> String
+{
+    format!(
+        "{}\nexport function run(input) {{\n\
+        \tconst tape = new Uint8Array(TAPE_SIZE);\n\
+        \tconst output = [];\n\
+        \tlet read = 0;\n\
+        \tconst ask = () => read < input.length ? input[read++] : 0;\n\
+        \tlet head = 0;\n\
+        {}\n\
+        \treturn Uint8Array.from(output);\n\
+        }}\n",
+        PRELUDE.replace("TAPE_SIZE_VALUE", & TAPE_SIZE.to_string()),
+        indent_string(js_blocks(bs))
+    )
+}
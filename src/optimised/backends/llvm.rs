@@ -0,0 +1,334 @@
+use std::collections::HashMap;
+
+use super::*;
+
+#[cfg(test)]
+mod tests {
+    use std::{collections::HashSet, process::Command};
+
+    use super::*;
+    use super::super::{ tests::*, super::optimiser::tests::* };
+
+    // every block ends in a terminator, every value is defined once and every label branched to exists
+    fn well_formed(ir: &str) -> Result<(), String> {
+        let (mut values, mut labels, mut targets) = (HashSet::new(), HashSet::new(), vec![]);
+        let mut terminated = true;
+        for line in ir.lines().map(|line| line.trim()).filter(|line| !line.is_empty() && !line.starts_with(';')) {
+            if line.starts_with("define") {
+                (labels, terminated) = (HashSet::new(), true);
+                values.clear();
+            } else if line == "}" {
+                if !terminated { return Err("function ends without a terminator".to_string()); }
+                for target in targets.drain(..) {
+                    if !labels.contains(& target) { return Err(format!("branch to missing label {target}")); }
+                }
+            } else if let Some(label) = line.strip_suffix(':') {
+                if !terminated { return Err(format!("block before {label} has no terminator")); }
+                if !labels.insert(label.to_string()) { return Err(format!("label {label} defined twice")); }
+                terminated = false;
+            } else if !line.starts_with('@') && !line.starts_with("declare") {
+                if let Some((value, _)) = line.split_once(" = ") {
+                    if !values.insert(value.to_string()) { return Err(format!("{value} defined twice")); }
+                }
+                targets.extend(line.split("label %").skip(1).map(|s| s.split(|c: char| c == ',' || c == ' ' || c == ']').next().unwrap().to_string()));
+                terminated = line.starts_with("br ") || line.starts_with("ret ") || line == "unreachable";
+            }
+        }
+        Ok(())
+    }
+
+    // lli only reads `ptr` from llvm 15 on, and needs it turned on before that
+    fn lli() -> Option<Command> {
+        let version = Command::new("lli").arg("--version").output().ok()?;
+        let version = String::from_utf8_lossy(& version.stdout).to_string();
+        let major: u32 = version.split("version ").nth(1)?.split('.').next()?.trim().parse().ok()?;
+        let mut command = Command::new("lli");
+        if major < 15 { command.arg("-opaque-pointers"); }
+        Some(command)
+    }
+
+    #[test]
+    fn structure() {
+        for (s, _) in PROGRAMS {
+            let ir = emit_llvm(& optimising_convert(raw(s)));
+            assert_eq!(well_formed(& ir), Ok(()), "{s} gave malformed ir:\n{ir}");
+            assert!(ir.contains("define i32 @main()"));
+        }
+        // an odd step is divided out at compile time, but an even one may never halt
+        assert!(!emit_llvm(& optimising_convert(raw(",[--->+<]>."))).contains("call i8 @bf_into"));
+        assert!(emit_llvm(& optimising_convert(raw(",[-->+<]>."))).contains("call i8 @bf_into(i8 2, i8 "));
+    }
+
+    #[test]
+    fn executed_programs() {
+        let Some(_) = lli() else { eprintln!("no lli, skipping"); return; };
+        let dir = scratch_dir("llvm");
+        for (i, (s, input)) in PROGRAMS.iter().enumerate() {
+            let file = dir.join(format!("program{i}.ll"));
+            std::fs::write(& file, emit_llvm(& optimising_convert(raw(s)))).unwrap();
+            assert_eq!(run_with_input(lli().unwrap().arg(& file), input), interpret(s, input), "{s} diverged");
+        }
+    }
+}
+
+pub const TAPE_SIZE: usize = 1 << 16; // the tape wraps around, so this must be a power of two
+
+const RUNTIME: &str = r#"
+@abort_message = private constant [25 x i8] c"aborted non-halting loop\0A"
+
+declare i32 @getchar()
+declare i32 @putchar(i32)
+declare i64 @write(i32, ptr, i64)
+declare void @exit(i32) noreturn
+declare i8 @llvm.cttz.i8(i8, i1)
+
+define internal void @bf_abort() noreturn {
+entry:
+  call i64 @write(i32 2, ptr @abort_message, i64 25)
+  call void @exit(i32 1)
+  unreachable
+}
+
+define internal i8 @bf_into(i8 %a, i8 %b) {
+entry:
+  %b.zero = icmp eq i8 %b, 0
+  br i1 %b.zero, label %zero, label %nonzero
+zero:
+  ret i8 0
+nonzero:
+  %a.zero = icmp eq i8 %a, 0
+  br i1 %a.zero, label %abort, label %divisible
+divisible:
+  %shift = call i8 @llvm.cttz.i8(i8 %a, i1 true)
+  %b.shift = call i8 @llvm.cttz.i8(i8 %b, i1 true)
+  %indivisible = icmp ult i8 %b.shift, %shift
+  br i1 %indivisible, label %abort, label %divide
+divide:
+  %x = lshr i8 %b, %shift
+  %y = lshr i8 %a, %shift
+  %p0 = mul i8 %y, %y
+  %s0 = sub i8 2, %p0
+  %i1 = mul i8 %y, %s0
+  %p1 = mul i8 %y, %i1
+  %s1 = sub i8 2, %p1
+  %i2 = mul i8 %i1, %s1
+  %p2 = mul i8 %y, %i2
+  %s2 = sub i8 2, %p2
+  %i3 = mul i8 %i2, %s2
+  %q = mul i8 %x, %i3
+  %mask = lshr i8 -1, %shift
+  %result = and i8 %q, %mask
+  ret i8 %result
+abort:
+  call void @bf_abort()
+  unreachable
+}
+
+define internal i8 @bf_choose(i8 %x, i8 %k) {
+entry:
+  %row = alloca [256 x i8]
+  store [256 x i8] zeroinitializer, ptr %row
+  store i8 1, ptr %row
+  %x.wide = zext i8 %x to i64
+  %k.wide = zext i8 %k to i64
+  br label %rows
+rows:
+  %i = phi i64 [ 0, %entry ], [ %i.next, %row.done ]
+  %more = icmp ult i64 %i, %x.wide
+  br i1 %more, label %columns, label %done
+columns:
+  %j = phi i64 [ %k.wide, %rows ], [ %j.next, %column ]
+  %first = icmp eq i64 %j, 0
+  br i1 %first, label %row.done, label %column
+column:
+  %j.next = sub i64 %j, 1
+  %here = getelementptr inbounds [256 x i8], ptr %row, i64 0, i64 %j
+  %left = getelementptr inbounds [256 x i8], ptr %row, i64 0, i64 %j.next
+  %here.value = load i8, ptr %here
+  %left.value = load i8, ptr %left
+  %sum = add i8 %here.value, %left.value
+  store i8 %sum, ptr %here
+  br label %columns
+row.done:
+  %i.next = add i64 %i, 1
+  br label %rows
+done:
+  %result.ptr = getelementptr inbounds [256 x i8], ptr %row, i64 0, i64 %k.wide
+  %result = load i8, ptr %result.ptr
+  ret i8 %result
+}
+"#;
+
+struct Function {
+    lines: Vec<String>,
+    values: usize,
+    labels: usize
+}
+
+impl Function {
+    fn emit(&mut self, line: String) -> () {
+        self.lines.push(format!("  {line}"));
+    }
+
+    fn value(&mut self) -> String {
+        self.values += 1;
+        format!("%v{}", self.values)
+    }
+
+    fn label(&mut self) -> String {
+        self.labels += 1;
+        format!("L{}", self.labels)
+    }
+
+    fn place(&mut self, label: & String) -> () {
+        self.lines.push(format!("{label}:"));
+    }
+
+    // a pointer to the cell, wrapping around the tape
+    fn cell(&mut self, register: i32) -> String {
+        let (head, index, wrapped, cell) = (self.value(), self.value(), self.value(), self.value());
+        self.emit(format!("{head} = load i64, ptr %head"));
+        self.emit(format!("{index} = add i64 {head}, {register}"));
+        self.emit(format!("{wrapped} = and i64 {index}, {}", TAPE_SIZE - 1));
+        self.emit(format!("{cell} = getelementptr inbounds [{TAPE_SIZE} x i8], ptr @tape, i64 0, i64 {wrapped}"));
+        cell
+    }
+
+    fn load(&mut self, register: i32) -> String {
+        let (cell, value) = (self.cell(register), self.value());
+        self.emit(format!("{value} = load i8, ptr {cell}"));
+        value
+    }
+
+    // the expression's value, with the label of the block it ends in
+    fn expr(&mut self, expr: & Expr, temporaries: & HashMap<Expr, String>, block: String) -> (String, String) {
+        if let Some(value) = temporaries.get(expr) {
+            return (value.clone(), block);
+        }
+        match expr.as_ref() {
+            ProcExpr::Lit(x) => ((*x as i8).to_string(), block),
+            ProcExpr::Reg(r) => (self.load(*r), block),
+            ProcExpr::Add(a, b) | ProcExpr::Mul(a, b) | ProcExpr::Into(a, b) => {
+                let (x, block) = self.expr(a, temporaries, block);
+                let (y, block) = self.expr(b, temporaries, block);
+                let value = self.value();
+                match expr.as_ref() {
+                    ProcExpr::Add(..) => self.emit(format!("{value} = add i8 {x}, {y}")),
+                    ProcExpr::Mul(..) => self.emit(format!("{value} = mul i8 {x}, {y}")),
+                    _ => self.emit(format!("{value} = call i8 @bf_into(i8 {x}, i8 {y})"))
+                }
+                (value, block)
+            },
+            ProcExpr::Choose(a, k) => {
+                let (x, block) = self.expr(a, temporaries, block);
+                let value = self.value();
+                self.emit(format!("{value} = call i8 @bf_choose(i8 {x}, i8 {})", *k as i8));
+                (value, block)
+            },
+            ProcExpr::If(c, a, b) => {
+                // only the side chosen is worked out, as the other may not halt
+                let (x, _) = self.expr(c, temporaries, block);
+                let (test, then, otherwise, end) = (self.value(), self.label(), self.label(), self.label());
+                self.emit(format!("{test} = icmp ne i8 {x}, 0"));
+                self.emit(format!("br i1 {test}, label %{then}, label %{otherwise}"));
+                self.place(& then);
+                let (y, then_end) = self.expr(a, temporaries, then);
+                self.emit(format!("br label %{end}"));
+                self.place(& otherwise);
+                let (z, otherwise_end) = self.expr(b, temporaries, otherwise);
+                self.emit(format!("br label %{end}"));
+                self.place(& end);
+                let value = self.value();
+                self.emit(format!("{value} = phi i8 [ {y}, %{then_end} ], [ {z}, %{otherwise_end} ]"));
+                (value, end)
+            },
+            ProcExpr::IsZero(a) => {
+                let (x, block) = self.expr(a, temporaries, block);
+                let (test, value) = (self.value(), self.value());
+                self.emit(format!("{test} = icmp eq i8 {x}, 0"));
+                self.emit(format!("{value} = zext i1 {test} to i8"));
+                (value, block)
+            }
+        }
+    }
+
+    // the label of the block it ends in
+    fn block(&mut self, b: & OptimisedBlock, block: String) -> String {
+        match b {
+            OptimisedBlock::Ask(register) => {
+                let (read, eof, byte, value) = (self.value(), self.value(), self.value(), self.value());
+                self.emit(format!("{read} = call i32 @getchar()"));
+                self.emit(format!("{eof} = icmp slt i32 {read}, 0"));
+                self.emit(format!("{byte} = trunc i32 {read} to i8"));
+                self.emit(format!("{value} = select i1 {eof}, i8 0, i8 {byte}"));
+                let cell = self.cell(*register);
+                self.emit(format!("store i8 {value}, ptr {cell}"));
+                block
+            },
+            OptimisedBlock::Put(register) => {
+                let (value, wide) = (self.load(*register), self.value());
+                self.emit(format!("{wide} = zext i8 {value} to i32"));
+                self.emit(format!("call i32 @putchar(i32 {wide})"));
+                block
+            },
+            OptimisedBlock::AtomicEffect(lines, effect) => {
+                let mut block = block;
+                let mut temporaries = HashMap::new();
+                for expr in effect_temporaries(lines) {
+                    let value;
+                    (value, block) = self.expr(& expr, & temporaries, block);
+                    temporaries.insert(expr, value);
+                }
+                let mut values = vec![];
+                for expr in lines.values() {
+                    let value;
+                    (value, block) = self.expr(expr, & temporaries, block);
+                    values.push(value);
+                }
+                for (register, value) in lines.keys().zip(values) {
+                    let cell = self.cell(*register);
+                    self.emit(format!("store i8 {value}, ptr {cell}"));
+                }
+                if *effect != 0 {
+                    let (head, moved) = (self.value(), self.value());
+                    self.emit(format!("{head} = load i64, ptr %head"));
+                    self.emit(format!("{moved} = add i64 {head}, {effect}"));
+                    self.emit(format!("store i64 {moved}, ptr %head"));
+                }
+                block
+            },
+            OptimisedBlock::Loop(register, bs, _) | OptimisedBlock::If(register, bs) => {
+                let (test, body, end) = (self.label(), self.label(), self.label());
+                let is_loop = matches!(b, OptimisedBlock::Loop(..));
+                self.emit(format!("br label %{test}"));
+                self.place(& test);
+                let (value, nonzero) = (self.load(*register), self.value());
+                self.emit(format!("{nonzero} = icmp ne i8 {value}, 0"));
+                self.emit(format!("br i1 {nonzero}, label %{body}, label %{end}"));
+                self.place(& body);
+                let mut block = body;
+                for b_ in bs { block = self.block(b_, block); }
+                self.emit(format!("br label %{}", if is_loop { & test } else { & end }));
+                self.place(& end);
+                end
+            }
+        }
+    }
+}
+
+// a textual llvm ir module for the program, defining `main`, that reads stdin and writes stdout through libc
+// it uses opaque pointers, so needs llvm 15 or later (or `-opaque-pointers` on llvm 14), and can be run with `lli` or built with `clang`
+// it exits with status 1 when the bytecode aborts a loop that would never halt
+pub fn emit_llvm(
+    bs: & Vec<OptimisedBlock>
+)
+    -> String
+{
+    let mut function = Function{ lines: vec![], values: 0, labels: 0 };
+    let mut block = "entry".to_string();
+    for b in bs { block = function.block(b, block); }
+    format!(
+        "; generated from optimised brainfuck bytecode\n\n@tape = internal global [{TAPE_SIZE} x i8] zeroinitializer\n{RUNTIME}\ndefine i32 @main() {{\nentry:\n  %head = alloca i64\n  store i64 0, ptr %head\n{}\n  ret i32 0\n}}\n",
+        function.lines.join("\n")
+    )
+}
@@ -0,0 +1,101 @@
+use std::collections::BTreeMap;
+
+use super::{ *, optimiser::* };
+
+pub mod bf;
+pub mod c;
+pub mod gas;
+#[cfg(all(target_arch = "x86_64", target_os = "linux"))]
+pub mod jit;
+pub mod js;
+pub mod llvm;
+pub mod rust;
+pub mod wat;
+
+#[cfg(test)]
+pub mod tests {
+    use std::{cell::RefCell, collections::HashMap, path::PathBuf, process::{Command, Stdio}, io::Write};
+
+    use super::super::{ interpreter::*, optimiser::tests::* };
+    use super::*;
+
+    // programs every backend is checked against, with their input
+    pub const PROGRAMS: [(&str, &[u8]); 12] = [
+        ("++++++++[>++++[>++>+++>+++>+<<<<-]>+>+>->>+[<]<-]>>.>---.+++++++..+++.>>.<-.<.+++.------.--------.>>+.>++.", b""),
+        (",[.,]", b"hello"),
+        (",>,<[>[->+>+<<]>>[-<<+>>]<<<-]>>.", &[7, 9]),
+        (",[--->+<]>.", &[10]),
+        (",[-->+<]>.", &[10]),
+        (",[------>+<]>.", &[30]),
+        ("+.,[-->+<]>.", &[9]), // never halts
+        (",[>+[->+>+<<]>>[-<<+>>]<<<-]>>.", &[20]),
+        ("++++++++[>++++++++<-]>[.-]", b""),
+        ("<<+++[>+<-]>.<.", b""),
+        (",[>+<[-]]>.,[>+<[-]]>.", &[0, 4]),
+        (">,[>,]<[.<]", b"reversed")
+    ];
+
+    // the output of the optimised program, and whether it halted
+    pub fn interpret(s: &str, input: &[u8]) -> (Vec<u8>, bool) {
+        let memory = RefCell::new(HashMap::<i32, u8>::new());
+        let input = RefCell::new(input.iter());
+        let output = RefCell::new(vec![]);
+        let mut ctx = BFCtx{
+            index: 0,
+            ask: || input.borrow_mut().next().cloned().unwrap_or(0),
+            put: |x| output.borrow_mut().push(x),
+            get: |i| *memory.borrow().get(& i).unwrap_or(&0),
+            set: |i, x| { memory.borrow_mut().insert(i, x); },
+            clear: || memory.borrow_mut().clear()
+        };
+        let halted = run_bfoptimised(&mut ctx, optimising_convert(raw(s)));
+        (output.into_inner(), halted)
+    }
+
+    pub fn command_exists(command: &str) -> bool {
+        Command::new(command).arg("--version").stdout(Stdio::null()).stderr(Stdio::null()).status().is_ok()
+    }
+
+    // a fresh directory for a test's generated files
+    pub fn scratch_dir(name: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("bf-{}-{}", name, std::process::id()));
+        let _ = std::fs::remove_dir_all(& dir);
+        std::fs::create_dir_all(& dir).unwrap();
+        dir
+    }
+
+    // runs a command with the given input, returning its output and whether it exited successfully
+    pub fn run_with_input(command: &mut Command, input: &[u8]) -> (Vec<u8>, bool) {
+        let mut child = command.stdin(Stdio::piped()).stdout(Stdio::piped()).stderr(Stdio::null()).spawn().unwrap();
+        child.stdin.take().unwrap().write_all(input).unwrap();
+        let output = child.wait_with_output().unwrap();
+        (output.stdout, output.status.success())
+    }
+
+    #[test]
+    fn temporaries() {
+        let cycles = Expr::new(ProcExpr::Into(Expr::new(ProcExpr::Lit(2)), Expr::new(ProcExpr::Reg(1))));
+        let square = Expr::new(ProcExpr::Mul(Expr::new(ProcExpr::Reg(1)), Expr::new(ProcExpr::Reg(1))));
+        let lines = BTreeMap::from([
+            (1, Expr::new(ProcExpr::If(Expr::new(ProcExpr::Reg(0)), cycles.clone(), square.clone()))),
+            (2, Expr::new(ProcExpr::Add(cycles.clone(), square.clone())))
+        ]);
+        // the halving may not halt, so it is only worked out where it is used
+        assert_eq!(effect_temporaries(& lines), vec![square]);
+    }
+}
+
+// the subexpressions of an effect that a backend works out once, before the effect's stores
+// those that may not halt are left where they are used, as they must only be worked out on the branch taken
+// every backend lowers an effect the same way: these first, then every line is read before any register is stored,
+// as the lines all happen at once
+// `into` and `choose` become helpers in each backend's runtime, agreeing with `div_u8` and `choose_u8`: `into` is the
+// smallest q with q * a = b mod 256, found by shifting out the factors of 2 then multiplying by the inverse as
+// `inverse_u8` does, and `choose` sums pascal's triangle mod 256, as the binomial itself won't fit
+pub fn effect_temporaries(
+    lines: & BTreeMap<i32, Expr>
+)
+    -> Vec<Expr>
+{
+    expr::shared_subexpressions(lines.values()).into_iter().filter(|expr| !may_throw(expr.clone())).collect()
+}
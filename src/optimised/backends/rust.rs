@@ -0,0 +1,275 @@
+use std::collections::HashMap;
+
+use super::*;
+
+#[cfg(test)]
+mod tests {
+    use std::{path::{Path, PathBuf}, process::Command};
+
+    use super::*;
+    use super::super::{ tests::*, super::optimiser::tests::* };
+
+    fn compile(dir: & Path, name: &str, main: &str) -> PathBuf {
+        let exe = dir.join(name);
+        let status = Command::new("rustc").arg("--edition=2021").arg("-o").arg(& exe).arg(dir.join(main)).status().unwrap();
+        assert!(status.success(), "{main} didn't compile");
+        exe
+    }
+
+    #[test]
+    fn compiled_programs() {
+        if !command_exists("rustc") { eprintln!("no rustc, skipping"); return; }
+        let dir = scratch_dir("rust");
+        for (tape, name) in [(RustTape::Wrapping(30000), "wrapping"), (RustTape::Unbounded, "unbounded")] {
+            for (i, (s, input)) in PROGRAMS.iter().enumerate() {
+                let main = format!("{name}{i}.rs");
+                std::fs::write(dir.join(& main), emit_rust_main(& optimising_convert(raw(s)), & tape)).unwrap();
+                let exe = compile(& dir, & format!("{name}{i}"), & main);
+                assert_eq!(run_with_input(&mut Command::new(& exe), input), interpret(s, input), "{s} diverged on a {name} tape");
+            }
+        }
+    }
+
+    #[test]
+    fn embedded_module() {
+        if !command_exists("rustc") { eprintln!("no rustc, skipping"); return; }
+        let dir = scratch_dir("rust-module");
+        std::fs::write(dir.join("program.rs"), emit_rust_module(& optimising_convert(raw("+.,[-->+<]>.")), & RustTape::Unbounded)).unwrap();
+        std::fs::write(dir.join("main.rs"), r#"
+            mod program;
+
+            fn main() {
+                let mut output = vec![];
+                assert!(program::run(&mut &[6u8][..], &mut output).is_ok());
+                assert_eq!(output, vec![1, 3]);
+
+                output.clear();
+                let err = program::run(&mut &[7u8][..], &mut output).unwrap_err();
+                assert!(matches!(err, program::Error::NonHalting));
+                assert_eq!(err.to_string(), "aborted non-halting loop");
+                assert_eq!(output, vec![1]);
+            }
+        "#).unwrap();
+        let (_, succeeded) = run_with_input(&mut Command::new(compile(& dir, "main", "main.rs")), b"");
+        assert!(succeeded);
+    }
+}
+
+// how a generated program stores its tape
+#[derive(Debug, Clone, PartialEq)]
+pub enum RustTape {
+    Wrapping(usize), // this many cells, with the head wrapping around at either end
+    Unbounded // grows in both directions, like the interpreter's
+}
+
+const ERRORS: &str = r#"use std::{fmt, io::{self, Read, Write}};
+
+#[derive(Debug)]
+pub enum Error {
+    NonHalting, // a loop that would never halt was aborted
+    Io(io::Error)
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Error::NonHalting => write!(f, "aborted non-halting loop"),
+            Error::Io(err) => write!(f, "{err}")
+        }
+    }
+}
+
+impl std::error::Error for Error {}
+
+impl From<io::Error> for Error {
+    fn from(err: io::Error) -> Error {
+        Error::Io(err)
+    }
+}
+"#;
+
+const WRAPPING_TAPE: &str = r#"
+struct Tape {
+    cells: Vec<u8>,
+    head: usize
+}
+
+impl Tape {
+    fn new() -> Tape {
+        Tape{ cells: vec![0; TAPE_SIZE], head: 0 }
+    }
+
+    fn get(&self, r: isize) -> u8 {
+        self.cells[(self.head as isize + r).rem_euclid(TAPE_SIZE as isize) as usize]
+    }
+
+    fn set(&mut self, r: isize, x: u8) {
+        self.cells[(self.head as isize + r).rem_euclid(TAPE_SIZE as isize) as usize] = x;
+    }
+
+    fn shift(&mut self, r: isize) {
+        self.head = (self.head as isize + r).rem_euclid(TAPE_SIZE as isize) as usize;
+    }
+}
+"#;
+
+const UNBOUNDED_TAPE: &str = r#"
+struct Tape {
+    cells: Vec<u8>,
+    origin: isize, // where cell 0 is in cells
+    head: isize
+}
+
+impl Tape {
+    fn new() -> Tape {
+        Tape{ cells: vec![], origin: 0, head: 0 }
+    }
+
+    fn get(&self, r: isize) -> u8 {
+        let i = self.origin + self.head + r;
+        if 0 <= i && (i as usize) < self.cells.len() { self.cells[i as usize] } else { 0 }
+    }
+
+    fn set(&mut self, r: isize, x: u8) {
+        let mut i = self.origin + self.head + r;
+        if i < 0 {
+            // grows by at least as much as it already has, so moving steadily left stays cheap
+            let grow = (-i as usize).max(self.cells.len());
+            self.cells.splice(0..0, std::iter::repeat(0).take(grow));
+            self.origin += grow as isize;
+            i += grow as isize;
+        }
+        if i as usize >= self.cells.len() {
+            self.cells.resize(i as usize + 1, 0);
+        }
+        self.cells[i as usize] = x;
+    }
+
+    fn shift(&mut self, r: isize) {
+        self.head += r;
+    }
+}
+"#;
+
+const HELPERS: &str = r#"
+fn ask(input: &mut impl Read) -> Result<u8, Error> {
+    let mut byte = [0];
+    // 0 at the end of input
+    match input.read(&mut byte)? {
+        0 => Ok(0),
+        _ => Ok(byte[0])
+    }
+}
+
+fn into(a: u8, b: u8) -> Result<u8, Error> {
+    if b == 0 { return Ok(0); }
+    if a == 0 { return Err(Error::NonHalting); }
+    let (mut x, mut y) = (b, a);
+    let shift = y.trailing_zeros();
+    if x.trailing_zeros() < shift { return Err(Error::NonHalting); }
+    x >>= shift;
+    y >>= shift;
+    let mut inverse = y;
+    for _ in 0..3 {
+        inverse = inverse.wrapping_mul(2u8.wrapping_sub(y.wrapping_mul(inverse)));
+    }
+    Ok(x.wrapping_mul(inverse) & (0xff >> shift))
+}
+
+fn choose(x: u8, k: u8) -> u8 {
+    let mut row = [0u8; 256];
+    row[0] = 1;
+    for _ in 0..x {
+        for j in (1..=k as usize).rev() {
+            row[j] = row[j].wrapping_add(row[j - 1]);
+        }
+    }
+    row[k as usize]
+}
+"#;
+
+fn rust_expr(
+    expr: & ProcExpr,
+    temporaries: & HashMap<Expr, usize>
+)
+    -> String
+{
+    let show = |x: & Expr| match temporaries.get(x) {
+        Some(i) => format!("t{i}"),
+        None => rust_expr(x, temporaries)
+    };
+    match expr {
+        ProcExpr::Lit(x) => format!("{x}u8"),
+        ProcExpr::Reg(r) => format!("tape.get({r})"),
+        ProcExpr::Add(a, b) => format!("{}.wrapping_add({})", show(a), show(b)),
+        ProcExpr::Mul(a, b) => format!("{}.wrapping_mul({})", show(a), show(b)),
+        ProcExpr::Into(a, b) => format!("into({}, {})?", show(a), show(b)),
+        ProcExpr::Choose(a, k) => format!("choose({}, {k})", show(a)),
+        ProcExpr::If(c, a, b) => format!("(if {} != 0 {{ {} }} else {{ {} }})", show(c), show(a), show(b)),
+        ProcExpr::IsZero(a) => format!("(({} == 0) as u8)", show(a)),
+    }
+}
+
+fn rust_block(
+    b: & OptimisedBlock
+)
+    -> String
+{
+    match b {
+        OptimisedBlock::Ask(register) => format!("tape.set({register}, ask(input)?);"),
+        OptimisedBlock::Put(register) => format!("output.write_all(&[tape.get({register})])?;"),
+        OptimisedBlock::AtomicEffect(lines, effect) if lines.is_empty() => format!("tape.shift({effect});"),
+        OptimisedBlock::AtomicEffect(lines, effect) => {
+            let shared = effect_temporaries(lines);
+            let temporaries: HashMap<Expr, usize> = shared.iter().enumerate().map(|(i, expr)| (expr.clone(), i)).collect();
+            let statements = shared.iter().enumerate().map(|(i, expr)| format!("let t{i} = {};", rust_expr(expr, & temporaries)))
+                .chain(lines.values().enumerate().map(|(i, expr)| match temporaries.get(expr) {
+                    Some(j) => format!("let v{i} = t{j};"),
+                    None => format!("let v{i} = {};", rust_expr(expr, & temporaries))
+                }))
+                .chain(lines.keys().enumerate().map(|(i, register)| format!("tape.set({register}, v{i});")))
+                .chain((*effect != 0).then(|| format!("tape.shift({effect});")));
+            format!("{{\n{}\n}}", indent_string(join_strings(statements)))
+        },
+        OptimisedBlock::Loop(register, bs, _) => format!("while tape.get({register}) != 0 {{\n{}\n}}", indent_string(rust_blocks(bs))),
+        OptimisedBlock::If(register, bs) => format!("if tape.get({register}) != 0 {{\n{}\n}}", indent_string(rust_blocks(bs)))
+    }
+}
+
+fn rust_blocks(
+    bs: & Vec<OptimisedBlock>
+)
+    -> String
+{
+    join_strings(bs.iter().map(rust_block))
+}
+
+// a standalone module exposing `run(input, output) -> Result<(), Error>`, for including in another crate
+pub fn emit_rust_module(
+    bs: & Vec<OptimisedBlock>,
+    tape: & RustTape
+)
+    -> String
+{
+    let tape_impl = match tape {
+        RustTape::Wrapping(size) => format!("\nconst TAPE_SIZE: usize = {size};\n{WRAPPING_TAPE}"),
+        RustTape::Unbounded => UNBOUNDED_TAPE.to_string()
+    };
+    format!(
+        "// generated from optimised brainfuck bytecode\n#![allow(unused)]\n\n{ERRORS}{tape_impl}{HELPERS}\npub fn run(input: &mut impl Read, output: &mut impl Write) -> Result<(), Error> {{\n\tlet mut tape = Tape::new();\n{}\n\toutput.flush()?;\n\tOk(())\n}}\n",
+        indent_string(rust_blocks(bs))
+    ).replace('\t', "    ")
+}
+
+// a `main.rs` running the program on stdin and stdout, exiting with status 1 on an error
+pub fn emit_rust_main(
+    bs: & Vec<OptimisedBlock>,
+    tape: & RustTape
+)
+    -> String
+{
+    format!(
+        "{}\nfn main() {{\n\tlet mut output = io::BufWriter::new(io::stdout().lock());\n\tlet result = run(&mut io::stdin().lock(), &mut output);\n\tlet _ = output.flush();\n\tif let Err(err) = result {{\n\t\teprintln!(\"{{err}}\");\n\t\tstd::process::exit(1);\n\t}}\n}}\n",
+        emit_rust_module(bs, tape)
+    ).replace('\t', "    ")
+}
@@ -0,0 +1,578 @@
+use std::collections::HashMap;
+
+use super::*;
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashSet;
+
+    use super::*;
+    use super::super::{ tests::*, super::optimiser::tests::* };
+
+    fn tokens(wat: &str) -> Vec<String> {
+        let mut tokens = vec![];
+        let mut chars = wat.chars().peekable();
+        while let Some(c) = chars.next() {
+            match c {
+                '(' | ')' => tokens.push(c.to_string()),
+                ';' if chars.peek() == Some(& ';') => { while chars.next().is_some_and(|c| c != '\n') {} },
+                '"' => {
+                    let mut s = "\"".to_string();
+                    for c in chars.by_ref() { s.push(c); if c == '"' { break; } }
+                    tokens.push(s);
+                },
+                c if c.is_whitespace() => (),
+                c => {
+                    let mut s = c.to_string();
+                    while chars.peek().is_some_and(|c| !c.is_whitespace() && *c != '(' && *c != ')') { s.push(chars.next().unwrap()); }
+                    tokens.push(s);
+                }
+            }
+        }
+        tokens
+    }
+
+    // the parenthesised form starting at `i`, as its tokens without the outer parentheses, and where it ends
+    fn form(tokens: & [String], i: usize) -> Result<(Vec<String>, usize), String> {
+        if tokens.get(i).map(|s| s.as_str()) != Some("(") { return Err(format!("expected ( at token {i}")); }
+        let mut depth = 0;
+        for j in i..tokens.len() {
+            match tokens[j].as_str() {
+                "(" => depth += 1,
+                ")" => { depth -= 1; if depth == 0 { return Ok((tokens[i + 1..j].to_vec(), j + 1)); } },
+                _ => ()
+            }
+        }
+        Err("unbalanced parentheses".to_string())
+    }
+
+    // the module's imports and exports, with every function's body type checked instruction by instruction
+    fn validate(wat: &str) -> Result<(), String> {
+        let tokens = tokens(wat);
+        let (module, end) = form(& tokens, 0)?;
+        if end != tokens.len() || module.first().map(|s| s.as_str()) != Some("module") { return Err("not a single module".to_string()); }
+
+        // every form in the module, and the signature of every function
+        let mut forms = vec![];
+        let mut i = 1;
+        while i < module.len() {
+            let (f, next) = form(& module, i)?;
+            forms.push(f);
+            i = next;
+        }
+        let signature = |f: & [String]| -> (usize, usize) {
+            (f.iter().filter(|s| *s == "param").count(), f.iter().take_while(|s| *s != "local").filter(|s| *s == "result").count())
+        };
+        let mut functions = HashMap::new();
+        let (mut imports, mut exports, mut globals) = (HashSet::new(), HashSet::new(), HashSet::new());
+        for f in forms.iter() {
+            match f[0].as_str() {
+                "import" => {
+                    imports.insert(f[2].clone());
+                    functions.insert(f[5].clone(), signature(& f[5..]));
+                },
+                "func" => { functions.insert(f[1].clone(), signature(f)); },
+                "global" => { globals.insert(f[1].clone()); },
+                _ => ()
+            }
+            for (j, s) in f.iter().enumerate() {
+                if s == "export" { exports.insert(f[j + 1].clone()); }
+            }
+        }
+        for name in ["\"ask\"", "\"put\""] {
+            if !imports.contains(name) { return Err(format!("{name} isn't imported")); }
+        }
+        for name in ["\"run\"", "\"tape\"", "\"head\""] {
+            if !exports.contains(name) { return Err(format!("{name} isn't exported")); }
+        }
+
+        for f in forms.iter().filter(|f| f[0] == "func") {
+            let (_, results) = signature(f);
+            let mut locals = HashSet::new();
+            let mut i = if f[1].starts_with('$') { 2 } else { 1 };
+            while i < f.len() && f[i] == "(" {
+                let (declaration, next) = form(f, i)?;
+                if declaration[0] == "param" || declaration[0] == "local" { locals.insert(declaration[1].clone()); }
+                i = next;
+            }
+
+            // (label, height when entered, results, whether it's a loop) of every enclosing block, and the stack height
+            let mut frames = vec![(String::new(), 0, results, false)];
+            let mut height = 0;
+            let mut unreachable = false;
+            let pop = |height: &mut usize, n: usize, unreachable: bool, frame: usize| -> Result<(), String> {
+                if !unreachable && *height < frame + n { return Err(format!("stack underflow in {}", f[1])); }
+                *height = height.saturating_sub(n).max(frame);
+                Ok(())
+            };
+            while i < f.len() {
+                let instruction = f[i].as_str();
+                let frame = frames.last().unwrap().1;
+                let operand = f.get(i + 1).cloned().unwrap_or_default();
+                i += 1;
+                match instruction {
+                    "i32.const" => { operand.parse::<i32>().map_err(|_| format!("bad constant {operand}"))?; i += 1; height += 1; },
+                    "local.get" | "local.set" | "local.tee" => {
+                        if !locals.contains(& operand) { return Err(format!("no local {operand}")); }
+                        i += 1;
+                        match instruction {
+                            "local.get" => height += 1,
+                            "local.set" => pop(&mut height, 1, unreachable, frame)?,
+                            _ => { pop(&mut height, 1, unreachable, frame)?; height += 1; }
+                        }
+                    },
+                    "global.get" | "global.set" => {
+                        if !globals.contains(& operand) { return Err(format!("no global {operand}")); }
+                        i += 1;
+                        if instruction == "global.get" { height += 1; } else { pop(&mut height, 1, unreachable, frame)?; }
+                    },
+                    "i32.add" | "i32.sub" | "i32.mul" | "i32.and" | "i32.shr_u" | "i32.eq" | "i32.lt_u" | "i32.gt_u" => {
+                        pop(&mut height, 2, unreachable, frame)?;
+                        height += 1;
+                    },
+                    "i32.eqz" | "i32.ctz" | "i32.load8_u" => { pop(&mut height, 1, unreachable, frame)?; height += 1; },
+                    "i32.store8" => pop(&mut height, 2, unreachable, frame)?,
+                    "call" => {
+                        let (params, results) = *functions.get(& operand).ok_or(format!("no function {operand}"))?;
+                        i += 1;
+                        pop(&mut height, params, unreachable, frame)?;
+                        height += results;
+                    },
+                    "block" | "loop" | "if" => {
+                        if instruction == "if" { pop(&mut height, 1, unreachable, frame)?; }
+                        let label = if operand.starts_with('$') { i += 1; operand } else { String::new() };
+                        let results = if f.get(i).map(|s| s.as_str()) == Some("(") {
+                            let (result, next) = form(f, i)?;
+                            i = next;
+                            (result[0] == "result") as usize
+                        } else { 0 };
+                        frames.push((label, height, results, instruction == "loop"));
+                        unreachable = false;
+                    },
+                    "else" | "end" => {
+                        let (label, entered, results, is_loop) = frames.pop().ok_or("unmatched end".to_string())?;
+                        if !unreachable && height != entered + results { return Err(format!("block {label} leaves {} values", height - entered)); }
+                        if instruction == "else" {
+                            frames.push((label, entered, results, is_loop));
+                            height = entered;
+                        } else {
+                            height = entered + results;
+                        }
+                        unreachable = false;
+                    },
+                    "br" | "br_if" => {
+                        if !frames.iter().any(|(label, ..)| *label == operand) { return Err(format!("no label {operand}")); }
+                        i += 1;
+                        if instruction == "br_if" { pop(&mut height, 1, unreachable, frame)?; } else { unreachable = true; }
+                    },
+                    "return" => unreachable = true,
+                    _ => return Err(format!("unknown instruction {instruction}"))
+                }
+            }
+            if frames.len() != 1 { return Err(format!("unclosed block in {}", f[1])); }
+            if !unreachable && height != results { return Err(format!("{} leaves {height} values", f[1])); }
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn valid_modules() {
+        for (s, _) in PROGRAMS {
+            let wat = emit_wat(& optimising_convert(raw(s)));
+            assert_eq!(validate(& wat), Ok(()), "{s} gave an invalid module:\n{wat}");
+        }
+        // the validator does catch mistakes
+        let wat = emit_wat(& optimising_convert(raw("+[->+<]>.")));
+        assert!(validate(& wat.replacen("call $put", "call $ask", 1)).is_err());
+        assert!(validate(& wat.replacen("call $put", "call $get", 1)).is_err());
+        assert!(validate(& wat.replacen("local.get $t0", "local.get $t9", 1)).is_err());
+        assert!(validate(& wat.replacen("end", "", 1)).is_err());
+    }
+
+    #[test]
+    fn stable() {
+        assert_eq!(
+            emit_wat(& optimising_convert(raw(",[->++<]>."))).split("(func (export \"run\")").nth(1).unwrap(),
+            r#" (result i32)
+    (local $into i32)
+    (local $t0 i32)
+    (local $t1 i32)
+    global.get $head
+    i32.const 0
+    i32.add
+    i32.const 65535
+    i32.and
+    call $ask
+    i32.store8
+    i32.const 0
+    local.set $t0
+    i32.const 2
+    global.get $head
+    i32.const 0
+    i32.add
+    i32.const 65535
+    i32.and
+    i32.load8_u
+    i32.mul
+    global.get $head
+    i32.const 1
+    i32.add
+    i32.const 65535
+    i32.and
+    i32.load8_u
+    i32.add
+    local.set $t1
+    global.get $head
+    i32.const 0
+    i32.add
+    i32.const 65535
+    i32.and
+    local.get $t0
+    i32.store8
+    global.get $head
+    i32.const 1
+    i32.add
+    i32.const 65535
+    i32.and
+    local.get $t1
+    i32.store8
+    global.get $head
+    i32.const 1
+    i32.add
+    i32.const 65535
+    i32.and
+    i32.load8_u
+    call $put
+    global.get $head
+    i32.const 1
+    i32.add
+    global.set $head
+    i32.const 1
+  )
+)
+"#
+        );
+    }
+}
+
+pub const TAPE_SIZE: usize = 1 << 16; // one page of linear memory, which the tape wraps around
+const CHOOSE_ROW: usize = TAPE_SIZE; // choose works out its row on the page after the tape
+
+const RUNTIME: &str = r#"
+  ;; 256 when there is no quotient, for the caller to trap on
+  (func $into (param $a i32) (param $b i32) (result i32)
+    (local $shift i32)
+    (local $inverse i32)
+    local.get $a
+    i32.const 255
+    i32.and
+    local.set $a
+    local.get $b
+    i32.const 255
+    i32.and
+    local.set $b
+    local.get $b
+    i32.eqz
+    if
+      i32.const 0
+      return
+    end
+    local.get $a
+    i32.eqz
+    if
+      i32.const 256
+      return
+    end
+    local.get $a
+    i32.ctz
+    local.set $shift
+    local.get $b
+    i32.ctz
+    local.get $shift
+    i32.lt_u
+    if
+      i32.const 256
+      return
+    end
+    local.get $a
+    local.get $shift
+    i32.shr_u
+    local.set $a
+    local.get $b
+    local.get $shift
+    i32.shr_u
+    local.set $b
+    local.get $a
+    local.set $inverse
+    NEWTON_STEPS
+    local.get $b
+    local.get $inverse
+    i32.mul
+    i32.const 255
+    local.get $shift
+    i32.shr_u
+    i32.and
+  )
+
+  (func $choose (param $x i32) (param $k i32) (result i32)
+    (local $j i32)
+    local.get $x
+    i32.const 255
+    i32.and
+    local.set $x
+    i32.const 0
+    local.set $j
+    block $cleared
+      loop $clear
+        local.get $j
+        local.get $k
+        i32.gt_u
+        br_if $cleared
+        local.get $j
+        i32.const CHOOSE_ROW
+        i32.add
+        i32.const 0
+        i32.store8
+        local.get $j
+        i32.const 1
+        i32.add
+        local.set $j
+        br $clear
+      end
+    end
+    i32.const CHOOSE_ROW
+    i32.const 1
+    i32.store8
+    block $done
+      loop $rows
+        local.get $x
+        i32.eqz
+        br_if $done
+        local.get $k
+        local.set $j
+        block $row_done
+          loop $columns
+            local.get $j
+            i32.eqz
+            br_if $row_done
+            local.get $j
+            i32.const CHOOSE_ROW
+            i32.add
+            local.get $j
+            i32.const CHOOSE_ROW
+            i32.add
+            i32.load8_u
+            local.get $j
+            i32.const CHOOSE_ROW_LEFT
+            i32.add
+            i32.load8_u
+            i32.add
+            i32.store8
+            local.get $j
+            i32.const 1
+            i32.sub
+            local.set $j
+            br $columns
+          end
+        end
+        local.get $x
+        i32.const 1
+        i32.sub
+        local.set $x
+        br $rows
+      end
+    end
+    local.get $k
+    i32.const CHOOSE_ROW
+    i32.add
+    i32.load8_u
+  )
+"#;
+
+const NEWTON_STEP: &str = "local.get $inverse\n    i32.const 2\n    local.get $a\n    local.get $inverse\n    i32.mul\n    i32.sub\n    i32.mul\n    local.set $inverse";
+
+struct Function {
+    lines: Vec<String>,
+    depth: usize,
+    labels: usize,
+    locals: usize // temporaries the function needs
+}
+
+impl Function {
+    fn emit(&mut self, line: & str) -> () {
+        self.lines.push(format!("{}{line}", "  ".repeat(self.depth)));
+    }
+
+    fn label(&mut self) -> usize {
+        self.labels += 1;
+        self.labels
+    }
+
+    // leaves the address of the cell, wrapping around the tape
+    fn address(&mut self, register: i32) -> () {
+        self.emit("global.get $head");
+        self.emit(& format!("i32.const {register}"));
+        self.emit("i32.add");
+        self.emit(& format!("i32.const {}", TAPE_SIZE - 1));
+        self.emit("i32.and");
+    }
+
+    // leaves the expression's value, the low byte of an i32
+    fn expr(&mut self, expr: & Expr, temporaries: & HashMap<Expr, usize>) -> () {
+        if let Some(i) = temporaries.get(expr) {
+            return self.emit(& format!("local.get $t{i}"));
+        }
+        match expr.as_ref() {
+            ProcExpr::Lit(x) => self.emit(& format!("i32.const {x}")),
+            ProcExpr::Reg(r) => {
+                self.address(*r);
+                self.emit("i32.load8_u");
+            },
+            ProcExpr::Add(a, b) | ProcExpr::Mul(a, b) | ProcExpr::Into(a, b) => {
+                self.expr(a, temporaries);
+                self.expr(b, temporaries);
+                match expr.as_ref() {
+                    ProcExpr::Add(..) => self.emit("i32.add"),
+                    ProcExpr::Mul(..) => self.emit("i32.mul"),
+                    _ => {
+                        // a division that never halts makes `run` return 0 straight away
+                        self.emit("call $into");
+                        self.emit("local.tee $into");
+                        self.emit("i32.const 256");
+                        self.emit("i32.eq");
+                        self.emit("if");
+                        self.emit("  i32.const 0");
+                        self.emit("  return");
+                        self.emit("end");
+                        self.emit("local.get $into");
+                    }
+                }
+            },
+            ProcExpr::Choose(a, k) => {
+                self.expr(a, temporaries);
+                self.emit(& format!("i32.const {k}"));
+                self.emit("call $choose");
+            },
+            ProcExpr::If(c, a, b) => {
+                self.expr(c, temporaries);
+                self.emit("i32.const 255");
+                self.emit("i32.and");
+                self.emit("if (result i32)");
+                self.depth += 1;
+                self.expr(a, temporaries);
+                self.depth -= 1;
+                self.emit("else");
+                self.depth += 1;
+                self.expr(b, temporaries);
+                self.depth -= 1;
+                self.emit("end");
+            },
+            ProcExpr::IsZero(a) => {
+                self.expr(a, temporaries);
+                self.emit("i32.const 255");
+                self.emit("i32.and");
+                self.emit("i32.eqz");
+            }
+        }
+    }
+
+    fn block(&mut self, b: & OptimisedBlock) -> () {
+        match b {
+            OptimisedBlock::Ask(register) => {
+                self.address(*register);
+                self.emit("call $ask");
+                self.emit("i32.store8");
+            },
+            OptimisedBlock::Put(register) => {
+                self.address(*register);
+                self.emit("i32.load8_u");
+                self.emit("call $put");
+            },
+            OptimisedBlock::AtomicEffect(lines, effect) => {
+                let shared = effect_temporaries(lines);
+                let mut temporaries = HashMap::new();
+                for (i, expr) in shared.iter().enumerate() {
+                    self.expr(expr, & temporaries);
+                    self.emit(& format!("local.set $t{i}"));
+                    temporaries.insert(expr.clone(), i);
+                }
+                for (i, expr) in lines.values().enumerate() {
+                    self.expr(expr, & temporaries);
+                    self.emit(& format!("local.set $t{}", shared.len() + i));
+                }
+                for (i, register) in lines.keys().enumerate() {
+                    self.address(*register);
+                    self.emit(& format!("local.get $t{}", shared.len() + i));
+                    self.emit("i32.store8");
+                }
+                self.locals = self.locals.max(shared.len() + lines.len());
+                if *effect != 0 {
+                    self.emit("global.get $head");
+                    self.emit(& format!("i32.const {effect}"));
+                    self.emit("i32.add");
+                    self.emit("global.set $head");
+                }
+            },
+            OptimisedBlock::Loop(register, bs, _) => {
+                let label = self.label();
+                self.emit(& format!("block $exit{label}"));
+                self.depth += 1;
+                self.emit(& format!("loop $loop{label}"));
+                self.depth += 1;
+                self.address(*register);
+                self.emit("i32.load8_u");
+                self.emit("i32.eqz");
+                self.emit(& format!("br_if $exit{label}"));
+                for b_ in bs { self.block(b_); }
+                self.emit(& format!("br $loop{label}"));
+                self.depth -= 1;
+                self.emit("end");
+                self.depth -= 1;
+                self.emit("end");
+            },
+            OptimisedBlock::If(register, bs) => {
+                self.address(*register);
+                self.emit("i32.load8_u");
+                self.emit("if");
+                self.depth += 1;
+                for b_ in bs { self.block(b_); }
+                self.depth -= 1;
+                self.emit("end");
+            }
+        }
+    }
+}
+
+// a webassembly text module mirroring `BFCtx`: it imports `env.ask: () -> i32` and `env.put: (i32) -> ()`,
+// and exports its tape as the memory `tape`, the head as the mutable global `head`, and `run: () -> i32`,
+// which returns 1 when the program halts and 0 when it aborts a loop that would never halt
+// names are numbered in the order they appear, so the same bytecode always gives the same module
+pub fn emit_wat(
+    bs: & Vec<OptimisedBlock>
+)
+    -> String
+{
+    let mut function = Function{ lines: vec![], depth: 2, labels: 0, locals: 0 };
+    for b in bs { function.block(b); }
+    let locals: String = (0..function.locals).map(|i| format!("    (local $t{i} i32)\n")).collect();
+    format!(
+        "(module\n  \
+        ;; generated from optimised brainfuck bytecode\n  \
+        (import \"env\" \"ask\" (func $ask (result i32)))\n  \
+        (import \"env\" \"put\" (func $put (param i32)))\n  \
+        (memory (export \"tape\") 2)\n  \
+        (global $head (export \"head\") (mut i32) (i32.const 0))\n\
+        {}\n  \
+        (func (export \"run\") (result i32)\n    \
+        (local $into i32)\n\
+        {locals}{}\n    \
+        i32.const 1\n  \
+        )\n\
+        )\n",
+        RUNTIME
+            .replace("NEWTON_STEPS", & [NEWTON_STEP; 3].join("\n    "))
+            .replace("CHOOSE_ROW_LEFT", & (CHOOSE_ROW - 1).to_string())
+            .replace("CHOOSE_ROW", & CHOOSE_ROW.to_string()),
+        function.lines.join("\n")
+    )
+}
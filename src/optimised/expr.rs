@@ -0,0 +1,187 @@
+use std::{fmt, cell::{Cell, RefCell}, cmp::Ordering, collections::HashMap, hash::{Hash, Hasher}, ops::Deref};
+use std::rc::{Rc, Weak};
+
+use super::*;
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn interned() {
+        let a = Expr::new(ProcExpr::Add(Expr::new(ProcExpr::Reg(1)), Expr::new(ProcExpr::Lit(2))));
+        let b = Expr::new(ProcExpr::Add(Expr::new(ProcExpr::Reg(1)), Expr::new(ProcExpr::Lit(2))));
+        assert_eq!(a.id(), b.id());
+        assert_ne!(a.id(), Expr::new(ProcExpr::Add(Expr::new(ProcExpr::Reg(2)), Expr::new(ProcExpr::Lit(2)))).id());
+        let ProcExpr::Add(x, _) = a.as_ref() else { panic!("not an addition") };
+        assert_eq!(x, & Expr::new(ProcExpr::Reg(1)));
+
+        // ordering is structural, so it doesn't depend on which expression happened to be made first
+        let c = Expr::new(ProcExpr::Reg(7));
+        assert!(Expr::new(ProcExpr::Lit(200)) < c);
+        assert!(Expr::new(ProcExpr::Reg(3)) < c);
+
+        let cycles = Expr::new(ProcExpr::Into(Expr::new(ProcExpr::Lit(3)), Expr::new(ProcExpr::Reg(0))));
+        let twice = Expr::new(ProcExpr::Mul(Expr::new(ProcExpr::Lit(2)), cycles.clone()));
+        let lines = vec![
+            twice.clone(),
+            Expr::new(ProcExpr::Add(twice.clone(), cycles.clone())),
+            Expr::new(ProcExpr::Add(Expr::new(ProcExpr::Reg(1)), Expr::new(ProcExpr::Reg(1))))
+        ];
+        assert_eq!(shared_subexpressions(lines.iter()), vec![cycles, twice]);
+
+        let before = live_expressions();
+        drop(Expr::new(ProcExpr::Reg(12345)));
+        assert_eq!(live_expressions(), before);
+    }
+}
+
+struct Node {
+    id: usize,
+    expr: ProcExpr
+}
+
+thread_local! {
+    // every expression still in use, so equal expressions are always the same node
+    static INTERNER: RefCell<HashMap<ProcExpr, Weak<Node>>> = RefCell::new(HashMap::new());
+    static NEXT_ID: Cell<usize> = const { Cell::new(0) };
+}
+
+// a hash-consed expression, structurally equal expressions share one node, so comparing or hashing them is O(1)
+#[derive(Clone)]
+pub struct Expr(Rc<Node>);
+
+impl Expr {
+    pub fn new(expr: ProcExpr) -> Expr {
+        let found = INTERNER.with(|interner| interner.borrow().get(& expr).and_then(|node| node.upgrade()));
+        if let Some(node) = found {
+            return Expr(node);
+        }
+        let id = NEXT_ID.with(|next| { let id = next.get(); next.set(id + 1); id });
+        let node = Rc::new(Node{ id, expr: expr.clone() });
+        INTERNER.with(|interner| interner.borrow_mut().insert(expr, Rc::downgrade(& node)));
+        Expr(node)
+    }
+
+    pub fn id(&self) -> usize {
+        self.0.id
+    }
+}
+
+// the number of distinct expressions alive on this thread
+pub fn live_expressions() -> usize {
+    INTERNER.with(|interner| interner.borrow().len())
+}
+
+impl Drop for Node {
+    fn drop(&mut self) {
+        // the interner may already be gone when the thread is exiting
+        let _ = INTERNER.try_with(|interner| {
+            if let Ok(mut interner) = interner.try_borrow_mut() {
+                if interner.get(& self.expr).is_some_and(|node| node.strong_count() == 0) {
+                    interner.remove(& self.expr);
+                }
+            }
+        });
+    }
+}
+
+impl Deref for Expr {
+    type Target = ProcExpr;
+
+    fn deref(&self) -> &ProcExpr {
+        & self.0.expr
+    }
+}
+
+impl AsRef<ProcExpr> for Expr {
+    fn as_ref(&self) -> &ProcExpr {
+        & self.0.expr
+    }
+}
+
+impl PartialEq for Expr {
+    fn eq(&self, other: &Expr) -> bool {
+        self.0.id == other.0.id
+    }
+}
+
+impl Eq for Expr {}
+
+impl Hash for Expr {
+    fn hash<H: Hasher>(&self, state: &mut H) {
+        self.0.id.hash(state)
+    }
+}
+
+// structural rather than by id, so that ordered collections come out the same every run
+impl Ord for Expr {
+    fn cmp(&self, other: &Expr) -> Ordering {
+        if self.0.id == other.0.id {
+            Ordering::Equal
+        } else {
+            self.0.expr.cmp(& other.0.expr)
+        }
+    }
+}
+
+impl PartialOrd for Expr {
+    fn partial_cmp(&self, other: &Expr) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl fmt::Debug for Expr {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{:?}", self.0.expr)
+    }
+}
+
+impl fmt::Display for Expr {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", self.0.expr)
+    }
+}
+
+// compound subexpressions that appear more than once in the given expressions, each one after those inside it
+pub fn shared_subexpressions<'a>(
+    exprs: impl Iterator<Item = &'a Expr>
+)
+    -> Vec<Expr>
+{
+    fn visit(
+        expr: & Expr,
+        counts: &mut HashMap<Expr, usize>,
+        order: &mut Vec<Expr>
+    )
+        -> ()
+    {
+        if let Some(count) = counts.get_mut(expr) {
+            // whatever is inside was already counted the first time round
+            *count += 1;
+            return;
+        }
+        match expr.as_ref() {
+            ProcExpr::Lit(_) | ProcExpr::Reg(_) => return,
+            ProcExpr::Add(a, b) | ProcExpr::Mul(a, b) | ProcExpr::Into(a, b) => {
+                visit(a, counts, order);
+                visit(b, counts, order);
+            },
+            ProcExpr::Choose(a, _) | ProcExpr::IsZero(a) => visit(a, counts, order),
+            ProcExpr::If(c, a, b) => {
+                visit(c, counts, order);
+                visit(a, counts, order);
+                visit(b, counts, order);
+            }
+        }
+        counts.insert(expr.clone(), 1);
+        order.push(expr.clone());
+    }
+
+    let mut counts = HashMap::new();
+    let mut order = vec![];
+    for expr in exprs {
+        visit(expr, &mut counts, &mut order);
+    }
+    order.into_iter().filter(|expr| counts.get(expr).unwrap() > & 1).collect()
+}
@@ -1,6 +1,6 @@
 use std::{collections::HashMap, future::Future, pin::Pin};
 
-use super::*;
+use super::{ *, expr::* };
 
 pub use crate::interpreter::{BFCtx, AsyncBFCtx};
 
@@ -72,9 +72,98 @@ mod tests {
 
         assert_eq!(Some(52), div_u8(4, 5));
     }
+
+    #[test]
+    fn shared_subexpression_test() {
+        use super::*;
+        use super::super::optimiser::tests::run_optimised;
+
+        let halved = Expr::new(ProcExpr::Into(Expr::new(ProcExpr::Lit(2)), Expr::new(ProcExpr::Reg(1))));
+        let pick = |otherwise: u8| Expr::new(ProcExpr::If(Expr::new(ProcExpr::Reg(0)), halved.clone(), Expr::new(ProcExpr::Lit(otherwise))));
+        let effect = OptimisedBlock::AtomicEffect(BTreeMap::from([(2, pick(5)), (3, pick(6))]), 0);
+
+        // the halving is the only subexpression both lines share, so it is given the only slot
+        let Lowered::AtomicEffect(_, _, slots) = lower(& effect) else { panic!("not lowered to an effect") };
+        assert_eq!(slots, HashMap::from([(halved.clone(), 0)]));
+
+        // halving an odd ~#1 never halts, but it is only worked out when ~#0 isn't 0
+        assert_eq!(run_optimised(vec![effect.clone()], &[(1, 3)], &[]).0, vec![(1, 3), (2, 5), (3, 6)]);
+        assert_eq!(run_optimised(vec![effect], &[(0, 1), (1, 4)], &[]).0, vec![(0, 1), (1, 4), (2, 2), (3, 2)]);
+    }
 }
 
-pub fn run_bfoptimised_block<
+// a block with the subexpressions each atomic effect shares given a memo slot, worked out once before it is run
+enum Lowered<'a> {
+    Ask(i32),
+    Put(i32),
+    AtomicEffect(&'a BTreeMap<i32, Expr>, i32, HashMap<Expr, usize>),
+    Loop(i32, Vec<Lowered<'a>>),
+    If(i32, Vec<Lowered<'a>>)
+}
+
+fn lower(
+    b: & OptimisedBlock
+)
+    -> Lowered<'_>
+{
+    match b {
+        OptimisedBlock::Ask(register) => Lowered::Ask(*register),
+        OptimisedBlock::Put(register) => Lowered::Put(*register),
+        OptimisedBlock::AtomicEffect(lines, offset) => Lowered::AtomicEffect(
+            lines,
+            *offset,
+            shared_subexpressions(lines.values()).into_iter().enumerate().map(|(i, expr)| (expr, i)).collect()
+        ),
+        OptimisedBlock::Loop(register, bs, _) => Lowered::Loop(*register, bs.iter().map(lower).collect()),
+        OptimisedBlock::If(register, bs) => Lowered::If(*register, bs.iter().map(lower).collect())
+    }
+}
+
+// an expression's value, reading registers relative to the head with `get`, or None when it would never halt
+// shared subexpressions are only worked out the first time they're needed, and kept in their slot of `memo`
+fn compute(
+    expr: & Expr,
+    get: &mut dyn FnMut(i32) -> u8,
+    slots: & HashMap<Expr, usize>,
+    memo: &mut Vec<Option<Option<u8>>>
+)
+    -> Option<u8>
+{
+    let slot = slots.get(expr).copied();
+    if let Some(Some(val)) = slot.map(|i| memo[i]) { return val; }
+    macro_rules! go {
+        ($x:expr) => { compute($x, get, slots, memo)? }
+    }
+    let val = (|| match expr.as_ref() {
+        ProcExpr::Lit(x) => Some(*x),
+        ProcExpr::Reg(r) => Some(get(*r)),
+        ProcExpr::Add(a, b) => Some(go!(a).wrapping_add(go!(b))),
+        ProcExpr::Mul(a, b) => Some(go!(a).wrapping_mul(go!(b))),
+        ProcExpr::Into(a, b) => {
+            let x = go!(a);
+            div_u8(go!(b), x)
+        },
+        ProcExpr::Choose(a, k) => Some(choose_u8(go!(a), *k)),
+        ProcExpr::If(c, a, b) => Some(if go!(c) != 0 { go!(a) } else { go!(b) }),
+        ProcExpr::IsZero(a) => Some((go!(a) == 0) as u8)
+    })();
+    if let Some(i) = slot { memo[i] = Some(val); }
+    val
+}
+
+// what an atomic effect stores, every line being worked out before any is stored, or None when it would never halt
+fn run_effect(
+    lines: & BTreeMap<i32, Expr>,
+    slots: & HashMap<Expr, usize>,
+    get: &mut dyn FnMut(i32) -> u8
+)
+    -> Option<Vec<(i32, u8)>>
+{
+    let mut memo = vec![None; slots.len()];
+    lines.iter().map(|(register, expr)| Some((*register, compute(expr, get, slots, &mut memo)?))).collect()
+}
+
+fn run_lowered<
     Ask: FnMut() -> u8,
     Put: FnMut(u8) -> (),
     Get: FnMut(i32) -> u8,
@@ -82,86 +171,50 @@ pub fn run_bfoptimised_block<
     Clear: Fn() -> ()
 >(
     ctx: &mut BFCtx<Ask, Put, Get, Set, Clear>,
-    b: & OptimisedBlock
+    b: & Lowered
 )
     -> bool
 {
     match b {
-        OptimisedBlock::Ask => { (ctx.set)(ctx.index, (ctx.ask)()); },
-        OptimisedBlock::Put => (ctx.put)((ctx.get)(ctx.index)),
-        OptimisedBlock::AtomicEffect(lines, offset) => {
-            let mut buffer = HashMap::<i32, u8>::new();
-            fn compute<
-                'a,
-                Ask: FnMut() -> u8,
-                Put: FnMut(u8) -> (),
-                Get: FnMut(i32) -> u8,
-                Set: FnMut(i32, u8) -> (),
-                Clear: Fn() -> ()
-            >(
-                ctx: &mut BFCtx<Ask, Put, Get, Set, Clear>,
-                expr: &'a ProcExpr
-            ) -> Option<u8> {
-                match expr {
-                    ProcExpr::Lit(x) => Some(*x),
-                    ProcExpr::Reg(r) => Some((ctx.get)(ctx.index + r)),
-                    ProcExpr::Add(a, b) =>
-                        if let Some(x) = compute(ctx, a) {
-                            if let Some(y) = compute(ctx, b) {
-                                Some(
-                                    x.wrapping_add(y)
-                                )
-                            } else {
-                                None
-                            }
-                        } else {
-                            None
-                        },
-                    ProcExpr::Mul(a, b) =>
-                        if let Some(x) = compute(ctx, a) {
-                            if let Some(y) = compute(ctx, b) {
-                                Some(
-                                    x.wrapping_mul(y)
-                                )
-                            } else {
-                                None
-                            }
-                        } else {
-                            None
-                        },
-                    ProcExpr::Into(a, b) => 
-                    if let Some(x) = compute(ctx, a) {
-                        if let Some(y) = compute(ctx, b) {
-                            div_u8(y, x)
-                        } else {
-                            None
-                        }
-                    } else {
-                        None
-                    },
-                }
-            }
-            for (register, expr) in lines {
-                if let Some(val) = compute(ctx, expr) {
-                    buffer.insert(*register, val);
-                } else {
-                    return false;
-                }
-            }
-            for (k, v) in buffer {
-                (ctx.set)(ctx.index + k, v)
+        Lowered::Ask(register) => { (ctx.set)(ctx.index + register, (ctx.ask)()); },
+        Lowered::Put(register) => (ctx.put)((ctx.get)(ctx.index + register)),
+        Lowered::AtomicEffect(lines, offset, slots) => {
+            let index = ctx.index;
+            let Some(values) = run_effect(lines, slots, &mut |r| (ctx.get)(index + r)) else { return false; };
+            for (register, val) in values {
+                (ctx.set)(ctx.index + register, val)
             }
             ctx.index += offset
         },
-        OptimisedBlock::Loop(blocks) => {
-            while (ctx.get)(ctx.index) != 0 {
-                for b_ in blocks { if !run_bfoptimised_block(ctx, b_) { return false; } }
+        Lowered::Loop(register, blocks) => {
+            while (ctx.get)(ctx.index + register) != 0 {
+                for b_ in blocks { if !run_lowered(ctx, b_) { return false; } }
+            }
+        },
+        Lowered::If(register, blocks) => {
+            if (ctx.get)(ctx.index + register) != 0 {
+                for b_ in blocks { if !run_lowered(ctx, b_) { return false; } }
             }
         }
     };
     true
 }
 
+pub fn run_bfoptimised_block<
+    Ask: FnMut() -> u8,
+    Put: FnMut(u8) -> (),
+    Get: FnMut(i32) -> u8,
+    Set: FnMut(i32, u8) -> (),
+    Clear: Fn() -> ()
+>(
+    ctx: &mut BFCtx<Ask, Put, Get, Set, Clear>,
+    b: & OptimisedBlock
+)
+    -> bool
+{
+    run_lowered(ctx, & lower(b))
+}
+
 pub fn run_bfoptimised<
     Ask: FnMut() -> u8,
     Put: FnMut(u8) -> (),
@@ -174,13 +227,13 @@ pub fn run_bfoptimised<
 )
     -> bool
 {
-    for b in bs {
-        if !run_bfoptimised_block(ctx, &b) { return false; }
+    for b in bs.iter().map(lower).collect::<Vec<Lowered>>() {
+        if !run_lowered(ctx, &b) { return false; }
     };
     true
 }
 
-pub fn async_run_bfoptimised_block<
+fn async_run_lowered<
     'a,
     AskFuture: Future::<Output=u8>,
     Ask: FnMut() -> AskFuture,
@@ -190,82 +243,30 @@ pub fn async_run_bfoptimised_block<
     Clear: Fn() -> ()
 >(
     ctx: &'a mut AsyncBFCtx<AskFuture, Ask, Put, Get, Set, Clear>,
-    b: &'a OptimisedBlock
+    b: &'a Lowered<'a>
 )
     -> Pin<Box<dyn Future<Output=bool> + 'a>>
 {
     Box::pin( async move {
         match b {
-            OptimisedBlock::Ask => { (ctx.set)(ctx.index, (ctx.ask)().await); },
-            OptimisedBlock::Put => (ctx.put)((ctx.get)(ctx.index)),
-            OptimisedBlock::AtomicEffect(lines, offset) => {
-                let mut buffer = HashMap::<i32, u8>::new();
-                fn compute<
-                    'a,
-                    AskFuture: Future::<Output=u8>,
-                    Ask: FnMut() -> AskFuture,
-                    Put: FnMut(u8) -> (),
-                    Get: FnMut(i32) -> u8,
-                    Set: FnMut(i32, u8) -> (),
-                    Clear: Fn() -> ()
-                >(
-                    ctx: &mut AsyncBFCtx<AskFuture, Ask, Put, Get, Set, Clear>,
-                    expr: &'a ProcExpr
-                ) -> Option<u8> {
-                        match expr {
-                            ProcExpr::Lit(x) => Some(*x),
-                            ProcExpr::Reg(r) => Some((ctx.get)(ctx.index + r)),
-                            ProcExpr::Add(a, b) =>
-                                if let Some(x) = compute(ctx, a) {
-                                    if let Some(y) = compute(ctx, b) {
-                                        Some(
-                                            x.wrapping_add(y)
-                                        )
-                                    } else {
-                                        None
-                                    }
-                                } else {
-                                    None
-                                },
-                            ProcExpr::Mul(a, b) =>
-                                if let Some(x) = compute(ctx, a) {
-                                    if let Some(y) = compute(ctx, b) {
-                                        Some(
-                                            x.wrapping_mul(y)
-                                        )
-                                    } else {
-                                        None
-                                    }
-                                } else {
-                                    None
-                                },
-                            ProcExpr::Into(a, b) => 
-                            if let Some(x) = compute(ctx, a) {
-                                if let Some(y) = compute(ctx, b) {
-                                    div_u8(y, x)
-                                } else {
-                                    None
-                                }
-                            } else {
-                                None
-                            },
-                        }
-                }
-                for (register, expr) in lines {
-                    if let Some(val) = compute(ctx, expr) {
-                        buffer.insert(*register, val);
-                    } else {
-                        return false;
-                    }
-                }
-                for (k, v) in buffer {
-                    (ctx.set)(ctx.index + k, v)
+            Lowered::Ask(register) => { (ctx.set)(ctx.index + register, (ctx.ask)().await); },
+            Lowered::Put(register) => (ctx.put)((ctx.get)(ctx.index + register)),
+            Lowered::AtomicEffect(lines, offset, slots) => {
+                let index = ctx.index;
+                let Some(values) = run_effect(lines, slots, &mut |r| (ctx.get)(index + r)) else { return false; };
+                for (register, val) in values {
+                    (ctx.set)(ctx.index + register, val)
                 }
                 ctx.index += offset
             },
-            OptimisedBlock::Loop(blocks) => {
-                while (ctx.get)(ctx.index) != 0 {
-                    for b_ in blocks { if !(async_run_bfoptimised_block(ctx, b_).await) { return false; } }
+            Lowered::Loop(register, blocks) => {
+                while (ctx.get)(ctx.index + register) != 0 {
+                    for b_ in blocks { if !(async_run_lowered(ctx, b_).await) { return false; } }
+                }
+            },
+            Lowered::If(register, blocks) => {
+                if (ctx.get)(ctx.index + register) != 0 {
+                    for b_ in blocks { if !(async_run_lowered(ctx, b_).await) { return false; } }
                 }
             }
         };
@@ -273,6 +274,26 @@ pub fn async_run_bfoptimised_block<
     })
 }
 
+pub fn async_run_bfoptimised_block<
+    'a,
+    AskFuture: Future::<Output=u8>,
+    Ask: FnMut() -> AskFuture,
+    Put: FnMut(u8) -> (),
+    Get: FnMut(i32) -> u8,
+    Set: FnMut(i32, u8) -> (),
+    Clear: Fn() -> ()
+>(
+    ctx: &'a mut AsyncBFCtx<AskFuture, Ask, Put, Get, Set, Clear>,
+    b: &'a OptimisedBlock
+)
+    -> Pin<Box<dyn Future<Output=bool> + 'a>>
+{
+    Box::pin( async move {
+        let lowered = lower(b);
+        async_run_lowered(ctx, & lowered).await
+    })
+}
+
 pub async fn async_run_bfoptimised<
     AskFuture: Future::<Output=u8>,
     Ask: FnMut() -> AskFuture,
@@ -286,8 +307,8 @@ pub async fn async_run_bfoptimised<
 )
     -> bool
 {
-    for b in bs {
-        if !(async_run_bfoptimised_block(ctx, &b).await) { return false; }
+    for b in bs.iter().map(lower).collect::<Vec<Lowered>>() {
+        if !(async_run_lowered(ctx, &b).await) { return false; }
     };
     true
-}
\ No newline at end of file
+}
@@ -1,37 +1,66 @@
-use std::{fmt, collections::HashMap};
-use std::rc::Rc;
+use std::{fmt, collections::{HashMap, BTreeMap}};
 
+pub mod backends;
+pub mod expr;
 pub mod interpreter;
 pub mod repl;
 pub mod optimiser;
+pub mod parser;
+pub mod passes;
+pub mod serialise;
+pub mod statistics;
+pub mod termination;
+
+pub use expr::Expr;
 
 #[derive(Debug, Hash, PartialOrd, Ord, PartialEq, Eq, Clone)]
 pub enum ProcExpr {
     Lit(u8),
     Reg(i32),
-    Add(Rc<ProcExpr>, Rc<ProcExpr>),
-    Mul(Rc<ProcExpr>, Rc<ProcExpr>),
-    Into(Rc<ProcExpr>, Rc<ProcExpr>) // Into(2, 8) = 4; Into(5, 4) = 52; Into(2, 3) = throw; Into(x, 0) = 0; Into(0, x) = throw; // throw when would forever-loop
+    Add(Expr, Expr),
+    Mul(Expr, Expr),
+    Into(Expr, Expr), // Into(2, 8) = 4; Into(5, 4) = 52; Into(2, 3) = throw; Into(x, 0) = 0; Into(0, x) = throw; // throw when would forever-loop
+    Choose(Expr, u8), // Choose(5, 2) = 10; Choose(x, 0) = 1; Choose(x, 1) = x; // x is taken as an integer in 0..256, not mod 256
+    If(Expr, Expr, Expr), // If(1, x, y) = x; If(0, x, y) = y; // only the chosen side is evaluated, so the other may throw
+    IsZero(Expr) // IsZero(0) = 1; IsZero(x) = 0;
 }
 
-#[derive(Debug)]
+#[derive(Debug, Clone, PartialEq)]
 pub enum OptimisedBlock {
-    Ask,
-    Put,
-    AtomicEffect(HashMap<i32, Rc<ProcExpr>>, i32),
-    Loop(Vec<OptimisedBlock>)
+    Ask(i32), // every block other than AtomicEffect acts on the register this far from the head
+    Put(i32),
+    AtomicEffect(BTreeMap<i32, Expr>, i32),
+    Loop(i32, Vec<OptimisedBlock>, Option<optimiser::LoopRejection>), // and why the optimiser couldn't solve it, when it tried
+    If(i32, Vec<OptimisedBlock>) // a loop that can only run once, as it always exits on a zero cell
+}
+
+// shows an expression, with any of the given temporaries inside it written by name
+fn show_expr(
+    expr: & ProcExpr,
+    temporaries: & HashMap<Expr, usize>
+)
+    -> String
+{
+    let show = |x: & Expr| match temporaries.get(x) {
+        Some(i) => format!("${i}"),
+        None => show_expr(x, temporaries)
+    };
+    match expr {
+        ProcExpr::Lit(x) => format!("{x}"),
+        ProcExpr::Reg(r) => format!("~#{r}"),
+        ProcExpr::Add(a, b) => format!("({} + {})", show(a), show(b)),
+        ProcExpr::Mul(a, b) => format!("{} * {}", show(a), show(b)),
+        ProcExpr::Into(a, b) => format!("({} into {})", show(a), show(b)),
+        ProcExpr::Choose(a, k) => format!("({} choose {k})", show(a)),
+        ProcExpr::If(c, a, b) => format!("({} ? {} : {})", show(c), show(a), show(b)),
+        ProcExpr::IsZero(a) => format!("({} == 0)", show(a)),
+    }
 }
 
 impl fmt::Display for ProcExpr {
 
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
-        match self {
-            ProcExpr::Lit(x) => write!(f, "{x}"),
-            ProcExpr::Reg(r) => write!(f, "~#{r}"),
-            ProcExpr::Add(a, b) => write!(f, "({a} + {b})"),
-            ProcExpr::Mul(a, b) => write!(f, "{a} * {b}"),
-            ProcExpr::Into(a, b) => write!(f, "({a} into {b})"),
-        }
+        write!(f, "{}", show_expr(self, & HashMap::new()))
     }
 }
 
@@ -53,23 +82,48 @@ impl fmt::Display for OptimisedBlock {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
 
         match self {
-            OptimisedBlock::Ask => write!(f, "ask"),
-            OptimisedBlock::Put => write!(f, "put"),
+            OptimisedBlock::Ask(0) => write!(f, "ask"),
+            OptimisedBlock::Ask(register) => write!(f, "ask ~#{register}"),
+            OptimisedBlock::Put(0) => write!(f, "put"),
+            OptimisedBlock::Put(register) => write!(f, "put ~#{register}"),
             OptimisedBlock::AtomicEffect(lines, effect) => 
                 if lines.is_empty() {
                     write!( f, "block {{}} (move {effect})")
                 } else {
+                    // subexpressions used more than once are worked out once, as temporaries
+                    let shared = expr::shared_subexpressions(lines.values());
+                    let temporaries: HashMap<Expr, usize> = shared.iter().enumerate().map(|(i, expr)| (expr.clone(), i)).collect();
                     write!( f,
                         "block {{\n{}\n}} (move {effect})",
                         indent_string(
                             join_strings(
-                                lines.into_iter().map(|(register, expr)| format!("~#{} = {};", register, expr))
+                                shared.iter().enumerate().map(|(i, expr)| format!("${} = {};", i, show_expr(expr, & temporaries)))
+                                    .chain(lines.iter().map(|(register, expr)| {
+                                        match temporaries.get(expr) {
+                                            Some(i) => format!("~#{} = ${};", register, i),
+                                            None => format!("~#{} = {};", register, show_expr(expr, & temporaries))
+                                        }
+                                    }))
                             )
                         )
                     )
                 },
-            OptimisedBlock::Loop(lines) => write!( f,
-                "loop [\n{}\n]",
+            OptimisedBlock::Loop(register, lines, rejection) => write!( f,
+                "loop {}[{}\n{}\n]",
+                if *register == 0 { "".to_string() } else { format!("~#{register} ") },
+                match rejection {
+                    Some(rejection) => format!(" // not solved: {rejection}"),
+                    None => "".to_string()
+                },
+                indent_string(
+                    join_strings(
+                        lines.into_iter().map(|line| format!("{line}"))
+                    )
+                )
+            ),
+            OptimisedBlock::If(register, lines) => write!( f,
+                "if {}[\n{}\n]",
+                if *register == 0 { "".to_string() } else { format!("~#{register} ") },
                 indent_string(
                     join_strings(
                         lines.into_iter().map(|line| format!("{line}"))
@@ -84,6 +138,27 @@ pub fn byte_code_pretty(bs: & Vec<OptimisedBlock>) -> String {
     join_strings(bs.iter().map(|line| format!("{line}")))
 }
 
+fn choose_u8(x: u8, k: u8) -> u8 {
+    // pascal's triangle mod 256, as the binomial itself won't fit
+    let mut row = vec![0u8; k as usize + 1];
+    row[0] = 1;
+    for _ in 0..x {
+        for j in (1..row.len()).rev() {
+            row[j] = row[j].wrapping_add(row[j - 1]);
+        }
+    }
+    row[k as usize]
+}
+
+fn inverse_u8(x: u8) -> u8 {
+    // newton's method, each step doubles the number of correct low bits, x is its own inverse mod 8 when odd
+    let mut inverse = x;
+    for _ in 0..3 {
+        inverse = inverse.wrapping_mul(2u8.wrapping_sub(x.wrapping_mul(inverse)));
+    }
+    inverse
+}
+
 fn div_u8(x: u8, y: u8) -> Option<u8> {
     if x == 0 { return Some(0); }
     if y == 0 { return None; }
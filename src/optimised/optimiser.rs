@@ -1,8 +1,276 @@
-use std::collections::{HashMap, HashSet, BTreeMap};
-use std::rc::Rc;
+use std::collections::{HashMap, HashSet, BTreeMap, BTreeSet};
 
 use crate::*;
-use super::*;
+use super::{ *, passes::*, statistics::* };
+
+#[cfg(test)]
+pub mod tests {
+    use std::cell::RefCell;
+
+    use crate::interpreter::*;
+    use super::super::interpreter::*;
+    use super::*;
+
+    pub fn raw(s: &str) -> Vec<BFRaw> {
+        fn go(cs: &mut std::str::Chars) -> Vec<BFRaw> {
+            let mut is = vec![];
+            while let Some(c) = cs.next() {
+                match c {
+                    '<' => is.push(BFRaw::Lft),
+                    '>' => is.push(BFRaw::Rgh),
+                    '+' => is.push(BFRaw::Inc),
+                    '-' => is.push(BFRaw::Dec),
+                    ',' => is.push(BFRaw::Ask),
+                    '.' => is.push(BFRaw::Put),
+                    '[' => is.push(BFRaw::Loop(go(cs))),
+                    ']' => break,
+                    _ => ()
+                }
+            }
+            is
+        }
+        go(&mut s.chars())
+    }
+
+    fn snapshot(memory: &RefCell<HashMap<i32, u8>>) -> Vec<(i32, u8)> {
+        let mut cells: Vec<(i32, u8)> = memory.borrow().iter().filter(|(_, v)| **v != 0).map(|(k, v)| (*k, *v)).collect();
+        cells.sort();
+        cells
+    }
+
+    // runs the optimised program from the given tape and input, returning the final tape, head and output
+    pub fn run_optimised(bs: Vec<OptimisedBlock>, tape: &[(i32, u8)], input: &[u8]) -> (Vec<(i32, u8)>, i32, Vec<u8>) {
+        let memory = RefCell::new(tape.iter().cloned().collect::<HashMap<i32, u8>>());
+        let input = RefCell::new(input.iter());
+        let output = RefCell::new(vec![]);
+        let mut ctx = BFCtx{
+            index: 0,
+            ask: || input.borrow_mut().next().cloned().unwrap_or(0),
+            put: |x| output.borrow_mut().push(x),
+            get: |i| *memory.borrow().get(& i).unwrap_or(&0),
+            set: |i, x| { memory.borrow_mut().insert(i, x); },
+            clear: || memory.borrow_mut().clear()
+        };
+        assert!(run_bfoptimised(&mut ctx, bs), "optimised program aborted");
+        let index = ctx.index;
+        (snapshot(& memory), index, output.into_inner())
+    }
+
+    // runs both the raw and optimised programs from the same tape and input, returning the final tapes, heads and outputs
+    pub fn run_both(s: &str, tape: &[(i32, u8)], input: &[u8]) -> ((Vec<(i32, u8)>, i32, Vec<u8>), (Vec<(i32, u8)>, i32, Vec<u8>)) {
+        let memory = RefCell::new(tape.iter().cloned().collect::<HashMap<i32, u8>>());
+        let raw_input = RefCell::new(input.iter());
+        let output = RefCell::new(vec![]);
+        let mut ctx = BFCtx{
+            index: 0,
+            ask: || raw_input.borrow_mut().next().cloned().unwrap_or(0),
+            put: |x| output.borrow_mut().push(x),
+            get: |i| *memory.borrow().get(& i).unwrap_or(&0),
+            set: |i, x| { memory.borrow_mut().insert(i, x); },
+            clear: || memory.borrow_mut().clear()
+        };
+        run_bfraw(&mut ctx, & raw(s));
+        let index = ctx.index;
+
+        (
+            (snapshot(& memory), index, output.into_inner()),
+            run_optimised(optimising_convert(raw(s)), tape, input)
+        )
+    }
+
+    #[test]
+    fn polynomial_loop_optimise() {
+        for s in [
+            "[>+[>+<-]<-]",
+            "[>+[>+>+<<-]>>[<<+>>-]<<<-]",
+            "[>+[>+>+<<-]>>[<<+>>-]<[>+>+<<-]>[<+>-]<<<-]",
+            "[->[>+>+<<-]>[<+>-]<<]",
+            "[>[>+>+<<-]>>[<<+>>-]<<<--]"
+        ] {
+            let optimised = optimising_convert(raw(s));
+            assert!(
+                matches!(optimised.as_slice(), [OptimisedBlock::AtomicEffect(_, 0)]),
+                "{s} was not solved:\n{}", byte_code_pretty(& optimised)
+            );
+            for x in [0, 1, 2, 3, 7, 100, 254, 255] {
+                if s.ends_with("--]") && x % 2 == 1 { continue; }
+                for y in [0, 1, 5, 200] {
+                    let (raw_result, optimised_result) = run_both(s, &[(0, x), (1, y), (2, 3)], &[]);
+                    assert_eq!(raw_result, optimised_result, "{s} diverged for ~#0 = {x}, ~#1 = {y}");
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn if_optimise() {
+        for (s, single_block) in [
+            ("[>+<[-]]", true),
+            ("+>++<[>[-]+<[-]]>", true),
+            ("[[-]]", true),
+            ("[>+>+<<[-]]>>[<<+>>-]<<", true),
+            ("[>[>+<-]<[-]]", true),
+            ("[>+<[>+<-]]", true),
+            ("[>+<[.-]]", false),
+            ("[>+[-]]", false)
+        ] {
+            let optimised = optimising_convert(raw(s));
+            assert_eq!(
+                matches!(optimised.as_slice(), [OptimisedBlock::AtomicEffect(..)]), single_block,
+                "{s} optimised to:\n{}", byte_code_pretty(& optimised)
+            );
+            for x in [0, 1, 2, 255] {
+                for y in [0, 1, 7] {
+                    let (raw_result, optimised_result) = run_both(s, &[(0, x), (1, y), (2, 3)], &[]);
+                    assert_eq!(raw_result, optimised_result, "{s} diverged for ~#0 = {x}, ~#1 = {y}");
+                }
+            }
+        }
+        assert!(matches!(optimising_convert(raw("[>+<[.-]]")).as_slice(), [OptimisedBlock::If(..)]));
+        assert!(matches!(optimising_convert(raw("[>+[-]]")).as_slice(), [OptimisedBlock::If(..)]));
+    }
+
+    #[test]
+    fn loop_hoist() {
+        for s in [
+            ",[.>[-]++++++++++.<,]",
+            ",[>[-]+++<.,]",
+            ",[>[-]+>[-]++<<.>.<,]"
+        ] {
+            let optimised = optimising_convert(raw(s));
+            assert!(
+                matches!(optimised.as_slice(), [OptimisedBlock::Ask(0), OptimisedBlock::If(0, _)]),
+                "{s} optimised to:\n{}", byte_code_pretty(& optimised)
+            );
+            for input in [&b""[..], b"a", b"hello"] {
+                for y in [0, 1, 7] {
+                    let (raw_result, optimised_result) = run_both(s, &[(1, y), (2, 3)], input);
+                    assert_eq!(raw_result, optimised_result, "{s} diverged for ~#1 = {y}");
+                }
+            }
+        }
+
+        // ~#2 only depends on what was read on the last iteration
+        let body = vec![
+            OptimisedBlock::Ask(0),
+            OptimisedBlock::AtomicEffect(BTreeMap::from([
+                (2, Expr::new(ProcExpr::Add(Expr::new(ProcExpr::Reg(0)), Expr::new(ProcExpr::Lit(1)))))
+            ]), 0),
+            OptimisedBlock::Put(0)
+        ];
+        let Some(OptimisedBlock::If(0, guarded)) = try_loop_hoist(& body, & LoopRejection::Io(0), &mut Statistics::default()) else { panic!("nothing was sunk") };
+        assert!(matches!(guarded.as_slice(), [OptimisedBlock::Loop(0, ..), OptimisedBlock::AtomicEffect(..)]));
+    }
+
+    #[test]
+    fn offset_optimise() {
+        for s in [
+            ",[.>[-]++++++++++.<,]",
+            ">>,[>+<-]>.<<.",
+            ">,[>.<-]>>>.<<[>.<-]",
+            ">,[>[>+<-]<[-]]>.",
+            "+[>,.]<<."
+        ] {
+            for input in [&b""[..], b"a", b"\x03", b"hello"] {
+                let (raw_result, optimised_result) = run_both(s, &[(0, 1), (5, 3)], input);
+                assert_eq!(raw_result, optimised_result, "{s} diverged for {input:?}");
+            }
+        }
+        let pretty = byte_code_pretty(& optimising_convert(raw(",[.>[-]++++++++++.<,]")));
+        assert_eq!(pretty.matches("(move ").count(), pretty.matches("(move 0)").count(), "moves were left in:\n{pretty}");
+    }
+
+    #[test]
+    fn loop_rejections() {
+        for (s, expected) in [
+            ("[.-]", "does I/O on ~#0"),
+            ("[>]", "moves the head by 1"),
+            ("[>+<]", "never changes ~#0"),
+            ("[->[.-]<]", "has an unsolved loop on ~#1"),
+            ("[>+[<->-]<]", "takes"),
+            ("[->[->++<]>[-<+>]<<]", "has no closed form for ~#1")
+        ] {
+            let pretty = byte_code_pretty(& optimising_convert(raw(s)));
+            assert!(pretty.contains(& format!("// not solved: {expected}")), "{s} optimised to:\n{pretty}");
+        }
+        assert_eq!(explain_loop(0, & vec![OptimisedBlock::AtomicEffect(BTreeMap::from([(0, Expr::new(ProcExpr::Add(Expr::new(ProcExpr::Reg(0)), Expr::new(ProcExpr::Lit(255)))))]), 0)]), None);
+        assert_eq!(explain_loop(2, & vec![OptimisedBlock::Put(3)]), Some(LoopRejection::Io(1)));
+        // effects that haven't been grouped give the reason their group would
+        let step = |r: i32| OptimisedBlock::AtomicEffect(BTreeMap::from([(r, Expr::new(ProcExpr::Add(Expr::new(ProcExpr::Reg(r)), Expr::new(ProcExpr::Lit(1)))))]), 0);
+        assert_eq!(explain_loop(0, & vec![step(1), OptimisedBlock::AtomicEffect(BTreeMap::new(), 1)]), Some(LoopRejection::Moves(1)));
+        assert_eq!(explain_loop(0, & vec![step(1), step(0)]), None);
+        assert_eq!(explain_loop(0, & vec![]), Some(LoopRejection::CounterUntouched));
+        assert!(!byte_code_pretty(& optimising_convert(raw("[->+<]"))).contains("not solved"));
+    }
+
+    #[test]
+    fn into_optimise() {
+        for (s, into) in [
+            ("[--->+<]", false),
+            ("[+>+<]", false),
+            ("[------>+<]", true),
+            ("[-]>[-<++>]<[-->++<]", false),
+            ("[-]>[-<++>]<[-->+<]", true)
+        ] {
+            let pretty = byte_code_pretty(& optimising_convert(raw(s)));
+            assert_eq!(pretty.contains("into"), into, "{s} optimised to:\n{pretty}");
+            // an odd ~#0 makes the even steps loop forever
+            for x in [0, 2, 6, 12, 130, 255].into_iter().filter(|x| !into || x % 2 == 0) {
+                let (raw_result, optimised_result) = run_both(s, &[(0, x), (1, 5)], &[]);
+                assert_eq!(raw_result, optimised_result, "{s} diverged for ~#0 = {x}");
+            }
+        }
+        for k in (1..=255u8).step_by(2) {
+            assert_eq!(k.wrapping_mul(inverse_u8(k)), 1, "{k} wasn't inverted");
+        }
+    }
+
+    #[test]
+    fn stable_output() {
+        let s = "[-->+>+<<],[->+<]>>>[-]<<<";
+        let pretty = byte_code_pretty(& optimising_convert(raw(s)));
+        assert_eq!(pretty, [
+            "block {",
+            "\t$0 = (2 into ~#0);",
+            "\t~#0 = 0;",
+            "\t~#1 = (~#1 + $0);",
+            "\t~#2 = (~#2 + $0);",
+            "} (move 0)",
+            "ask",
+            "block {",
+            "\t~#0 = 0;",
+            "\t~#1 = (~#0 + ~#1);",
+            "\t~#3 = 0;",
+            "} (move 0)"
+        ].join("\n"));
+        for _ in 0..10 {
+            assert_eq!(byte_code_pretty(& optimising_convert(raw(s))), pretty);
+        }
+    }
+
+    #[test]
+    fn conditional_optimise() {
+        for s in [
+            ">+<[>-<[-]]>",
+            "[>[>>+<<[-]]<[-]]",
+            "[->[>+<[-]]<]",
+            "[->[>+<[-]]+<]",
+            ">>+<<[>[>-<[-]]<[-]]>>[<<+>>-]<<"
+        ] {
+            let optimised = optimising_convert(raw(s));
+            assert!(
+                matches!(optimised.as_slice(), [OptimisedBlock::AtomicEffect(..)]),
+                "{s} optimised to:\n{}", byte_code_pretty(& optimised)
+            );
+            for x in [0, 1, 2, 255] {
+                for y in [0, 1, 7] {
+                    let (raw_result, optimised_result) = run_both(s, &[(0, x), (1, y), (2, 3)], &[]);
+                    assert_eq!(raw_result, optimised_result, "{s} diverged for ~#0 = {x}, ~#1 = {y}");
+                }
+            }
+        }
+    }
+}
 
 pub fn convert(
     raw: Vec<BFRaw>
@@ -10,7 +278,7 @@ pub fn convert(
     -> Vec<OptimisedBlock>
 {
     let mut bs: Vec<OptimisedBlock> = vec![];
-    let mut diff: HashMap<i32, u8> = HashMap::new();
+    let mut diff: BTreeMap<i32, u8> = BTreeMap::new();
     let mut offset: i32 = 0;
 
     macro_rules! flush_block {
@@ -26,9 +294,9 @@ pub fn convert(
                     OptimisedBlock::AtomicEffect(
                         diff.into_iter().map(|(k, v)| (
                             k,
-                            Rc::new(ProcExpr::Add(
-                                Rc::new(ProcExpr::Reg(k)),
-                                Rc::new(ProcExpr::Lit(v))
+                            Expr::new(ProcExpr::Add(
+                                Expr::new(ProcExpr::Reg(k)),
+                                Expr::new(ProcExpr::Lit(v))
                             ))
                         )).collect(),
                         offset
@@ -50,15 +318,15 @@ pub fn convert(
                     OptimisedBlock::AtomicEffect(
                         diff.into_iter().map(|(k, v)| (
                             k,
-                            Rc::new(ProcExpr::Add(
-                                Rc::new(ProcExpr::Reg(k)),
-                                Rc::new(ProcExpr::Lit(v))
+                            Expr::new(ProcExpr::Add(
+                                Expr::new(ProcExpr::Reg(k)),
+                                Expr::new(ProcExpr::Lit(v))
                             ))
                         )).collect(),
                         offset
                     )
                 );
-                diff = HashMap::new();
+                diff = BTreeMap::new();
                 offset = 0;
             }
         };
@@ -72,15 +340,15 @@ pub fn convert(
             BFRaw::Dec => { diff.insert(offset, diff.get(& offset).unwrap_or(& 0).wrapping_add(255)); },
             BFRaw::Ask => {
                 flush_block_reset!();
-                bs.push(OptimisedBlock::Ask)
+                bs.push(OptimisedBlock::Ask(0))
             },
             BFRaw::Put => {
                 flush_block_reset!();
-                bs.push(OptimisedBlock::Put)
+                bs.push(OptimisedBlock::Put(0))
             },
             BFRaw::Loop(is) => {
                 flush_block_reset!();
-                bs.push(OptimisedBlock::Loop(convert(is)))
+                bs.push(OptimisedBlock::Loop(0, convert(is), None))
             },
         }
     };
@@ -88,64 +356,88 @@ pub fn convert(
     bs
 }
 
+fn shift(
+    s: i32,
+    x: Expr
+)
+    -> Expr
+{
+    Expr::new(match x.as_ref() {
+        ProcExpr::Reg(r) => ProcExpr::Reg(r + s),
+        ProcExpr::Lit(v) => ProcExpr::Lit(*v),
+        ProcExpr::Add(a, b) => ProcExpr::Add(
+            shift(s, a.clone()),
+            shift(s, b.clone())
+        ),
+        ProcExpr::Mul(a, b) => ProcExpr::Mul(
+            shift(s, a.clone()),
+            shift(s, b.clone())
+        ),
+        ProcExpr::Into(a, b) => ProcExpr::Into(
+            shift(s, a.clone()),
+            shift(s, b.clone())
+        ),
+        ProcExpr::Choose(a, k) => ProcExpr::Choose(
+            shift(s, a.clone()),
+            *k
+        ),
+        ProcExpr::If(c, a, b) => ProcExpr::If(
+            shift(s, c.clone()),
+            shift(s, a.clone()),
+            shift(s, b.clone())
+        ),
+        ProcExpr::IsZero(a) => ProcExpr::IsZero(
+            shift(s, a.clone())
+        )
+    })
+}
+
+fn replace(
+    lines: & BTreeMap<i32, Expr>,
+    x: Expr
+)
+    -> Expr
+{
+    match x.as_ref() {
+        ProcExpr::Reg(r) => lines.get(r).unwrap_or(&x).clone(),
+        ProcExpr::Lit(v) => Expr::new(ProcExpr::Lit(*v)),
+        ProcExpr::Add(a, b) => Expr::new(ProcExpr::Add(
+            replace(lines, a.clone()),
+            replace(lines, b.clone())
+        )),
+        ProcExpr::Mul(a, b) => Expr::new(ProcExpr::Mul(
+            replace(lines, a.clone()),
+            replace(lines, b.clone())
+        )),
+        ProcExpr::Into(a, b) => Expr::new(ProcExpr::Into(
+            replace(lines, a.clone()),
+            replace(lines, b.clone())
+        )),
+        ProcExpr::Choose(a, k) => Expr::new(ProcExpr::Choose(
+            replace(lines, a.clone()),
+            *k
+        )),
+        ProcExpr::If(c, a, b) => Expr::new(ProcExpr::If(
+            replace(lines, c.clone()),
+            replace(lines, a.clone()),
+            replace(lines, b.clone())
+        )),
+        ProcExpr::IsZero(a) => Expr::new(ProcExpr::IsZero(
+            replace(lines, a.clone())
+        ))
+    }
+}
+
 fn try_merge(
     a: & OptimisedBlock,
     b: & OptimisedBlock
 )
     -> Option<OptimisedBlock>
 {
-    fn shift(
-        s: i32,
-        x: Rc<ProcExpr>
-    )
-        -> Rc<ProcExpr>
-    {
-        Rc::new(match x.as_ref() {
-            ProcExpr::Reg(r) => ProcExpr::Reg(r + s),
-            ProcExpr::Lit(v) => ProcExpr::Lit(*v),
-            ProcExpr::Add(a, b) => ProcExpr::Add(
-                shift(s, a.clone()),
-                shift(s, b.clone())
-            ),
-            ProcExpr::Mul(a, b) => ProcExpr::Mul(
-                shift(s, a.clone()),
-                shift(s, b.clone())
-            ),
-            ProcExpr::Into(a, b) => ProcExpr::Into(
-                shift(s, a.clone()),
-                shift(s, b.clone())
-            )
-        })
-    }
-
-    pub fn replace(
-        lines: & HashMap<i32, Rc<ProcExpr>>,
-        x: Rc<ProcExpr>
-    )
-        -> Rc<ProcExpr>
-    {
-        match x.as_ref() {
-            ProcExpr::Reg(r) => lines.get(r).unwrap_or(&x).clone(),
-            ProcExpr::Lit(v) => Rc::new(ProcExpr::Lit(*v)),
-            ProcExpr::Add(a, b) => Rc::new(ProcExpr::Add(
-                replace(lines, a.clone()),
-                replace(lines, b.clone())
-            )),
-            ProcExpr::Mul(a, b) => Rc::new(ProcExpr::Mul(
-                replace(lines, a.clone()),
-                replace(lines, b.clone())
-            )),
-            ProcExpr::Into(a, b) => Rc::new(ProcExpr::Into(
-                replace(lines, a.clone()),
-                replace(lines, b.clone())
-            ))
-        }
-    }
-
     let OptimisedBlock::AtomicEffect(xs, i) = a else { return None; };
     let OptimisedBlock::AtomicEffect(ys, j) = b else { return None; };
-    let mut new_xs: HashMap<i32, Rc<ProcExpr>> = xs.clone();
-    let mut new_ys: HashMap<i32, Rc<ProcExpr>> = ys.iter().map(|(register, expr)| (register + i, shift(*i, expr.clone()))).collect();
+    let mut new_xs: BTreeMap<i32, Expr> = xs.clone();
+    let mut new_ys: BTreeMap<i32, Expr> = ys.iter().map(|(register, expr)| (register + i, shift(*i, expr.clone()))).collect();
     for (_, expr_y) in new_ys.iter_mut() {
         *expr_y = replace(&new_xs, expr_y.clone());
     }
@@ -155,14 +447,14 @@ fn try_merge(
 
 #[derive(Debug, Clone, Default, PartialEq, Eq)]
 struct Multinomial{
-    coefficients: HashMap<BTreeMap<Rc<ProcExpr>, u32>, u8>,
-    symbols: HashSet<Rc<ProcExpr>>
+    coefficients: BTreeMap<BTreeMap<Expr, u32>, u8>,
+    symbols: BTreeSet<Expr>
 }
 
 impl Multinomial {
-    pub fn symbol(expr: Rc<ProcExpr>) -> Multinomial {
+    pub fn symbol(expr: Expr) -> Multinomial {
         let mut res = Multinomial::default();
-        let mut map: BTreeMap<Rc<ProcExpr>, u32> = BTreeMap::new();
+        let mut map: BTreeMap<Expr, u32> = BTreeMap::new();
         map.insert(expr.clone(), 1);
         res.coefficients.insert(map, 1);
         res
@@ -189,10 +481,42 @@ impl Multinomial {
         }
     }
 
+    // c * (2^t into x) is (c / 2^t) * x when 2^t divides c, as long as 2^t always divides x too, so that it can't fail
+    // this needs the whole expression's coefficients, so it is done once by `reduce` rather than after every add and mul
+    fn _cancel_divisions(self) -> Multinomial {
+        let mut res = Multinomial::default();
+        let mut cancelled = vec![];
+        for (term, coefficient) in self.coefficients.into_iter() {
+            let cancellable = term.keys().find(|symbol| match symbol.as_ref() {
+                ProcExpr::Into(a, b) => divides(a, b) && matches!(a.as_ref(), ProcExpr::Lit(a_) if coefficient % a_ == 0),
+                _ => false
+            }).cloned();
+            let Some(symbol) = cancellable else {
+                res.coefficients.insert(term, coefficient);
+                continue;
+            };
+            let ProcExpr::Into(a, b) = symbol.as_ref() else { unreachable!() };
+            let ProcExpr::Lit(a_) = a.as_ref() else { unreachable!() };
+            let mut rest = term;
+            *rest.get_mut(& symbol).unwrap() -= 1;
+            if rest.get(& symbol) == Some(& 0) { rest.remove(& symbol); }
+            cancelled.push(
+                Multinomial{ coefficients: BTreeMap::from([(rest, coefficient / a_)]), symbols: BTreeSet::new() }
+                    .mul(& reduce_to_multinomial(b.clone()))
+            );
+        }
+        // the quotients may leave more to cancel
+        let more = !cancelled.is_empty();
+        for part in cancelled {
+            res = res.add(& part);
+        }
+        if more { res._cancel_divisions() } else { res }
+    }
+
     pub fn add(&self, other: &Self) -> Multinomial {
         let mut res = Multinomial::default();
-        let self_terms = self.coefficients.keys().collect::<HashSet<&BTreeMap<Rc<ProcExpr>, u32>>>();
-        let other_terms = other.coefficients.keys().collect::<HashSet<&BTreeMap<Rc<ProcExpr>, u32>>>();
+        let self_terms = self.coefficients.keys().collect::<BTreeSet<&BTreeMap<Expr, u32>>>();
+        let other_terms = other.coefficients.keys().collect::<BTreeSet<&BTreeMap<Expr, u32>>>();
         for term in self_terms.union(&other_terms).map(|map| (*map).clone()) {
             let coefficient = self.coefficients.get(&term).unwrap_or(&0).wrapping_add(*other.coefficients.get(&term).unwrap_or(&0));
             res.coefficients.insert(
@@ -216,6 +540,13 @@ impl Multinomial {
                 for (symbol, power) in other_term {
                     term.insert(symbol.clone(), term.get(symbol).unwrap_or(&0) + power);
                 }
+                // (x == 0) is only ever 0 or 1, so any power of it is itself, and x * (x == 0) is always 0
+                for (symbol, power) in term.iter_mut() {
+                    if let ProcExpr::IsZero(_) = symbol.as_ref() { *power = 1; }
+                }
+                if term.keys().any(|symbol| matches!(symbol.as_ref(), ProcExpr::IsZero(x) if term.contains_key(x))) {
+                    continue;
+                }
                 let coefficient = res.coefficients.get(&term).unwrap_or(&0).wrapping_add(self_coefficient.wrapping_mul(*other_coefficient));
                 res.coefficients.insert(term, coefficient);
             }
@@ -225,155 +556,491 @@ impl Multinomial {
         res
     }
 
-    pub fn as_val(&self) -> Rc<ProcExpr> {
+    pub fn as_val(&self) -> Expr {
         let mut coefficients = self.coefficients.iter();
         if let Some((term, coefficient)) = coefficients.next() {
-            fn as_prod(term: &BTreeMap<Rc<ProcExpr>, u32>, coefficient: &u8) -> Rc<ProcExpr> {
-                let mut expr = Rc::new(ProcExpr::Lit(*coefficient));
+            fn as_prod(term: &BTreeMap<Expr, u32>, coefficient: &u8) -> Expr {
+                let mut expr = if *coefficient == 1 { None } else { Some(Expr::new(ProcExpr::Lit(*coefficient))) };
                 for (symbol, power) in term {
                     for _ in 0..*power {
-                        expr = Rc::new(ProcExpr::Mul(expr, symbol.clone()))
-                    }
-                }
-                if *coefficient == 1 {
-                    if let ProcExpr::Mul(_, e) = expr.as_ref() {
-                        return e.clone();
+                        expr = Some(match expr {
+                            Some(e) => Expr::new(ProcExpr::Mul(e, symbol.clone())),
+                            None => symbol.clone()
+                        })
                     }
                 }
-                expr
+                expr.unwrap_or(Expr::new(ProcExpr::Lit(*coefficient)))
             }
             let mut expr = as_prod(term, coefficient);
             for (term, coefficient) in coefficients {
-                expr = Rc::new(ProcExpr::Add(expr, as_prod(term, coefficient)))
+                expr = Expr::new(ProcExpr::Add(expr, as_prod(term, coefficient)))
             }
             expr
         } else {
-            Rc::new(ProcExpr::Lit(0))
+            Expr::new(ProcExpr::Lit(0))
         }
     }
 }
 
-fn reduce(
-    expr: Rc<ProcExpr>
+fn reduce_to_multinomial(
+    expr: Expr
 )
-    -> Rc<ProcExpr>
+    -> Multinomial
 {
-    fn reduce_to_multinomial(
-        expr: Rc<ProcExpr>
-    )
-        -> Multinomial
-    {
-        match expr.as_ref() {
-            ProcExpr::Lit(x) => Multinomial::value(*x),
-            ProcExpr::Reg(_) => Multinomial::symbol(expr),
-            ProcExpr::Add(a, b) => reduce_to_multinomial(a.clone()).add(& reduce_to_multinomial(b.clone())),
-            ProcExpr::Mul(a, b) => reduce_to_multinomial(a.clone()).mul(& reduce_to_multinomial(b.clone())),
-            ProcExpr::Into(a, b) => {
-                let expr_a = reduce(a.clone());
-                let expr_b = reduce(b.clone());
-                if expr_b.as_ref() == &ProcExpr::Lit(0) {
-                    return Multinomial::default();
+    match expr.as_ref() {
+        ProcExpr::Lit(x) => Multinomial::value(*x),
+        ProcExpr::Reg(_) => Multinomial::symbol(expr),
+        ProcExpr::Add(a, b) => reduce_to_multinomial(a.clone()).add(& reduce_to_multinomial(b.clone())),
+        ProcExpr::Mul(a, b) => reduce_to_multinomial(a.clone()).mul(& reduce_to_multinomial(b.clone())),
+        ProcExpr::Into(a, b) => {
+            let expr_a = reduce(a.clone());
+            let expr_b = reduce(b.clone());
+            if expr_b.as_ref() == &ProcExpr::Lit(0) {
+                return Multinomial::default();
+            }
+            // x * 1 = x, and nothing smaller works unless x is 0
+            if expr_a == expr_b {
+                return Multinomial::value(1).add(& Multinomial::value(255).mul(& reduce_to_multinomial(
+                    Expr::new(ProcExpr::IsZero(expr_a))
+                )));
+            }
+            let ProcExpr::Lit(a_) = expr_a.as_ref() else {
+                return Multinomial::symbol(Expr::new(ProcExpr::Into(expr_a, expr_b)));
+            };
+            // odd numbers are invertible mod 256, so dividing by one always works
+            if a_ % 2 == 1 {
+                return Multinomial::value(inverse_u8(*a_)).mul(& reduce_to_multinomial(expr_b));
+            }
+            if let ProcExpr::Lit(b_) = expr_b.as_ref() {
+                if let Some(c) = div_u8(*b_, *a_) {
+                    return Multinomial::value(c);
                 }
-                if expr_a.as_ref() == &ProcExpr::Lit(1) {
-                    return reduce_to_multinomial(expr_b);
+            }
+            // only the power of two in the divisor can stop it dividing, the odd part can be multiplied out
+            let power = a_.trailing_zeros();
+            if *a_ != 0 && a_ >> power != 1 {
+                return reduce_to_multinomial(Expr::new(ProcExpr::Into(
+                    Expr::new(ProcExpr::Lit(1 << power)),
+                    Expr::new(ProcExpr::Mul(Expr::new(ProcExpr::Lit(inverse_u8(a_ >> power))), expr_b))
+                )));
+            }
+            Multinomial::symbol(Expr::new(ProcExpr::Into(expr_a, expr_b)))
+        },
+        ProcExpr::Choose(a, k) => {
+            if *k == 0 {
+                return Multinomial::value(1);
+            }
+            let expr_a = reduce(a.clone());
+            if *k == 1 {
+                return reduce_to_multinomial(expr_a);
+            }
+            let ProcExpr::Lit(a_) = expr_a.as_ref() else {
+                return Multinomial::symbol(Expr::new(ProcExpr::Choose(expr_a, *k)));
+            };
+            Multinomial::value(choose_u8(*a_, *k))
+        },
+        ProcExpr::If(c, a, b) => {
+            let expr_c = reduce(c.clone());
+            if let ProcExpr::Lit(c_) = expr_c.as_ref() {
+                return reduce_to_multinomial(if *c_ != 0 { a.clone() } else { b.clone() });
+            }
+            let expr_a = reduce(a.clone());
+            // the else branch is only taken when the condition is 0
+            let expr_b = reduce(match expr_c.as_ref() {
+                ProcExpr::Reg(r) => replace(& BTreeMap::from([(*r, Expr::new(ProcExpr::Lit(0)))]), b.clone()),
+                _ => b.clone()
+            });
+            if expr_a == expr_b {
+                return reduce_to_multinomial(expr_a);
+            }
+            if may_throw(expr_a.clone()) || may_throw(expr_b.clone()) {
+                return Multinomial::symbol(Expr::new(ProcExpr::If(expr_c, expr_a, expr_b)));
+            }
+            // neither side can throw, so both can be evaluated: a + (b - a) * (c == 0)
+            let multinomial_a = reduce_to_multinomial(expr_a);
+            let multinomial_b = reduce_to_multinomial(expr_b);
+            multinomial_a.add(
+                & multinomial_b
+                    .add(& Multinomial::value(255).mul(& multinomial_a))
+                    .mul(& reduce_to_multinomial(Expr::new(ProcExpr::IsZero(expr_c))))
+            )
+        },
+        ProcExpr::IsZero(a) => {
+            let expr_a = reduce(a.clone());
+            if let ProcExpr::Lit(a_) = expr_a.as_ref() {
+                return Multinomial::value((*a_ == 0) as u8);
+            }
+            // ((x == 0) == 0) = 1 - (x == 0)
+            if let ProcExpr::IsZero(_) = expr_a.as_ref() {
+                return Multinomial::value(1).add(& Multinomial::value(255).mul(& Multinomial::symbol(expr_a)));
+            }
+            Multinomial::symbol(Expr::new(ProcExpr::IsZero(expr_a)))
+        },
+    }
+}
+
+// whether a power of two always divides the other expression, so the division can't fail
+fn divides(
+    a: & Expr,
+    b: & Expr
+)
+    -> bool
+{
+    let ProcExpr::Lit(a_) = a.as_ref() else { return false; };
+    if *a_ == 0 || a_ & (a_ - 1) != 0 { return false; }
+    reduce_to_multinomial(b.clone()).coefficients.values().all(|coefficient| coefficient % a_ == 0)
+}
+
+// only Into can throw, when the loop it counts would never halt
+pub fn may_throw(
+    expr: Expr
+)
+    -> bool
+{
+    match expr.as_ref() {
+        ProcExpr::Lit(_) | ProcExpr::Reg(_) => false,
+        ProcExpr::Add(a, b) | ProcExpr::Mul(a, b) => may_throw(a.clone()) || may_throw(b.clone()),
+        ProcExpr::Into(a, b) => may_throw(b.clone()) || !divides(a, b),
+        ProcExpr::Choose(a, _) | ProcExpr::IsZero(a) => may_throw(a.clone()),
+        ProcExpr::If(c, a, b) => may_throw(c.clone()) || may_throw(a.clone()) || may_throw(b.clone()),
+    }
+}
+
+pub fn reduce(
+    expr: Expr
+)
+    -> Expr
+{
+    reduce_to_multinomial(expr)._cancel_divisions().as_val()
+}
+
+// a polynomial in the iteration count `k`, held in the binomial basis as `sum_j binomials[j] * (k choose j) + first * [k == 0]`
+// the binomial basis keeps every coefficient integral, so summing over `k` never needs a division mod 256
+// and the `[k == 0]` term covers registers that the loop overwrites, which only hold their old value before the first iteration
+#[derive(Debug, Clone, Default)]
+struct IterationPolynomial {
+    binomials: Vec<Multinomial>,
+    first: Multinomial
+}
+
+impl IterationPolynomial {
+    pub fn constant(x: Multinomial) -> IterationPolynomial {
+        IterationPolynomial{ binomials: vec![x], first: Multinomial::default() }
+    }
+
+    // `before` on the first iteration and `after` on every iteration following it
+    pub fn overwritten(before: Multinomial, after: Multinomial) -> IterationPolynomial {
+        IterationPolynomial{
+            first: before.add(& Multinomial::value(255).mul(& after)),
+            binomials: vec![after]
+        }
+    }
+
+    pub fn add(&self, other: &Self) -> IterationPolynomial {
+        let mut res = IterationPolynomial{
+            binomials: vec![Multinomial::default(); self.binomials.len().max(other.binomials.len())],
+            first: self.first.add(& other.first)
+        };
+        for (j, coefficient) in self.binomials.iter().enumerate() {
+            res.binomials[j] = res.binomials[j].add(coefficient);
+        }
+        for (j, coefficient) in other.binomials.iter().enumerate() {
+            res.binomials[j] = res.binomials[j].add(coefficient);
+        }
+        res
+    }
+
+    pub fn mul(&self, other: &Self) -> IterationPolynomial {
+        // (k choose a) * (k choose b) = sum_i ((a + b - i) choose i) * ((a + b - 2i) choose (a - i)) * (k choose (a + b - i))
+        // k never exceeds 255, so any (k choose j) with j > 255 is 0 and can be dropped
+        let mut res = IterationPolynomial{
+            binomials: vec![Multinomial::default(); (self.binomials.len() + other.binomials.len()).min(257).saturating_sub(1)],
+            first: Multinomial::default()
+        };
+        for (a, self_coefficient) in self.binomials.iter().enumerate() {
+            for (b, other_coefficient) in other.binomials.iter().enumerate() {
+                let product = self_coefficient.mul(other_coefficient);
+                for i in 0..=a.min(b) {
+                    let j = a + b - i;
+                    if j > 255 { continue; }
+                    let scale = choose_u8(j as u8, i as u8).wrapping_mul(choose_u8((j - i) as u8, (a - i) as u8));
+                    res.binomials[j] = res.binomials[j].add(& Multinomial::value(scale).mul(& product));
                 }
-                let ProcExpr::Lit(a_) = expr_a.as_ref() else {
-                    return Multinomial::symbol(Rc::new(ProcExpr::Into(expr_a, expr_b)));
-                };
-                let ProcExpr::Lit(b_) = expr_b.as_ref() else {
-                    return Multinomial::symbol(Rc::new(ProcExpr::Into(expr_a, expr_b)));
-                };
-                let Some(c) = div_u8(*b_, *a_) else {
-                    return Multinomial::symbol(Rc::new(ProcExpr::Into(expr_a, expr_b)));
-                };
-                Multinomial::symbol(Rc::new(ProcExpr::Lit(c)))
-            },
+            }
+        }
+        // [k == 0] * (k choose j) is [k == 0] when j == 0 and 0 otherwise
+        let self_constant = self.binomials.first().cloned().unwrap_or_default();
+        let other_constant = other.binomials.first().cloned().unwrap_or_default();
+        res.first = self.first.mul(& other.first)
+            .add(& self.first.mul(& other_constant))
+            .add(& other.first.mul(& self_constant));
+        res
+    }
+
+    // the running total `S(k) = sum_{i < k} self(i)`, as `sum_{i < k} (i choose j) = (k choose (j + 1))` and `sum_{i < k} [i == 0] = 1 - [k == 0]`
+    pub fn sum(&self) -> IterationPolynomial {
+        let mut res = IterationPolynomial{
+            binomials: vec![self.first.clone()],
+            first: Multinomial::value(255).mul(& self.first)
+        };
+        res.binomials.extend(self.binomials.iter().take(255).cloned());
+        res
+    }
+
+    pub fn at(&self, k: Expr, is_first: Expr) -> Expr {
+        let mut expr = Expr::new(ProcExpr::Lit(0));
+        for (j, coefficient) in self.binomials.iter().enumerate() {
+            if coefficient.coefficients.is_empty() { continue; }
+            let basis = match j {
+                0 => Expr::new(ProcExpr::Lit(1)),
+                1 => k.clone(),
+                _ => Expr::new(ProcExpr::Choose(k.clone(), j as u8))
+            };
+            expr = Expr::new(ProcExpr::Add(
+                expr,
+                Expr::new(ProcExpr::Mul(coefficient.as_val(), basis))
+            ));
+        }
+        if !self.first.coefficients.is_empty() {
+            expr = Expr::new(ProcExpr::Add(
+                expr,
+                Expr::new(ProcExpr::Mul(self.first.as_val(), is_first))
+            ));
+        }
+        expr
+    }
+}
+
+fn registers(
+    expr: Expr
+)
+    -> HashSet<i32>
+{
+    match expr.as_ref() {
+        ProcExpr::Lit(_) => HashSet::new(),
+        ProcExpr::Reg(r) => {
+            let mut set = HashSet::new();
+            set.insert(*r);
+            set
+        },
+        ProcExpr::Add(a, b) => registers(a.clone()).union(&registers(b.clone())).map(|r| r.clone()).collect(),
+        ProcExpr::Mul(a, b) => registers(a.clone()).union(&registers(b.clone())).map(|r| r.clone()).collect(),
+        ProcExpr::Into(a, b) => registers(a.clone()).union(&registers(b.clone())).map(|r| r.clone()).collect(),
+        ProcExpr::Choose(a, _) => registers(a.clone()),
+        ProcExpr::If(c, a, b) => {
+            let mut set = registers(c.clone());
+            set.extend(registers(a.clone()));
+            set.extend(registers(b.clone()));
+            set
+        },
+        ProcExpr::IsZero(a) => registers(a.clone()),
+    }
+}
+
+// why a loop couldn't be solved into a single atomic effect, registers are relative to the loop's counter
+#[derive(Debug, Clone, PartialEq)]
+pub enum LoopRejection {
+    Io(i32), // the body asks or puts this register
+    InnerLoop(i32), // the body still has a loop or if-block on this register
+    Moves(i32), // the body moves the head this far each time round
+    CounterUntouched, // ~#0 is never changed, so the loop either never runs or never ends
+    StepVaries(i32, Expr), // the amount taken off ~#0 each time round reads this register, which the loop changes
+    Unsolvable(i32, Expr) // this register's assignment has no closed form in the number of iterations
+}
+
+impl fmt::Display for LoopRejection {
+
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            LoopRejection::Io(register) => write!(f, "does I/O on ~#{register}"),
+            LoopRejection::InnerLoop(register) => write!(f, "has an unsolved loop on ~#{register}"),
+            LoopRejection::Moves(effect) => write!(f, "moves the head by {effect}"),
+            LoopRejection::CounterUntouched => write!(f, "never changes ~#0"),
+            LoopRejection::StepVaries(register, step) => write!(f, "takes {step} off ~#0, which depends on ~#{register}"),
+            LoopRejection::Unsolvable(register, expr) => write!(f, "has no closed form for ~#{register} = {expr}")
         }
     }
-    reduce_to_multinomial(expr).as_val()
 }
 
 fn try_loop_optimise(
-    b: & OptimisedBlock
+    bs: & Vec<OptimisedBlock>
 )
-    -> Option<OptimisedBlock>
+    -> Result<OptimisedBlock, LoopRejection>
 {
-    fn registers(
-        expr: Rc<ProcExpr>
+    // the amount added on iteration `k`, if everything it reads is either untouched by the loop or already solved
+    fn progression(
+        addition: & Multinomial,
+        lines: & BTreeMap<i32, Expr>,
+        progressions: & HashMap<i32, IterationPolynomial>
     )
-        -> HashSet<i32>
+        -> Option<IterationPolynomial>
     {
-        match expr.as_ref() {
-            ProcExpr::Lit(_) => HashSet::new(),
-            ProcExpr::Reg(r) => {
-                let mut set = HashSet::new();
-                set.insert(*r);
-                set
-            },
-            ProcExpr::Add(a, b) => registers(a.clone()).union(&registers(b.clone())).map(|r| r.clone()).collect(),
-            ProcExpr::Mul(a, b) => registers(a.clone()).union(&registers(b.clone())).map(|r| r.clone()).collect(),
-            ProcExpr::Into(a, b) => registers(a.clone()).union(&registers(b.clone())).map(|r| r.clone()).collect(),
+        let mut res = IterationPolynomial::default();
+        for (term, coefficient) in addition.coefficients.iter() {
+            let mut product = IterationPolynomial::constant(Multinomial::value(*coefficient));
+            for (symbol, power) in term {
+                let factor = match symbol.as_ref() {
+                    ProcExpr::Reg(r) if progressions.contains_key(r) => progressions.get(r).unwrap().clone(),
+                    // ~#0 is never 0 whilst the loop is running
+                    ProcExpr::IsZero(x) if x.as_ref() == &ProcExpr::Reg(0) => IterationPolynomial::default(),
+                    // a condition on something that only changes on the first iteration is known for every iteration after it
+                    ProcExpr::IsZero(x) => {
+                        let IterationPolynomial{ binomials, first } = progression(& reduce_to_multinomial(x.clone()), lines, progressions)?;
+                        if binomials.iter().skip(1).any(|coefficient| !coefficient.coefficients.is_empty()) { return None; }
+                        let after = binomials.into_iter().next().unwrap_or_default();
+                        let before = after.add(& first);
+                        IterationPolynomial::overwritten(
+                            reduce_to_multinomial(Expr::new(ProcExpr::IsZero(before.as_val()))),
+                            reduce_to_multinomial(Expr::new(ProcExpr::IsZero(after.as_val())))
+                        )
+                    },
+                    _ => {
+                        let rs = registers(symbol.clone());
+                        for register in lines.keys() {
+                            if rs.contains(register) { return None; }
+                        }
+                        IterationPolynomial::constant(Multinomial::symbol(symbol.clone()))
+                    }
+                };
+                for _ in 0..*power {
+                    product = product.mul(&factor);
+                }
+            }
+            res = res.add(&product);
         }
+        Some(res)
     }
 
-    match b {
+    if bs.len() != 1 {
+        // the head may have moved by the time a block is reached, and rejections are relative to ~#0
+        let mut offset = 0;
+        for b in bs {
+            match b {
+                OptimisedBlock::Ask(register) | OptimisedBlock::Put(register) => return Err(LoopRejection::Io(offset + register)),
+                OptimisedBlock::Loop(register, ..) | OptimisedBlock::If(register, _) => return Err(LoopRejection::InnerLoop(offset + register)),
+                OptimisedBlock::AtomicEffect(_, effect) => offset += effect
+            }
+        }
+        // an empty body never changes ~#0, and effects that haven't been grouped yet are solved as one
+        return match merge_all(bs.clone()).as_slice() {
+            [] => Err(LoopRejection::CounterUntouched),
+            merged => try_loop_optimise(& merged.to_vec())
+        };
+    }
+    match bs.first().unwrap() {
         OptimisedBlock::AtomicEffect(lines, 0) => {
-            let Some(index_expr) = lines.get(& 0) else { return None; };
+            let Some(index_expr) = lines.get(& 0) else { return Err(LoopRejection::CounterUntouched); };
             let subtraction = reduce(
-                Rc::new(ProcExpr::Add(
-                    Rc::new(ProcExpr::Reg(0)),
-                    Rc::new(ProcExpr::Mul(
-                        Rc::new(ProcExpr::Lit(255)),
+                Expr::new(ProcExpr::Add(
+                    Expr::new(ProcExpr::Reg(0)),
+                    Expr::new(ProcExpr::Mul(
+                        Expr::new(ProcExpr::Lit(255)),
                         index_expr.clone()
                     ))
                 ))
             );
 
-            let mut new_lines = HashMap::<i32, Rc<ProcExpr>>::new();
+            let mut new_lines = BTreeMap::<i32, Expr>::new();
 
-            let cycles = Rc::new(ProcExpr::Into(
+            let cycles = Expr::new(ProcExpr::Into(
                 subtraction.clone(),
-                Rc::new(ProcExpr::Reg(0))
+                Expr::new(ProcExpr::Reg(0))
             ));
             
-            let rs = registers(subtraction);
-            for register in lines.keys() {
-                if rs.contains(register) { return None; }
+            let rs = registers(subtraction.clone());
+            if let Some(register) = lines.keys().filter(|register| rs.contains(register)).min() {
+                return Err(LoopRejection::StepVaries(*register, subtraction));
             }
 
-            new_lines.insert(0, Rc::new(ProcExpr::Lit(0)));
+            new_lines.insert(0, Expr::new(ProcExpr::Lit(0)));
 
-            for (r, expr) in lines {
-                let addition = reduce(
-                    Rc::new(ProcExpr::Add(
-                        expr.clone(),
-                        Rc::new(ProcExpr::Mul(
-                            Rc::new(ProcExpr::Lit(255)),
-                            Rc::new(ProcExpr::Reg(*r))
+            // ~#0 on iteration k is ~#0 - k * subtraction
+            let mut progressions = HashMap::<i32, IterationPolynomial>::new();
+            progressions.insert(0, IterationPolynomial{
+                binomials: vec![
+                    Multinomial::symbol(Expr::new(ProcExpr::Reg(0))),
+                    Multinomial::value(255).mul(& reduce_to_multinomial(subtraction))
+                ],
+                first: Multinomial::default()
+            });
+
+            // the loop runs no times exactly when ~#0 starts at 0
+            let never_runs = Expr::new(ProcExpr::IsZero(
+                Expr::new(ProcExpr::Reg(0))
+            ));
+
+            // solve registers in dependency order, each one may only read registers solved before it
+            let mut unsolved: BTreeSet<i32> = lines.keys().filter(|r| **r != 0).cloned().collect();
+            while !unsolved.is_empty() {
+                let Some((r, values)) = unsolved.iter().find_map(|r| {
+                    let expr = lines.get(r).unwrap();
+                    let addition = reduce_to_multinomial(
+                        Expr::new(ProcExpr::Add(
+                            expr.clone(),
+                            Expr::new(ProcExpr::Mul(
+                                Expr::new(ProcExpr::Lit(255)),
+                                Expr::new(ProcExpr::Reg(*r))
+                            ))
                         ))
-                    ))
-                );
-                if *r == 0 { continue }
-                let rs = registers(addition.clone());
-                for register in lines.keys() {
-                    if rs.contains(register) { return None; }
-                }
+                    );
+                    if let Some(per_iteration) = progression(& addition, lines, & progressions) {
+                        let start = IterationPolynomial::constant(Multinomial::symbol(Expr::new(ProcExpr::Reg(*r))));
+                        return Some((*r, start.add(& per_iteration.sum())));
+                    }
+                    let assignment = reduce_to_multinomial(expr.clone());
+                    if let Some(IterationPolynomial{ binomials, .. }) = progression(& assignment, lines, & HashMap::new()) {
+                        return Some((*r, IterationPolynomial::overwritten(
+                            Multinomial::symbol(Expr::new(ProcExpr::Reg(*r))),
+                            binomials.into_iter().next().unwrap_or_default()
+                        )));
+                    }
+                    None
+                }) else {
+                    let r = *unsolved.iter().min().unwrap();
+                    return Err(LoopRejection::Unsolvable(r, lines.get(& r).unwrap().clone()));
+                };
+                unsolved.remove(& r);
 
-                new_lines.insert(*r, Rc::new(ProcExpr::Add(
-                    Rc::new(ProcExpr::Reg(*r)),
-                    Rc::new(ProcExpr::Mul(
-                        cycles.clone(),
-                        addition
-                    ))
-                )));
+                new_lines.insert(r, values.at(cycles.clone(), never_runs.clone()));
+                progressions.insert(r, values);
             }
-            Some(OptimisedBlock::AtomicEffect(new_lines, 0))
-        }
-        _ => None
+            Ok(OptimisedBlock::AtomicEffect(new_lines, 0))
+        },
+        OptimisedBlock::AtomicEffect(_, effect) => Err(LoopRejection::Moves(*effect)),
+        OptimisedBlock::Ask(register) | OptimisedBlock::Put(register) => Err(LoopRejection::Io(*register)),
+        OptimisedBlock::Loop(register, ..) | OptimisedBlock::If(register, _) => Err(LoopRejection::InnerLoop(*register))
     }
 }
 
-fn merge_all(
+fn shift_blocks(
+    s: i32,
+    bs: & Vec<OptimisedBlock>
+)
+    -> Vec<OptimisedBlock>
+{
+    bs.iter().map(|b| match b {
+        OptimisedBlock::Ask(register) => OptimisedBlock::Ask(register + s),
+        OptimisedBlock::Put(register) => OptimisedBlock::Put(register + s),
+        OptimisedBlock::AtomicEffect(lines, effect) => OptimisedBlock::AtomicEffect(
+            lines.iter().map(|(r, expr)| (r + s, shift(s, expr.clone()))).collect(),
+            *effect
+        ),
+        OptimisedBlock::Loop(register, bs_, rejection) => OptimisedBlock::Loop(register + s, shift_blocks(s, bs_), rejection.clone()),
+        OptimisedBlock::If(register, bs_) => OptimisedBlock::If(register + s, shift_blocks(s, bs_))
+    }).collect()
+}
+
+// why a loop on the register this far from the head wasn't solved, or None if it could be
+pub fn explain_loop(
+    register: i32,
+    bs: & Vec<OptimisedBlock>
+)
+    -> Option<LoopRejection>
+{
+    try_loop_optimise(& shift_blocks(-register, bs)).err()
+}
+
+pub fn merge_all(
     bs: Vec<OptimisedBlock>
 )
     -> Vec<OptimisedBlock>
@@ -393,46 +1060,289 @@ fn merge_all(
     merge_all(from_c)
 }
 
-fn reduce_all(
-    bs: Vec<OptimisedBlock>
+pub fn reduce_all(
+    bs: Vec<OptimisedBlock>,
+    stats: &mut Statistics
 )
     ->  Vec<OptimisedBlock>
 {
     bs.into_iter().map(|b| match b {
         OptimisedBlock::AtomicEffect(lines, i) => OptimisedBlock::AtomicEffect(
-            lines.into_iter().map(|(r, expr)| (r, reduce(expr))).collect(),
+            lines.into_iter().map(|(r, expr)| {
+                let reduced = reduce(expr.clone());
+                if reduced != expr { stats.expressions_reduced += 1; }
+                (r, reduced)
+            }).collect(),
             i
         ),
         _ => b
     }).collect()
 }
 
-fn optimise_loops(
-    bs: Vec<OptimisedBlock>
+// a loop whose body always leaves the head on a zero cell can only ever run once
+fn is_if(
+    bs: & Vec<OptimisedBlock>
 )
-    ->  Vec<OptimisedBlock>
+    -> bool
 {
-    bs.into_iter().map(|b| match b {
-        OptimisedBlock::Loop(bs_) => {
-            let optimised_bs_ = optimise(bs_);
-            if optimised_bs_.len() == 1 {
-                try_loop_optimise(optimised_bs_.first().unwrap()).unwrap_or(OptimisedBlock::Loop(optimised_bs_))
+    match bs.last() {
+        Some(OptimisedBlock::Loop(0, ..)) | Some(OptimisedBlock::If(0, _)) => true,
+        Some(OptimisedBlock::AtomicEffect(lines, offset)) => lines.get(offset).is_some_and(|expr| expr.as_ref() == &ProcExpr::Lit(0)),
+        _ => false
+    }
+}
+
+fn try_if_optimise(
+    bs: & Vec<OptimisedBlock>
+)
+    -> Option<OptimisedBlock>
+{
+    let [OptimisedBlock::AtomicEffect(lines, 0)] = bs.as_slice() else { return None; };
+    Some(OptimisedBlock::AtomicEffect(
+        lines.iter().map(|(r, expr)| (
+            *r,
+            Expr::new(ProcExpr::If(
+                Expr::new(ProcExpr::Reg(0)),
+                expr.clone(),
+                Expr::new(ProcExpr::Reg(*r))
+            ))
+        )).collect(),
+        0
+    ))
+}
+
+// the net movement of the head over some bytecode, if it is known
+fn net_move(
+    bs: & [OptimisedBlock]
+)
+    -> Option<i32>
+{
+    let mut offset = 0;
+    for b in bs {
+        match b {
+            OptimisedBlock::Ask(_) | OptimisedBlock::Put(_) => (),
+            OptimisedBlock::AtomicEffect(_, i) => offset += i,
+            OptimisedBlock::Loop(_, bs_, _) | OptimisedBlock::If(_, bs_) => if net_move(bs_)? != 0 { return None; }
+        }
+    }
+    Some(offset)
+}
+
+// the cells some balanced bytecode may read from and write to, relative to where the head was before it
+fn accesses(
+    bs: & [OptimisedBlock],
+    mut offset: i32,
+    reads: &mut HashSet<i32>,
+    writes: &mut HashSet<i32>
+)
+    -> ()
+{
+    for b in bs {
+        match b {
+            OptimisedBlock::Ask(register) => { writes.insert(offset + register); },
+            OptimisedBlock::Put(register) => { reads.insert(offset + register); },
+            OptimisedBlock::AtomicEffect(lines, i) => {
+                for (r, expr) in lines {
+                    writes.insert(offset + r);
+                    reads.extend(registers(expr.clone()).into_iter().map(|x| x + offset));
+                }
+                offset += i;
+            },
+            OptimisedBlock::Loop(register, bs_, _) | OptimisedBlock::If(register, bs_) => {
+                reads.insert(offset + register);
+                accesses(bs_, offset, reads, writes);
+            }
+        }
+    }
+}
+
+// moves the assignments in a balanced loop that are the same on every iteration to before it,
+// and the ones nothing in the loop reads back to after it,
+// guarding the loop with an if-block so that neither happens when the loop doesn't run
+// what is left is still a loop, and still not solved for the same reason
+fn try_loop_hoist(
+    bs: & Vec<OptimisedBlock>,
+    rejection: & LoopRejection,
+    stats: &mut Statistics
+)
+    -> Option<OptimisedBlock>
+{
+    if net_move(bs)? != 0 { return None; }
+
+    let mut offsets = vec![];
+    let mut block_reads = vec![];
+    let mut block_writes = vec![];
+    let mut offset = 0;
+    for b in bs {
+        let mut reads = HashSet::new();
+        let mut writes = HashSet::new();
+        accesses(std::slice::from_ref(b), offset, &mut reads, &mut writes);
+        offsets.push(offset);
+        block_reads.push(reads);
+        block_writes.push(writes);
+        if let OptimisedBlock::AtomicEffect(_, i) = b { offset += i; }
+    }
+    let mut write_counts = HashMap::<i32, usize>::new();
+    for writes in block_writes.iter() {
+        for p in writes {
+            write_counts.insert(*p, write_counts.get(p).unwrap_or(&0) + 1);
+        }
+    }
+
+    let mut new_bs = bs.clone();
+    let mut hoisted = BTreeMap::<i32, Expr>::new();
+    let mut sunk = vec![];
+    for (i, b) in bs.iter().enumerate() {
+        let OptimisedBlock::AtomicEffect(lines, _) = b else { continue; };
+        for (r, expr) in lines {
+            let p = offsets[i] + r;
+            // ~#0 is read by the loop itself
+            if p == 0 || write_counts.get(& p) != Some(& 1) || may_throw(expr.clone()) { continue; }
+            let rs: HashSet<i32> = registers(expr.clone()).into_iter().map(|x| x + offsets[i]).collect();
+            let read_before = block_reads[..=i].iter().any(|reads| reads.contains(& p));
+            let read_after = block_reads[i + 1..].iter().any(|reads| reads.contains(& p));
+            if !read_before && rs.iter().all(|x| !write_counts.contains_key(x)) {
+                hoisted.insert(p, shift(offsets[i], expr.clone()));
+            } else if !read_before && !read_after && rs.iter().all(|x| block_writes[i..].iter().all(|writes| !writes.contains(x))) {
+                sunk.push(OptimisedBlock::AtomicEffect(BTreeMap::from([(p, shift(offsets[i], expr.clone()))]), 0));
             } else {
-                OptimisedBlock::Loop(optimised_bs_)
+                continue;
             }
+            if let OptimisedBlock::AtomicEffect(new_lines, _) = &mut new_bs[i] {
+                new_lines.remove(r);
+            }
+        }
+    }
+    if hoisted.is_empty() && sunk.is_empty() { return None; }
+
+    let mut guarded = vec![];
+    if !hoisted.is_empty() {
+        guarded.push(OptimisedBlock::AtomicEffect(hoisted, 0));
+    }
+    guarded.push(OptimisedBlock::Loop(
+        0,
+        new_bs.into_iter().filter(|b| !matches!(b, OptimisedBlock::AtomicEffect(lines, 0) if lines.is_empty())).collect(),
+        Some(rejection.clone())
+    ));
+    guarded.extend(reduce_all(merge_all(sunk), stats));
+    Some(OptimisedBlock::If(0, guarded))
+}
+
+// solves a loop on the head, or failing that makes it an if-block or hoists what it can out of it, given its body already optimised
+pub fn optimise_loop(
+    optimised_bs: Vec<OptimisedBlock>,
+    stats: &mut Statistics
+)
+    -> OptimisedBlock
+{
+    let rejection = match try_loop_optimise(& optimised_bs) {
+        Ok(b) => {
+            stats.loops_solved += 1;
+            return b;
+        },
+        Err(rejection) => rejection
+    };
+    if is_if(& optimised_bs) {
+        stats.loops_to_ifs += 1;
+        try_if_optimise(& optimised_bs).unwrap_or(OptimisedBlock::If(0, optimised_bs))
+    } else {
+        stats.rejections.push(rejection.clone());
+        try_loop_hoist(& optimised_bs, & rejection, stats).unwrap_or(OptimisedBlock::Loop(0, optimised_bs, Some(rejection)))
+    }
+}
+
+pub fn optimise_loops(
+    bs: Vec<OptimisedBlock>,
+    stats: &mut Statistics
+)
+    ->  Vec<OptimisedBlock>
+{
+    bs.into_iter().map(|b| match b {
+        OptimisedBlock::Loop(0, bs_, _) => optimise_loop(optimise(bs_, stats), stats),
+        // a loop normalising offsets has moved off the head is solved as if it were on it, then moved back
+        OptimisedBlock::Loop(register, bs_, _) => {
+            let solved = optimise_loop(shift_blocks(-register, & optimise(bs_, stats)), stats);
+            shift_blocks(register, & vec![solved]).remove(0)
         },
+        OptimisedBlock::If(register, bs_) => OptimisedBlock::If(register, optimise(bs_, stats)),
         _ => b
     }).collect()
 }
 
+fn flush_offset(
+    bs: &mut Vec<OptimisedBlock>,
+    offset: i32
+)
+    -> ()
+{
+    if offset == 0 { return; }
+    match bs.last_mut() {
+        Some(OptimisedBlock::AtomicEffect(_, i)) if *i == 0 => *i = offset,
+        _ => bs.push(OptimisedBlock::AtomicEffect(BTreeMap::new(), offset))
+    }
+}
+
+// applies moves lazily, with every block after a move referencing registers relative to where the head would have been
+// the head only has to really move before a loop that doesn't return it to where it started
+fn normalise_offsets_from(
+    bs: Vec<OptimisedBlock>,
+    pending: &mut i32
+)
+    -> Vec<OptimisedBlock>
+{
+    let mut res = vec![];
+    for b in bs {
+        match b {
+            OptimisedBlock::Ask(register) => res.push(OptimisedBlock::Ask(register + *pending)),
+            OptimisedBlock::Put(register) => res.push(OptimisedBlock::Put(register + *pending)),
+            OptimisedBlock::AtomicEffect(lines, i) => {
+                if !lines.is_empty() {
+                    res.push(OptimisedBlock::AtomicEffect(
+                        lines.into_iter().map(|(r, expr)| (r + *pending, shift(*pending, expr))).collect(),
+                        0
+                    ));
+                }
+                *pending += i;
+            },
+            OptimisedBlock::Loop(register, bs_, rejection) => if net_move(& bs_) == Some(0) {
+                res.push(OptimisedBlock::Loop(register + *pending, normalise_offsets_from(bs_, &mut pending.clone()), rejection));
+            } else {
+                flush_offset(&mut res, *pending);
+                *pending = 0;
+                res.push(OptimisedBlock::Loop(register, normalise_offsets(bs_), rejection));
+            },
+            OptimisedBlock::If(register, bs_) => if net_move(& bs_) == Some(0) {
+                res.push(OptimisedBlock::If(register + *pending, normalise_offsets_from(bs_, &mut pending.clone())));
+            } else {
+                flush_offset(&mut res, *pending);
+                *pending = 0;
+                res.push(OptimisedBlock::If(register, normalise_offsets(bs_)));
+            },
+        }
+    }
+    res
+}
+
+pub fn normalise_offsets(
+    bs: Vec<OptimisedBlock>
+)
+    -> Vec<OptimisedBlock>
+{
+    let mut pending = 0;
+    let mut res = normalise_offsets_from(bs, &mut pending);
+    flush_offset(&mut res, pending);
+    res
+}
+
 fn optimise(
-    mut bs: Vec<OptimisedBlock>
+    mut bs: Vec<OptimisedBlock>,
+    stats: &mut Statistics
 )
     ->  Vec<OptimisedBlock>
 {
-    bs = optimise_loops(bs);
+    bs = optimise_loops(bs, stats);
     bs = merge_all(bs);
-    bs = reduce_all(bs);
+    bs = reduce_all(bs, stats);
     bs
 }
 
@@ -441,5 +1351,5 @@ pub fn optimising_convert(
 )
     -> Vec<OptimisedBlock>
 {
-    optimise(convert(raw))
+    PassManager::with_level(2).run(convert(raw))
 }
\ No newline at end of file
@@ -0,0 +1,362 @@
+use nibbler::{ parser, builders::*, errors::*, combinators::*, alternative };
+
+use crate::parser::{ TextIter, TextInfo };
+
+use super::{ *, optimiser::LoopRejection };
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use super::super::optimiser::{ *, tests::* };
+    use super::super::passes::PassManager;
+
+    fn try_parse_str(s: &str) -> Result<Vec<OptimisedBlock>, ParseError<TextInfo>> {
+        parse_byte_code()(&mut TextIter{ iter: s.chars(), line: 0, index: 0 })
+    }
+
+    fn parse(s: &str) -> Vec<OptimisedBlock> {
+        try_parse_str(s).unwrap_or_else(|err| panic!("{}", show_error("".to_string(), & |info: TextInfo| format!("at {}:{}", info.line, info.index), err)))
+    }
+
+    #[test]
+    fn round_trip() {
+        for s in [
+            "[->+>++<<]",
+            ",[.>[-]++++++++++.<,]",
+            "[-->+>+<<],[->+<]>>>[-]<<<",
+            "[->[->+<]<]",
+            ">+<[>-<[-]]>",
+            "[>+[<->-]<]",
+            ">>[->[.-]<]<<<.",
+            "[--->+>++<<]>>[-<+>]<<,[->+<]",
+            "[->[->++<]>[-<+>]<<]",
+            ",[.,]"
+        ] {
+            // loops below -O2 aren't annotated, and must stay that way
+            for level in 0..=2 {
+                let bs = PassManager::with_level(level).run(convert(raw(s)));
+                let pretty = byte_code_pretty(& bs);
+                let parsed = parse(& pretty);
+                assert_eq!(byte_code_pretty(& parsed), pretty, "{s} didn't round-trip at -O{level}");
+                for x in [0, 2, 6] {
+                    assert_eq!(run_optimised(parsed.clone(), &[(0, x), (1, 3)], b"ab"), run_optimised(bs.clone(), &[(0, x), (1, 3)], b"ab"), "{s} diverged at -O{level}");
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn hand_written() {
+        let bs = parse("
+            ask ~#-1
+            block {
+                $0 = (3 into ~#0);
+                ~#1 = ($0 + 2 * $0 * ~#2);
+                ~#2 = ((~#1 == 0) ? 1 : (~#2 choose 2));
+            } (move -2)
+            loop ~#1 [ // a comment
+                put
+            ]
+            loop [ // not solved: takes (~#1 + 2 * ~#2) off ~#0, which depends on ~#1
+                block { ~#1 = (~#1 + 1); } (move 0)
+            ]
+            if [ block {} (move 1) ]
+        ");
+        assert_eq!(byte_code_pretty(& bs), [
+            "ask ~#-1",
+            "block {",
+            "\t$0 = (3 into ~#0);",
+            "\t~#1 = ($0 + 2 * $0 * ~#2);",
+            "\t~#2 = ((~#1 == 0) ? 1 : (~#2 choose 2));",
+            "} (move -2)",
+            "loop ~#1 [",
+            "\tput",
+            "]",
+            "loop [ // not solved: takes (~#1 + 2 * ~#2) off ~#0, which depends on ~#1",
+            "\tblock {",
+            "\t\t~#1 = (~#1 + 1);",
+            "\t} (move 0)",
+            "]",
+            "if [",
+            "\tblock {} (move 1)",
+            "]"
+        ].join("\n"));
+
+        assert!(try_parse_str("block { ~#0 = $0; } (move 0)").is_err());
+        assert!(try_parse_str("loop [ put").is_err());
+        assert!(try_parse_str("block { ~#0 = (1 + ); } (move 0)").is_err());
+    }
+}
+
+macro_rules! msg {
+    ($s:expr) => (
+        |iter_: & TextIter<Iter>| ParseError::Message(($s).to_string(), TextInfo{ line: iter_.line, index: iter_.index })
+    );
+}
+
+// whitespace and `//` comments, which run to the end of the line
+pub const fn parse_space<Iter: Iterator<Item = char> + Clone>()
+    -> parser![TextIter<Iter>, ParseError<TextInfo>, ()]
+{
+    |iter| {
+        loop {
+            if try_parse(predicate::<TextIter<Iter>, ParseError<TextInfo>, 1>(|s| s[0].is_whitespace(), |_| ParseError::Silent))(iter).is_ok() {
+                continue;
+            }
+            if try_parse(expect::<TextIter<Iter>, ParseError<TextInfo>, 2>(['/', '/'], msg!("'//'")))(iter).is_ok() {
+                while try_parse(predicate::<TextIter<Iter>, ParseError<TextInfo>, 1>(|s| s[0] != '\n', |_| ParseError::Silent))(iter).is_ok() {}
+                continue;
+            }
+            return Ok(());
+        }
+    }
+}
+
+pub const fn parse_number<Iter: Iterator<Item = char> + Clone>()
+    -> parser![TextIter<Iter>, ParseError<TextInfo>, i32]
+{
+    |iter| {
+        parse_space()(iter)?;
+        let err = msg!("number")(iter);
+        let mut digits = String::new();
+        if try_parse(expect::<TextIter<Iter>, ParseError<TextInfo>, 1>(['-'], msg!("'-'")))(iter).is_ok() {
+            digits.push('-');
+        }
+        while let Ok([c]) = try_parse(predicate::<TextIter<Iter>, ParseError<TextInfo>, 1>(|s| s[0].is_ascii_digit(), |_| ParseError::Silent))(iter) {
+            digits.push(c);
+        }
+        str::parse::<i32>(digits.as_str()).map_err(|_| err)
+    }
+}
+
+pub const fn parse_literal<Iter: Iterator<Item = char> + Clone>()
+    -> parser![TextIter<Iter>, ParseError<TextInfo>, u8]
+{
+    |iter| {
+        parse_space()(iter)?;
+        let err = msg!("number from 0 to 255")(iter);
+        u8::try_from(parse_number()(iter)?).map_err(|_| err)
+    }
+}
+
+pub const fn parse_register<Iter: Iterator<Item = char> + Clone>()
+    -> parser![TextIter<Iter>, ParseError<TextInfo>, i32]
+{
+    |iter| {
+        parse_space()(iter)?;
+        expect::<TextIter<Iter>, ParseError<TextInfo>, 2>(['~', '#'], msg!("register"))(iter)?;
+        parse_number()(iter)
+    }
+}
+
+pub const fn parse_temporary<Iter: Iterator<Item = char> + Clone>()
+    -> parser![TextIter<Iter>, ParseError<TextInfo>, usize]
+{
+    |iter| {
+        parse_space()(iter)?;
+        expect::<TextIter<Iter>, ParseError<TextInfo>, 1>(['$'], msg!("temporary"))(iter)?;
+        let err = msg!("temporary number")(iter);
+        usize::try_from(parse_number()(iter)?).map_err(|_| err)
+    }
+}
+
+// a token after any whitespace
+macro_rules! token {
+    ($n:literal, $cs:expr, $s:expr) => (
+        |iter: &mut TextIter<Iter>| {
+            parse_space()(iter)?;
+            expect::<TextIter<Iter>, ParseError<TextInfo>, $n>($cs, msg!($s))(iter)
+        }
+    );
+}
+
+// a product of factors, as `Mul` is written without brackets
+fn parse_expr<Iter: Iterator<Item = char> + Clone>(
+    iter: &mut TextIter<Iter>,
+    temporaries: & Vec<Expr>
+)
+    -> Result<Expr, ParseError<TextInfo>>
+{
+    let mut expr = parse_factor(iter, temporaries)?;
+    while try_parse(token!(1, ['*'], "'*'"))(iter).is_ok() {
+        expr = Expr::new(ProcExpr::Mul(expr, parse_factor(iter, temporaries)?));
+    }
+    Ok(expr)
+}
+
+fn parse_factor<Iter: Iterator<Item = char> + Clone>(
+    iter: &mut TextIter<Iter>,
+    temporaries: & Vec<Expr>
+)
+    -> Result<Expr, ParseError<TextInfo>>
+{
+    if let Ok(x) = try_parse(parse_literal())(iter) {
+        return Ok(Expr::new(ProcExpr::Lit(x)));
+    }
+    if let Ok(r) = try_parse(parse_register())(iter) {
+        return Ok(Expr::new(ProcExpr::Reg(r)));
+    }
+    parse_space()(iter)?;
+    let err = msg!("defined temporary")(iter);
+    if let Ok(i) = try_parse(parse_temporary())(iter) {
+        return temporaries.get(i).cloned().ok_or(err);
+    }
+    token!(1, ['('], "'('")(iter).map_err(|_| msg!("expression")(iter))?;
+    let a = parse_expr(iter, temporaries)?;
+    let expr = if try_parse(token!(1, ['+'], "'+'"))(iter).is_ok() {
+        ProcExpr::Add(a, parse_expr(iter, temporaries)?)
+    } else if try_parse(token!(4, ['i', 'n', 't', 'o'], "'into'"))(iter).is_ok() {
+        ProcExpr::Into(a, parse_expr(iter, temporaries)?)
+    } else if try_parse(token!(6, ['c', 'h', 'o', 'o', 's', 'e'], "'choose'"))(iter).is_ok() {
+        ProcExpr::Choose(a, parse_literal()(iter)?)
+    } else if try_parse(token!(1, ['?'], "'?'"))(iter).is_ok() {
+        let b = parse_expr(iter, temporaries)?;
+        token!(1, [':'], "':'")(iter)?;
+        ProcExpr::If(a, b, parse_expr(iter, temporaries)?)
+    } else {
+        token!(2, ['=', '='], "'=='")(iter).map_err(|_| msg!("'+', 'into', 'choose', '?' or '=='")(iter))?;
+        token!(1, ['0'], "'0'")(iter)?;
+        ProcExpr::IsZero(a)
+    };
+    token!(1, [')'], "')'")(iter)?;
+    Ok(Expr::new(expr))
+}
+
+// words separated by whitespace, each one exactly as given
+fn parse_words<Iter: Iterator<Item = char> + Clone>(
+    iter: &mut TextIter<Iter>,
+    words: & str
+)
+    -> Result<(), ParseError<TextInfo>>
+{
+    for word in words.split(' ') {
+        parse_space()(iter)?;
+        for c in word.chars() {
+            expect::<TextIter<Iter>, ParseError<TextInfo>, 1>([c], msg!(format!("'{word}'")))(iter)?;
+        }
+    }
+    Ok(())
+}
+
+// the `// not solved: ...` annotation printed on the same line as a loop's '[', any other comment being skipped as usual
+fn parse_rejection<Iter: Iterator<Item = char> + Clone>(
+    iter: &mut TextIter<Iter>
+)
+    -> Result<LoopRejection, ParseError<TextInfo>>
+{
+    while try_parse(predicate::<TextIter<Iter>, ParseError<TextInfo>, 1>(|s| s[0] == ' ' || s[0] == '\t', |_| ParseError::Silent))(iter).is_ok() {}
+    expect::<TextIter<Iter>, ParseError<TextInfo>, 2>(['/', '/'], msg!("'//'"))(iter)?;
+    parse_words(iter, "not solved:")?;
+    if try_parse(|iter_: &mut TextIter<Iter>| parse_words(iter_, "does I/O on"))(iter).is_ok() {
+        return Ok(LoopRejection::Io(parse_register()(iter)?));
+    }
+    if try_parse(|iter_: &mut TextIter<Iter>| parse_words(iter_, "has an unsolved loop on"))(iter).is_ok() {
+        return Ok(LoopRejection::InnerLoop(parse_register()(iter)?));
+    }
+    if try_parse(|iter_: &mut TextIter<Iter>| parse_words(iter_, "moves the head by"))(iter).is_ok() {
+        return Ok(LoopRejection::Moves(parse_number()(iter)?));
+    }
+    if try_parse(|iter_: &mut TextIter<Iter>| parse_words(iter_, "never changes ~#0"))(iter).is_ok() {
+        return Ok(LoopRejection::CounterUntouched);
+    }
+    if try_parse(|iter_: &mut TextIter<Iter>| parse_words(iter_, "takes"))(iter).is_ok() {
+        let step = parse_expr(iter, & vec![])?;
+        parse_words(iter, "off ~#0, which depends on")?;
+        return Ok(LoopRejection::StepVaries(parse_register()(iter)?, step));
+    }
+    parse_words(iter, "has no closed form for")?;
+    let register = parse_register()(iter)?;
+    token!(1, ['='], "'='")(iter)?;
+    Ok(LoopRejection::Unsolvable(register, parse_expr(iter, & vec![])?))
+}
+
+pub const fn parse_effect<Iter: Iterator<Item = char> + Clone>()
+    -> parser![TextIter<Iter>, ParseError<TextInfo>, OptimisedBlock]
+{
+    |iter| {
+        token!(5, ['b', 'l', 'o', 'c', 'k'], "'block'")(iter)?;
+        token!(1, ['{'], "'{'")(iter)?;
+        let mut temporaries = vec![];
+        let mut lines = BTreeMap::new();
+        while try_parse(token!(1, ['}'], "'}'"))(iter).is_err() {
+            parse_space()(iter)?;
+            let err = msg!("temporaries in order")(iter);
+            if let Ok(i) = try_parse(parse_temporary())(iter) {
+                if i != temporaries.len() { return Err(err); }
+                token!(1, ['='], "'='")(iter)?;
+                temporaries.push(parse_expr(iter, & temporaries)?);
+            } else {
+                let register = parse_register()(iter).map_err(|_| msg!("register, temporary or '}'")(iter))?;
+                token!(1, ['='], "'='")(iter)?;
+                lines.insert(register, parse_expr(iter, & temporaries)?);
+            }
+            token!(1, [';'], "';'")(iter)?;
+        }
+        token!(1, ['('], "'('")(iter)?;
+        token!(4, ['m', 'o', 'v', 'e'], "'move'")(iter)?;
+        let effect = parse_number()(iter)?;
+        token!(1, [')'], "')'")(iter)?;
+        Ok(OptimisedBlock::AtomicEffect(lines, effect))
+    }
+}
+
+// the blocks up to the closing ']' of a loop or if-block
+pub const fn parse_body<Iter: Iterator<Item = char> + Clone>()
+    -> parser![TextIter<Iter>, ParseError<TextInfo>, Vec<OptimisedBlock>]
+{
+    |iter| {
+        let mut bs = vec![];
+        while try_parse(token!(1, [']'], "']'"))(iter).is_err() {
+            bs.push(parse_block()(iter)?);
+        }
+        Ok(bs)
+    }
+}
+
+pub const fn parse_block<Iter: Iterator<Item = char> + Clone>()
+    -> parser![TextIter<Iter>, ParseError<TextInfo>, OptimisedBlock]
+{
+    |iter| display_fst_nonsilent(
+        alternative!(
+            try_parse(|iter_: &mut TextIter<Iter>| {
+                token!(3, ['a', 's', 'k'], "'ask'")(iter_)?;
+                Ok(OptimisedBlock::Ask(try_parse(parse_register())(iter_).unwrap_or(0)))
+            }),
+            try_parse(|iter_: &mut TextIter<Iter>| {
+                token!(3, ['p', 'u', 't'], "'put'")(iter_)?;
+                Ok(OptimisedBlock::Put(try_parse(parse_register())(iter_).unwrap_or(0)))
+            }),
+            try_parse(parse_effect()),
+            try_parse(|iter_: &mut TextIter<Iter>| {
+                token!(4, ['l', 'o', 'o', 'p'], "'loop'")(iter_)?;
+                let register = try_parse(parse_register())(iter_).unwrap_or(0);
+                token!(1, ['['], "'['")(iter_)?;
+                let rejection = try_parse(parse_rejection)(iter_).ok();
+                Ok(OptimisedBlock::Loop(register, parse_body()(iter_)?, rejection))
+            }),
+            try_parse(|iter_: &mut TextIter<Iter>| {
+                token!(2, ['i', 'f'], "'if'")(iter_)?;
+                let register = try_parse(parse_register())(iter_).unwrap_or(0);
+                token!(1, ['['], "'['")(iter_)?;
+                Ok(OptimisedBlock::If(register, parse_body()(iter_)?))
+            }),
+            fail(msg!("block (e.g. 'ask', 'put', 'block', 'loop' or 'if')"))
+        )
+    )(iter)
+}
+
+// reads back what `byte_code_pretty` prints
+pub const fn parse_byte_code<Iter: Iterator<Item = char> + Clone>()
+    -> parser![TextIter<Iter>, ParseError<TextInfo>, Vec<OptimisedBlock>]
+{
+    |iter| {
+        let mut bs = vec![];
+        loop {
+            parse_space()(iter)?;
+            if try_parse(eos::<TextIter<Iter>, ParseError<TextInfo>>(msg!("end of stream")))(iter).is_ok() {
+                return Ok(bs);
+            }
+            bs.push(parse_block()(iter)?);
+        }
+    }
+}
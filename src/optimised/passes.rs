@@ -0,0 +1,224 @@
+use crate::*;
+use super::{ *, optimiser::*, statistics::* };
+
+#[cfg(test)]
+mod tests {
+    use std::cell::Cell;
+
+    use super::*;
+    use super::super::optimiser::tests::*;
+
+    #[test]
+    fn levels_agree() {
+        for s in [
+            "[>+[>+<-]<-]",
+            ",[.>[-]++++++++++.<,]",
+            ">,[>.<-]>>>.<<[>.<-]",
+            "++++[>+++<-]>[<+>>[-]<-]<."
+        ] {
+            let reference = run_both(s, &[(0, 2)], b"ab").0;
+            for level in 0..=2 {
+                assert_eq!(
+                    run_optimised(PassManager::with_level(level).run(convert(raw(s))), &[(0, 2)], b"ab"),
+                    reference,
+                    "{s} diverged at -O{level}"
+                );
+            }
+        }
+        assert_eq!(PassManager::with_level(0).run(convert(raw("+>+<[-]"))), convert(raw("+>+<[-]")));
+    }
+
+    #[test]
+    fn custom_passes() {
+        struct Counting<'a>(&'a Cell<usize>);
+
+        impl<'a> Pass for Counting<'a> {
+            fn name(&self) -> String { "counting".to_string() }
+
+            fn run(&self, mut bs: Vec<OptimisedBlock>, _stats: &mut Statistics) -> Vec<OptimisedBlock> {
+                self.0.set(self.0.get() + 1);
+                // keeps changing the program until it has run 3 times
+                if self.0.get() < 3 {
+                    bs.push(OptimisedBlock::Put(0));
+                }
+                bs
+            }
+        }
+
+        let runs = Cell::new(0);
+        let manager = PassManager::new().with(Merge).with(Counting(& runs)).to_fixpoint();
+        assert_eq!(manager.names(), vec!["merge".to_string(), "counting".to_string()]);
+        let bs = manager.run(convert(raw("+>+")));
+        // the 3rd run doesn't change anything, and the 4th confirms it
+        assert_eq!(runs.get(), 3);
+        assert_eq!(bs.len(), 3);
+
+        // passes keep the order they are added in, and loops moved off the head are still solved
+        let manager = PassManager::default().with(NormaliseOffsets).with(SolveLoops).with(Merge);
+        assert_eq!(manager.names(), vec!["offsets".to_string(), "loops".to_string(), "merge".to_string()]);
+        let bs = manager.run(convert(raw(">[-]<")));
+        assert!(bs.iter().all(|b| !matches!(b, OptimisedBlock::Loop(..))));
+
+        assert!(pass_by_name("loops").is_some());
+        assert!(pass_by_name("nope").is_none());
+    }
+}
+
+// an optimisation over the whole bytecode
+pub trait Pass {
+    fn name(&self) -> String;
+
+    fn run(&self, bs: Vec<OptimisedBlock>, stats: &mut Statistics) -> Vec<OptimisedBlock>;
+}
+
+// applies a pass to the body of every loop and if-block, innermost first, and then to the bytecode itself
+fn recursively(
+    bs: Vec<OptimisedBlock>,
+    f: &mut dyn FnMut(Vec<OptimisedBlock>) -> Vec<OptimisedBlock>
+)
+    -> Vec<OptimisedBlock>
+{
+    let bs = bs.into_iter().map(|b| match b {
+        OptimisedBlock::Loop(register, bs_, rejection) => OptimisedBlock::Loop(register, recursively(bs_, f), rejection),
+        OptimisedBlock::If(register, bs_) => OptimisedBlock::If(register, recursively(bs_, f)),
+        _ => b
+    }).collect();
+    f(bs)
+}
+
+// solves loops, turns loops that can only run once into if-blocks, and hoists what it can out of the rest
+// solving a loop needs its body grouped and reduced first, so this also does that to every loop body
+pub struct SolveLoops;
+
+impl Pass for SolveLoops {
+    fn name(&self) -> String { "loops".to_string() }
+
+    fn run(&self, bs: Vec<OptimisedBlock>, stats: &mut Statistics) -> Vec<OptimisedBlock> { optimise_loops(bs, stats) }
+}
+
+// groups neighbouring atomic effects together
+pub struct Merge;
+
+impl Pass for Merge {
+    fn name(&self) -> String { "merge".to_string() }
+
+    fn run(&self, bs: Vec<OptimisedBlock>, _stats: &mut Statistics) -> Vec<OptimisedBlock> { recursively(bs, &mut merge_all) }
+}
+
+// reduces every expression to its normal form
+pub struct Reduce;
+
+impl Pass for Reduce {
+    fn name(&self) -> String { "reduce".to_string() }
+
+    fn run(&self, bs: Vec<OptimisedBlock>, stats: &mut Statistics) -> Vec<OptimisedBlock> { recursively(bs, &mut |bs_| reduce_all(bs_, stats)) }
+}
+
+// applies moves lazily, leaving loops that don't move the head off it
+pub struct NormaliseOffsets;
+
+impl Pass for NormaliseOffsets {
+    fn name(&self) -> String { "offsets".to_string() }
+
+    fn run(&self, bs: Vec<OptimisedBlock>, _stats: &mut Statistics) -> Vec<OptimisedBlock> { normalise_offsets(bs) }
+}
+
+pub fn pass_by_name(
+    name: & str
+)
+    -> Option<Box<dyn Pass>>
+{
+    match name {
+        "loops" => Some(Box::new(SolveLoops)),
+        "merge" => Some(Box::new(Merge)),
+        "reduce" => Some(Box::new(Reduce)),
+        "offsets" => Some(Box::new(NormaliseOffsets)),
+        _ => None
+    }
+}
+
+pub struct PassManager<'a> {
+    pub passes: Vec<Box<dyn Pass + 'a>>,
+    // when set, the passes are repeated until they stop changing anything, or max_iterations is reached
+    pub fixpoint: bool,
+    pub max_iterations: usize
+}
+
+impl<'a> Default for PassManager<'a> {
+    fn default() -> PassManager<'a> {
+        PassManager::new()
+    }
+}
+
+impl<'a> PassManager<'a> {
+    pub fn new() -> PassManager<'a> {
+        PassManager{ passes: vec![], fixpoint: false, max_iterations: 16 }
+    }
+
+    // -O0 leaves the bytecode as converted
+    // -O1 groups and reduces
+    // -O2 (and above) also solves loops and applies moves lazily
+    pub fn with_level(level: u8) -> PassManager<'a> {
+        match level {
+            0 => PassManager::new(),
+            1 => PassManager::new().with(Merge).with(Reduce),
+            _ => PassManager::new().with(SolveLoops).with(Merge).with(Reduce).with(NormaliseOffsets)
+        }
+    }
+
+    // adds a pass after the others
+    pub fn with(mut self, pass: impl Pass + 'a) -> PassManager<'a> {
+        self.passes.push(Box::new(pass));
+        self
+    }
+
+    pub fn to_fixpoint(mut self) -> PassManager<'a> {
+        self.fixpoint = true;
+        self
+    }
+
+    pub fn names(&self) -> Vec<String> {
+        self.passes.iter().map(|pass| pass.name()).collect()
+    }
+
+    pub fn run(&self, bs: Vec<OptimisedBlock>) -> Vec<OptimisedBlock> {
+        self.run_with_statistics(bs, &mut Statistics::default())
+    }
+
+    pub fn run_with_statistics(&self, mut bs: Vec<OptimisedBlock>, stats: &mut Statistics) -> Vec<OptimisedBlock> {
+        for _ in 0..(if self.fixpoint { self.max_iterations } else { 1 }) {
+            let before = if self.fixpoint { Some(bs.clone()) } else { None };
+            // only the last time round says why the loops that are left weren't solved
+            stats.rejections.clear();
+            for pass in self.passes.iter() {
+                let blocks_before = count_blocks(& bs);
+                bs = pass.run(bs, stats);
+                stats.passes.push(PassReport{ name: pass.name(), blocks_before, blocks_after: count_blocks(& bs) });
+            }
+            if before.is_some_and(|before| before == bs) { break; }
+        }
+        stats.loops_left = count_loops(& bs);
+        stats.largest_expression = largest_expression(& bs);
+        bs
+    }
+}
+
+pub fn optimising_convert_with(
+    raw: Vec<BFRaw>,
+    manager: & PassManager
+)
+    -> Vec<OptimisedBlock>
+{
+    manager.run(convert(raw))
+}
+
+pub fn optimising_convert_with_statistics(
+    raw: Vec<BFRaw>,
+    manager: & PassManager
+)
+    -> (Vec<OptimisedBlock>, Statistics)
+{
+    let mut stats = Statistics{ raw_instructions: count_raw(& raw), ..Statistics::default() };
+    let bs = manager.run_with_statistics(convert(raw), &mut stats);
+    (bs, stats)
+}
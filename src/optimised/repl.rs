@@ -3,7 +3,7 @@ use std::future::Future;
 use nibbler::errors::show_error;
 use crate::{ parser::*, interpreter::* };
 
-use super::{ *, optimiser::*, interpreter::* };
+use super::{ *, interpreter::*, passes::*, statistics::*, termination::* };
 
 pub struct ConsoleInteractor<
     ReadLn: FnMut(String) -> String,
@@ -16,7 +16,8 @@ pub struct ConsoleInteractor<
     pub writeln: WriteLn,
     pub write_errln: WriteErrLn,
     pub display_help: DisplayHelp,
-    pub display_optimisation: DisplayOptimisation
+    pub display_optimisation: DisplayOptimisation,
+    pub statistics: Statistics // from the last input that was run
 }
 
 pub fn rep<
@@ -51,12 +52,19 @@ pub fn rep<
             Ok(BFCMD::Help) => (console_interactor.display_help)(),
             Ok(BFCMD::Find) => (console_interactor.writeln)(format!("head: #{}", ctx.index)),
             Ok(BFCMD::Move(x)) => ctx.index = x,
+            Ok(BFCMD::Stats) => (console_interactor.writeln)(format!("{}", console_interactor.statistics)),
             Err(err) => (console_interactor.write_errln)(format!("{}\n...whilst parsing instruction", show_error("".to_string(), & show_info, err))),
         };
     } else {
         match parse_program()(&mut iter) {
             Ok(is) => {
-                let optimised = optimising_convert(is);
+                let positions = instruction_positions(& s);
+                for TerminationWarning{ instruction, reason } in termination_warnings(& is) {
+                    let (line, index) = positions[instruction];
+                    (console_interactor.write_errln)(format!("warning at {line}:{index}: {reason}"));
+                }
+                let (optimised, statistics) = optimising_convert_with_statistics(is, & PassManager::with_level(2));
+                console_interactor.statistics = statistics;
                 (console_interactor.display_optimisation)(& optimised);
                 if !run_bfoptimised(ctx, optimised) {
                     (console_interactor.write_errln)("aborted non-halting loop".to_string())
@@ -80,7 +88,8 @@ pub struct AsyncConsoleInteractor<
     pub writeln: WriteLn,
     pub write_errln: WriteErrLn,
     pub display_help: DisplayHelp,
-    pub display_optimisation: DisplayOptimisation
+    pub display_optimisation: DisplayOptimisation,
+    pub statistics: Statistics // from the last input that was run
 }
 
 pub async fn async_rep<
@@ -117,12 +126,19 @@ pub async fn async_rep<
             Ok(BFCMD::Help) => (console_interactor.display_help)(),
             Ok(BFCMD::Find) => (console_interactor.writeln)(format!("head: #{}", ctx.index)),
             Ok(BFCMD::Move(x)) => ctx.index = x,
+            Ok(BFCMD::Stats) => (console_interactor.writeln)(format!("{}", console_interactor.statistics)),
             Err(err) => (console_interactor.write_errln)(format!("{}\n...whilst parsing instruction", show_error("".to_string(), & show_info, err))),
         };
     } else {
         match parse_program()(&mut iter) {
             Ok(is) => {
-                let optimised = optimising_convert(is);
+                let positions = instruction_positions(& s);
+                for TerminationWarning{ instruction, reason } in termination_warnings(& is) {
+                    let (line, index) = positions[instruction];
+                    (console_interactor.write_errln)(format!("warning at {line}:{index}: {reason}"));
+                }
+                let (optimised, statistics) = optimising_convert_with_statistics(is, & PassManager::with_level(2));
+                console_interactor.statistics = statistics;
                 (console_interactor.display_optimisation)(& optimised);
                 if !(async_run_bfoptimised(ctx, optimised).await) {
                     (console_interactor.write_errln)("aborted non-halting loop".to_string())
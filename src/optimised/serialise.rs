@@ -0,0 +1,447 @@
+use std::{fmt, collections::HashMap};
+
+use super::{ *, optimiser::LoopRejection };
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use super::super::optimiser::{ *, tests::* };
+
+    #[test]
+    fn round_trip() {
+        for s in [
+            "",
+            "[->+>++<<]",
+            ",[.>[-]++++++++++.<,]",
+            "[-->+>+<<],[->+<]>>>[-]<<<",
+            ">>[->[.-]<]<<<.",
+            ">+<[>-<[-]]>",
+            "[>+[<->-]<]",
+            "[->[->++<]>[-<+>]<<]"
+        ] {
+            let bs = optimising_convert(raw(s));
+            assert_eq!(decode(& encode(& bs)), Ok(bs), "{s} didn't round-trip");
+        }
+    }
+
+    #[test]
+    fn sharing() {
+        let mut big = Expr::new(ProcExpr::Reg(0));
+        for i in 1..50 {
+            big = Expr::new(ProcExpr::Add(big, Expr::new(ProcExpr::Mul(Expr::new(ProcExpr::Lit(i)), Expr::new(ProcExpr::Reg(i as i32))))));
+        }
+        let once = encode(& vec![OptimisedBlock::AtomicEffect(BTreeMap::from([(1, big.clone())]), 0)]);
+        let twice = encode(& vec![OptimisedBlock::AtomicEffect(BTreeMap::from([(1, big.clone()), (2, big.clone())]), 0)]);
+        assert!(twice.len() <= once.len() + 3, "{} bytes for one copy but {} for two", once.len(), twice.len());
+    }
+
+    #[test]
+    fn malformed() {
+        let bytes = encode(& optimising_convert(raw(",[.>[-]++++++++++.<,]")));
+        for length in 0..bytes.len() {
+            assert!(decode(& bytes[..length]).is_err(), "decoded a file cut to {length} bytes");
+        }
+        assert_eq!(decode(b"BFO"), Err(DecodeError::Truncated));
+        assert_eq!(decode(b"nope, not bytecode"), Err(DecodeError::BadMagic));
+
+        let mut flipped = bytes.clone();
+        flipped[7] ^= 1;
+        assert_eq!(decode(& flipped), Err(DecodeError::BadChecksum));
+
+        let mut newer = bytes.clone();
+        newer[4] = VERSION + 1;
+        assert_eq!(decode(& newer), Err(DecodeError::UnsupportedVersion(VERSION + 1)));
+
+        // a valid checksum over a payload that refers to an expression that doesn't exist
+        let mut forward = MAGIC.to_vec();
+        forward.push(VERSION);
+        forward.extend([1, 7, 0, 1, 2, 2, 0, 0]);
+        let checksum = adler32(& forward);
+        forward.extend(checksum.to_le_bytes());
+        assert_eq!(decode(& forward), Err(DecodeError::BadReference(0)));
+    }
+
+    // a file with its checksum, from the payload after the version
+    fn file(payload: & [u8]) -> Vec<u8> {
+        let mut bytes = MAGIC.to_vec();
+        bytes.push(VERSION);
+        bytes.extend(payload);
+        let checksum = adler32(& bytes);
+        bytes.extend(checksum.to_le_bytes());
+        bytes
+    }
+
+    #[test]
+    fn too_deep() {
+        // loops nested inside each other, each one a block count, a tag, its register and no rejection
+        let loops = |n: usize| file(& [vec![0], [1, BLOCK_LOOP, 0, REJECTION_NONE].repeat(n), vec![0]].concat());
+        assert!(decode(& loops(MAX_DEPTH)).is_ok());
+        assert_eq!(decode(& loops(MAX_DEPTH + 1)), Err(DecodeError::TooDeep(MAGIC.len() + 2 + 4 * (MAX_DEPTH + 1))));
+        assert!(matches!(decode(& loops(200_000)), Err(DecodeError::TooDeep(_))));
+
+        // (((~#0 == 0) == 0) ...), each expression reading the one before it
+        let is_zeros = |n: usize| {
+            let mut payload = vec![];
+            write_unsigned(&mut payload, n as u64 + 1);
+            payload.extend([EXPR_REG, 0]);
+            for i in 0..n {
+                payload.push(EXPR_IS_ZERO);
+                write_unsigned(&mut payload, i as u64);
+            }
+            payload.push(0);
+            file(& payload)
+        };
+        assert!(decode(& is_zeros(MAX_DEPTH - 1)).is_ok());
+        assert!(matches!(decode(& is_zeros(MAX_DEPTH)), Err(DecodeError::TooDeep(_))));
+    }
+}
+
+pub const MAGIC: [u8; 4] = *b"BFOB";
+pub const VERSION: u8 = 2;
+// how deeply loops and if-blocks, or expressions, may nest in a file, so that decoding it and running it can't overflow the stack
+pub const MAX_DEPTH: usize = 512;
+
+// file layout, after the magic and version:
+//   the number of expressions, then each one as a tag and its operands, children being indices of earlier expressions
+//   the number of blocks, then each one as a tag and its fields, with the bodies of loops and if-blocks nested inside
+//   and each loop's register followed by why it wasn't solved, as a tag (0 when the optimiser didn't try) and its fields
+//   an adler-32 checksum of everything before it, little-endian
+// numbers are LEB128, zigzagged first when they can be negative
+
+const EXPR_LIT: u8 = 0;
+const EXPR_REG: u8 = 1;
+const EXPR_ADD: u8 = 2;
+const EXPR_MUL: u8 = 3;
+const EXPR_INTO: u8 = 4;
+const EXPR_CHOOSE: u8 = 5;
+const EXPR_IF: u8 = 6;
+const EXPR_IS_ZERO: u8 = 7;
+
+const BLOCK_ASK: u8 = 0;
+const BLOCK_PUT: u8 = 1;
+const BLOCK_EFFECT: u8 = 2;
+const BLOCK_LOOP: u8 = 3;
+const BLOCK_IF: u8 = 4;
+
+const REJECTION_NONE: u8 = 0;
+const REJECTION_IO: u8 = 1;
+const REJECTION_INNER_LOOP: u8 = 2;
+const REJECTION_MOVES: u8 = 3;
+const REJECTION_COUNTER_UNTOUCHED: u8 = 4;
+const REJECTION_STEP_VARIES: u8 = 5;
+const REJECTION_UNSOLVABLE: u8 = 6;
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum DecodeError {
+    BadMagic,
+    UnsupportedVersion(u8),
+    BadChecksum,
+    Truncated,
+    BadTag(u8, usize), // and its offset
+    BadReference(usize), // an expression used before it was defined
+    Overflow(usize), // a number too big for its field, at this offset
+    TrailingBytes(usize), // from this offset
+    TooDeep(usize) // nested past MAX_DEPTH, at this offset
+}
+
+impl fmt::Display for DecodeError {
+
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            DecodeError::BadMagic => write!(f, "not optimised bytecode, the magic header is missing"),
+            DecodeError::UnsupportedVersion(version) => write!(f, "unsupported version {version}, expected {VERSION}"),
+            DecodeError::BadChecksum => write!(f, "checksum doesn't match, the file is corrupted"),
+            DecodeError::Truncated => write!(f, "file ends early"),
+            DecodeError::BadTag(tag, offset) => write!(f, "unknown tag {tag} at byte {offset}"),
+            DecodeError::BadReference(index) => write!(f, "expression {index} is used before it is defined"),
+            DecodeError::Overflow(offset) => write!(f, "number at byte {offset} is too big"),
+            DecodeError::TrailingBytes(offset) => write!(f, "unexpected bytes from byte {offset}"),
+            DecodeError::TooDeep(offset) => write!(f, "nested more than {MAX_DEPTH} deep at byte {offset}")
+        }
+    }
+}
+
+fn adler32(bytes: & [u8]) -> u32 {
+    let (mut a, mut b) = (1u32, 0u32);
+    for byte in bytes {
+        a = (a + *byte as u32) % 65521;
+        b = (b + a) % 65521;
+    }
+    (b << 16) | a
+}
+
+fn write_unsigned(out: &mut Vec<u8>, mut x: u64) -> () {
+    loop {
+        let byte = (x & 0x7f) as u8;
+        x >>= 7;
+        if x == 0 {
+            out.push(byte);
+            return;
+        }
+        out.push(byte | 0x80);
+    }
+}
+
+fn write_signed(out: &mut Vec<u8>, x: i32) -> () {
+    write_unsigned(out, ((x << 1) ^ (x >> 31)) as u32 as u64)
+}
+
+pub fn encode(
+    bs: & Vec<OptimisedBlock>
+)
+    -> Vec<u8>
+{
+    // every distinct expression once, after the expressions inside it
+    fn index_expr(
+        expr: & Expr,
+        indices: &mut HashMap<Expr, usize>,
+        table: &mut Vec<u8>
+    )
+        -> usize
+    {
+        if let Some(i) = indices.get(expr) { return *i; }
+        let mut node = vec![];
+        match expr.as_ref() {
+            ProcExpr::Lit(x) => { node.push(EXPR_LIT); node.push(*x); },
+            ProcExpr::Reg(r) => { node.push(EXPR_REG); write_signed(&mut node, *r); },
+            ProcExpr::Add(a, b) | ProcExpr::Mul(a, b) | ProcExpr::Into(a, b) => {
+                node.push(match expr.as_ref() { ProcExpr::Add(..) => EXPR_ADD, ProcExpr::Mul(..) => EXPR_MUL, _ => EXPR_INTO });
+                write_unsigned(&mut node, index_expr(a, indices, table) as u64);
+                write_unsigned(&mut node, index_expr(b, indices, table) as u64);
+            },
+            ProcExpr::Choose(a, k) => {
+                node.push(EXPR_CHOOSE);
+                write_unsigned(&mut node, index_expr(a, indices, table) as u64);
+                node.push(*k);
+            },
+            ProcExpr::If(c, a, b) => {
+                node.push(EXPR_IF);
+                write_unsigned(&mut node, index_expr(c, indices, table) as u64);
+                write_unsigned(&mut node, index_expr(a, indices, table) as u64);
+                write_unsigned(&mut node, index_expr(b, indices, table) as u64);
+            },
+            ProcExpr::IsZero(a) => {
+                node.push(EXPR_IS_ZERO);
+                write_unsigned(&mut node, index_expr(a, indices, table) as u64);
+            }
+        }
+        table.extend(node);
+        let i = indices.len();
+        indices.insert(expr.clone(), i);
+        i
+    }
+
+    fn encode_blocks(
+        bs: & Vec<OptimisedBlock>,
+        indices: &mut HashMap<Expr, usize>,
+        table: &mut Vec<u8>,
+        out: &mut Vec<u8>
+    )
+        -> ()
+    {
+        write_unsigned(out, bs.len() as u64);
+        for b in bs {
+            match b {
+                OptimisedBlock::Ask(register) => { out.push(BLOCK_ASK); write_signed(out, *register); },
+                OptimisedBlock::Put(register) => { out.push(BLOCK_PUT); write_signed(out, *register); },
+                OptimisedBlock::AtomicEffect(lines, effect) => {
+                    out.push(BLOCK_EFFECT);
+                    write_unsigned(out, lines.len() as u64);
+                    for (register, expr) in lines {
+                        write_signed(out, *register);
+                        write_unsigned(out, index_expr(expr, indices, table) as u64);
+                    }
+                    write_signed(out, *effect);
+                },
+                OptimisedBlock::Loop(register, bs_, rejection) => {
+                    out.push(BLOCK_LOOP);
+                    write_signed(out, *register);
+                    match rejection {
+                        None => out.push(REJECTION_NONE),
+                        Some(LoopRejection::Io(r)) => { out.push(REJECTION_IO); write_signed(out, *r); },
+                        Some(LoopRejection::InnerLoop(r)) => { out.push(REJECTION_INNER_LOOP); write_signed(out, *r); },
+                        Some(LoopRejection::Moves(effect)) => { out.push(REJECTION_MOVES); write_signed(out, *effect); },
+                        Some(LoopRejection::CounterUntouched) => out.push(REJECTION_COUNTER_UNTOUCHED),
+                        Some(LoopRejection::StepVaries(r, expr) | LoopRejection::Unsolvable(r, expr)) => {
+                            out.push(if let Some(LoopRejection::StepVaries(..)) = rejection { REJECTION_STEP_VARIES } else { REJECTION_UNSOLVABLE });
+                            write_signed(out, *r);
+                            write_unsigned(out, index_expr(expr, indices, table) as u64);
+                        }
+                    }
+                    encode_blocks(bs_, indices, table, out);
+                },
+                OptimisedBlock::If(register, bs_) => {
+                    out.push(BLOCK_IF);
+                    write_signed(out, *register);
+                    encode_blocks(bs_, indices, table, out);
+                }
+            }
+        }
+    }
+
+    let mut indices = HashMap::new();
+    let mut table = vec![];
+    let mut blocks = vec![];
+    encode_blocks(bs, &mut indices, &mut table, &mut blocks);
+
+    let mut out = MAGIC.to_vec();
+    out.push(VERSION);
+    write_unsigned(&mut out, indices.len() as u64);
+    out.extend(table);
+    out.extend(blocks);
+    let checksum = adler32(& out);
+    out.extend(checksum.to_le_bytes());
+    out
+}
+
+struct Reader<'a> {
+    bytes: &'a [u8],
+    offset: usize
+}
+
+impl<'a> Reader<'a> {
+    fn byte(&mut self) -> Result<u8, DecodeError> {
+        let byte = *self.bytes.get(self.offset).ok_or(DecodeError::Truncated)?;
+        self.offset += 1;
+        Ok(byte)
+    }
+
+    fn unsigned(&mut self) -> Result<u64, DecodeError> {
+        let start = self.offset;
+        let mut x = 0u64;
+        for shift in (0..64).step_by(7) {
+            let byte = self.byte()?;
+            x |= ((byte & 0x7f) as u64) << shift;
+            if byte & 0x80 == 0 { return Ok(x); }
+        }
+        Err(DecodeError::Overflow(start))
+    }
+
+    fn index(&mut self) -> Result<usize, DecodeError> {
+        let start = self.offset;
+        usize::try_from(self.unsigned()?).map_err(|_| DecodeError::Overflow(start))
+    }
+
+    fn signed(&mut self) -> Result<i32, DecodeError> {
+        let start = self.offset;
+        let x = u32::try_from(self.unsigned()?).map_err(|_| DecodeError::Overflow(start))?;
+        Ok(((x >> 1) as i32) ^ -((x & 1) as i32))
+    }
+}
+
+pub fn decode(
+    bytes: & [u8]
+)
+    -> Result<Vec<OptimisedBlock>, DecodeError>
+{
+    // expressions are kept with how deeply they nest
+    fn expr(
+        reader: &mut Reader,
+        table: & Vec<(Expr, usize)>
+    )
+        -> Result<Expr, DecodeError>
+    {
+        let i = reader.index()?;
+        table.get(i).map(|(expr, _)| expr.clone()).ok_or(DecodeError::BadReference(i))
+    }
+
+    fn rejection(
+        reader: &mut Reader,
+        table: & Vec<(Expr, usize)>
+    )
+        -> Result<Option<LoopRejection>, DecodeError>
+    {
+        let offset = reader.offset;
+        Ok(Some(match reader.byte()? {
+            REJECTION_NONE => return Ok(None),
+            REJECTION_IO => LoopRejection::Io(reader.signed()?),
+            REJECTION_INNER_LOOP => LoopRejection::InnerLoop(reader.signed()?),
+            REJECTION_MOVES => LoopRejection::Moves(reader.signed()?),
+            REJECTION_COUNTER_UNTOUCHED => LoopRejection::CounterUntouched,
+            REJECTION_STEP_VARIES => LoopRejection::StepVaries(reader.signed()?, expr(reader, table)?),
+            REJECTION_UNSOLVABLE => LoopRejection::Unsolvable(reader.signed()?, expr(reader, table)?),
+            tag => return Err(DecodeError::BadTag(tag, offset))
+        }))
+    }
+
+    fn decode_blocks(
+        reader: &mut Reader,
+        table: & Vec<(Expr, usize)>,
+        depth: usize
+    )
+        -> Result<Vec<OptimisedBlock>, DecodeError>
+    {
+        if depth > MAX_DEPTH { return Err(DecodeError::TooDeep(reader.offset)); }
+        let count = reader.index()?;
+        let mut bs = vec![];
+        for _ in 0..count {
+            let offset = reader.offset;
+            bs.push(match reader.byte()? {
+                BLOCK_ASK => OptimisedBlock::Ask(reader.signed()?),
+                BLOCK_PUT => OptimisedBlock::Put(reader.signed()?),
+                BLOCK_EFFECT => {
+                    let mut lines = BTreeMap::new();
+                    for _ in 0..reader.index()? {
+                        let register = reader.signed()?;
+                        lines.insert(register, expr(reader, table)?);
+                    }
+                    OptimisedBlock::AtomicEffect(lines, reader.signed()?)
+                },
+                BLOCK_LOOP => {
+                    let register = reader.signed()?;
+                    let rejection = rejection(reader, table)?;
+                    OptimisedBlock::Loop(register, decode_blocks(reader, table, depth + 1)?, rejection)
+                },
+                BLOCK_IF => OptimisedBlock::If(reader.signed()?, decode_blocks(reader, table, depth + 1)?),
+                tag => return Err(DecodeError::BadTag(tag, offset))
+            });
+        }
+        Ok(bs)
+    }
+
+    if bytes.len() < MAGIC.len() {
+        return Err(if MAGIC.starts_with(bytes) { DecodeError::Truncated } else { DecodeError::BadMagic });
+    }
+    if bytes[..MAGIC.len()] != MAGIC { return Err(DecodeError::BadMagic); }
+    let version = *bytes.get(MAGIC.len()).ok_or(DecodeError::Truncated)?;
+    if version != VERSION { return Err(DecodeError::UnsupportedVersion(version)); }
+    if bytes.len() < MAGIC.len() + 5 { return Err(DecodeError::Truncated); }
+
+    let (content, checksum) = bytes.split_at(bytes.len() - 4);
+    if adler32(content) != u32::from_le_bytes(checksum.try_into().unwrap()) {
+        return Err(DecodeError::BadChecksum);
+    }
+
+    let mut reader = Reader{ bytes: content, offset: MAGIC.len() + 1 };
+    let mut table: Vec<(Expr, usize)> = vec![];
+    for _ in 0..reader.index()? {
+        let offset = reader.offset;
+        let mut deepest = 0;
+        let node = {
+            let mut child = |reader: &mut Reader| -> Result<Expr, DecodeError> {
+                let i = reader.index()?;
+                let (expr, depth) = table.get(i).ok_or(DecodeError::BadReference(i))?;
+                deepest = deepest.max(*depth);
+                Ok(expr.clone())
+            };
+            match reader.byte()? {
+                EXPR_LIT => ProcExpr::Lit(reader.byte()?),
+                EXPR_REG => ProcExpr::Reg(reader.signed()?),
+                EXPR_ADD => ProcExpr::Add(child(&mut reader)?, child(&mut reader)?),
+                EXPR_MUL => ProcExpr::Mul(child(&mut reader)?, child(&mut reader)?),
+                EXPR_INTO => ProcExpr::Into(child(&mut reader)?, child(&mut reader)?),
+                EXPR_CHOOSE => ProcExpr::Choose(child(&mut reader)?, reader.byte()?),
+                EXPR_IF => ProcExpr::If(child(&mut reader)?, child(&mut reader)?, child(&mut reader)?),
+                EXPR_IS_ZERO => ProcExpr::IsZero(child(&mut reader)?),
+                tag => return Err(DecodeError::BadTag(tag, offset))
+            }
+        };
+        if deepest >= MAX_DEPTH { return Err(DecodeError::TooDeep(offset)); }
+        table.push((Expr::new(node), deepest + 1));
+    }
+    let bs = decode_blocks(&mut reader, & table, 0)?;
+    if reader.offset != content.len() {
+        return Err(DecodeError::TrailingBytes(reader.offset));
+    }
+    Ok(bs)
+}
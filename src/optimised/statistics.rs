@@ -0,0 +1,128 @@
+use std::fmt;
+
+use crate::*;
+use super::{ *, optimiser::* };
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use super::super::{ passes::*, optimiser::tests::* };
+
+    #[test]
+    fn counts() {
+        let (bs, stats) = optimising_convert_with_statistics(raw("+[>+<-],[.,]>[-]"), & PassManager::with_level(2));
+        assert_eq!(stats.raw_instructions, 16);
+        assert_eq!(stats.loops_solved, 2);
+        assert_eq!(stats.loops_to_ifs, 0);
+        assert_eq!(stats.loops_left, 1);
+        assert_eq!(stats.rejections, vec![LoopRejection::Io(0)]);
+        assert_eq!(stats.passes.len(), 4);
+        assert_eq!(stats.passes.first().unwrap().blocks_before, count_blocks(& convert(raw("+[>+<-],[.,]>[-]"))));
+        assert_eq!(stats.passes.last().unwrap().blocks_after, count_blocks(& bs));
+        assert!(stats.expressions_reduced > 0);
+        assert!(stats.largest_expression > 1);
+
+        let (_, stats) = optimising_convert_with_statistics(raw(">[-]<[>+<-]"), & PassManager::with_level(1));
+        assert_eq!(stats.loops_solved, 0);
+        assert_eq!(stats.loops_left, 2);
+        assert!(stats.rejections.is_empty());
+    }
+}
+
+// how much a single pass changed the bytecode
+#[derive(Debug, Clone, PartialEq)]
+pub struct PassReport {
+    pub name: String,
+    pub blocks_before: usize,
+    pub blocks_after: usize
+}
+
+// what the optimiser managed to do to a program
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct Statistics {
+    pub raw_instructions: usize,
+    pub passes: Vec<PassReport>,
+    pub loops_solved: usize,
+    pub loops_to_ifs: usize,
+    pub loops_left: usize,
+    pub expressions_reduced: usize,
+    pub largest_expression: usize, // in nodes
+    pub rejections: Vec<LoopRejection> // one for every loop that is still a loop, from the last run through the passes
+}
+
+pub fn count_raw(
+    raw: & Vec<BFRaw>
+)
+    -> usize
+{
+    raw.iter().map(|b| match b {
+        BFRaw::Loop(raw_) => 2 + count_raw(raw_),
+        _ => 1
+    }).sum()
+}
+
+// counts every block, including those inside loops and if-blocks
+pub fn count_blocks(
+    bs: & Vec<OptimisedBlock>
+)
+    -> usize
+{
+    bs.iter().map(|b| match b {
+        OptimisedBlock::Loop(_, bs_, _) | OptimisedBlock::If(_, bs_) => 1 + count_blocks(bs_),
+        _ => 1
+    }).sum()
+}
+
+pub fn count_loops(
+    bs: & Vec<OptimisedBlock>
+)
+    -> usize
+{
+    bs.iter().map(|b| match b {
+        OptimisedBlock::Loop(_, bs_, _) => 1 + count_loops(bs_),
+        OptimisedBlock::If(_, bs_) => count_loops(bs_),
+        _ => 0
+    }).sum()
+}
+
+pub fn expression_size(
+    expr: & ProcExpr
+)
+    -> usize
+{
+    match expr {
+        ProcExpr::Lit(_) | ProcExpr::Reg(_) => 1,
+        ProcExpr::Add(a, b) | ProcExpr::Mul(a, b) | ProcExpr::Into(a, b) => 1 + expression_size(a) + expression_size(b),
+        ProcExpr::Choose(a, _) | ProcExpr::IsZero(a) => 1 + expression_size(a),
+        ProcExpr::If(c, a, b) => 1 + expression_size(c) + expression_size(a) + expression_size(b)
+    }
+}
+
+pub fn largest_expression(
+    bs: & Vec<OptimisedBlock>
+)
+    -> usize
+{
+    bs.iter().map(|b| match b {
+        OptimisedBlock::AtomicEffect(lines, _) => lines.values().map(|expr| expression_size(expr)).max().unwrap_or(0),
+        OptimisedBlock::Loop(_, bs_, _) | OptimisedBlock::If(_, bs_) => largest_expression(bs_),
+        _ => 0
+    }).max().unwrap_or(0)
+}
+
+impl fmt::Display for Statistics {
+
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        writeln!(f, "raw instructions: {}", self.raw_instructions)?;
+        for PassReport{ name, blocks_before, blocks_after } in self.passes.iter() {
+            writeln!(f, "pass {name}: {blocks_before} -> {blocks_after} blocks")?;
+        }
+        writeln!(f, "loops: {} solved, {} made into ifs, {} left", self.loops_solved, self.loops_to_ifs, self.loops_left)?;
+        writeln!(f, "expressions reduced: {}", self.expressions_reduced)?;
+        write!(f, "largest expression: {} nodes", self.largest_expression)?;
+        for rejection in self.rejections.iter() {
+            write!(f, "\nloop left: {rejection}")?;
+        }
+        Ok(())
+    }
+}
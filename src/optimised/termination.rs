@@ -0,0 +1,137 @@
+use std::fmt;
+
+use crate::*;
+use super::{ *, optimiser::*, statistics::* };
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use super::super::optimiser::tests::*;
+
+    #[test]
+    fn warnings() {
+        let warning = |instruction, reason| TerminationWarning{ instruction, reason };
+        assert_eq!(termination_warnings(& raw("+[]")), vec![warning(1, NonTermination::Always)]);
+        assert_eq!(termination_warnings(& raw("[>+<]")), vec![warning(0, NonTermination::Always)]);
+        assert_eq!(termination_warnings(& raw(",[++]")), vec![warning(1, NonTermination::UnlessMultipleOf(2))]);
+        assert_eq!(termination_warnings(& raw("[-]>[----]")), vec![warning(4, NonTermination::UnlessMultipleOf(4))]);
+        assert_eq!(termination_warnings(& raw("[->[]<]")), vec![warning(3, NonTermination::Always)]);
+        assert!(termination_warnings(& raw("[->+<],[.,]>[---]")).is_empty());
+        // each body is only optimised once, however deeply the loops nest
+        let deep = "[->".repeat(300) + "+" + & "<]".repeat(300) + "+[]";
+        assert_eq!(termination_warnings(& raw(& deep)), vec![warning(1502, NonTermination::Always)]);
+
+        assert_eq!(instruction_positions("+ hi\n[]"), vec![(0, 1), (1, 1), (1, 2)]);
+    }
+}
+
+// how a loop can fail to ever finish
+#[derive(Debug, Clone, PartialEq)]
+pub enum NonTermination {
+    Always, // once it is entered, as ~#0 never changes
+    UnlessMultipleOf(u8) // when ~#0 doesn't start as a multiple of this, as it is only ever stepped by multiples of it
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct TerminationWarning {
+    pub instruction: usize, // of the loop's '[', counting every instruction from the start of the program
+    pub reason: NonTermination
+}
+
+impl fmt::Display for NonTermination {
+
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            NonTermination::Always => write!(f, "loop never ends once entered"),
+            NonTermination::UnlessMultipleOf(k) => write!(f, "loop never ends unless its cell is a multiple of {k}")
+        }
+    }
+}
+
+// whether a loop can finish, from its body as the optimiser leaves it
+fn never_terminates(
+    bs: & [OptimisedBlock]
+)
+    -> Option<NonTermination>
+{
+    let step = match bs {
+        [] => Expr::new(ProcExpr::Lit(0)),
+        [OptimisedBlock::AtomicEffect(lines, 0)] => reduce(Expr::new(ProcExpr::Add(
+            Expr::new(ProcExpr::Reg(0)),
+            Expr::new(ProcExpr::Mul(
+                Expr::new(ProcExpr::Lit(255)),
+                lines.get(& 0).cloned().unwrap_or(Expr::new(ProcExpr::Reg(0)))
+            ))
+        ))),
+        _ => return None
+    };
+    match step.as_ref() {
+        ProcExpr::Lit(0) => Some(NonTermination::Always),
+        ProcExpr::Lit(k) if k % 2 == 0 => Some(NonTermination::UnlessMultipleOf(1 << k.trailing_zeros())),
+        _ => None
+    }
+}
+
+// loops that can't finish for some, or all, of the values their cell could have when they're reached
+pub fn termination_warnings(
+    raw: & Vec<BFRaw>
+)
+    -> Vec<TerminationWarning>
+{
+    // optimises the program innermost loop first, as the optimiser would, checking each loop's body on the way
+    // every loop is given to its parent already optimised, so each body is only optimised once
+    fn go(
+        raw: & Vec<BFRaw>,
+        instruction: &mut usize,
+        warnings: &mut Vec<TerminationWarning>,
+        stats: &mut Statistics
+    )
+        -> Vec<OptimisedBlock>
+    {
+        let mut bs = vec![];
+        let mut straight = vec![];
+        for b in raw {
+            if let BFRaw::Loop(body) = b {
+                bs.extend(convert(std::mem::take(&mut straight)));
+                let at = *instruction;
+                *instruction += 1;
+                let body = go(body, instruction, warnings, stats);
+                if let Some(reason) = never_terminates(& body) {
+                    warnings.push(TerminationWarning{ instruction: at, reason });
+                }
+                bs.push(optimise_loop(body, stats));
+            } else {
+                straight.push(b.clone());
+            }
+            *instruction += 1;
+        }
+        bs.extend(convert(straight));
+        reduce_all(merge_all(bs), stats)
+    }
+
+    let mut warnings = vec![];
+    go(raw, &mut 0, &mut warnings, &mut Statistics::default());
+    warnings
+}
+
+// the line and index of every instruction in the source, counted the same way as the parser does
+pub fn instruction_positions(
+    s: & str
+)
+    -> Vec<(usize, usize)>
+{
+    let mut positions = vec![];
+    let (mut line, mut index) = (0, 0);
+    for c in s.chars() {
+        if c == '\n' {
+            line += 1;
+            index = 0;
+            continue;
+        }
+        index += 1;
+        if "<>+-,.[]".contains(c) {
+            positions.push((line, index));
+        }
+    }
+    positions
+}
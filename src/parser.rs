@@ -230,7 +230,8 @@ pub enum BFCMD {
     Clear,
     Help,
     Find,
-    Move(i32)
+    Move(i32),
+    Stats
 }
 
 pub const fn parse_exit<Iter: Iterator<Item = char>>()
@@ -317,6 +318,18 @@ pub const fn parse_move<Iter: Iterator<Item = char>>()
     )(iter)
 }
 
+pub const fn parse_stats<Iter: Iterator<Item = char>>()
+    -> parser![TextIter<Iter>, ParseError<TextInfo>, BFCMD]
+{
+    |iter| fmap(
+        |_| BFCMD::Stats,
+        expect::<TextIter<Iter>, ParseError<TextInfo>, 2>(
+            [':', 's'],
+            msg!("':s'")
+        )
+    )(iter)
+}
+
 pub const fn parse_bfcmd<Iter: Iterator<Item = char> + Clone>()
     -> parser![TextIter<Iter>, ParseError<TextInfo>, BFCMD]
 {
@@ -328,6 +341,7 @@ pub const fn parse_bfcmd<Iter: Iterator<Item = char> + Clone>()
             silence(try_parse(parse_help())),
             silence(try_parse(parse_find())),
             try_parse(parse_move()),
+            silence(try_parse(parse_stats())),
             fail(msg!("command (e.g. ':h')"))
         )
     )(iter)
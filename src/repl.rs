@@ -46,6 +46,7 @@ pub fn rep<
             Ok(BFCMD::Help) => (console_interactor.display_help)(),
             Ok(BFCMD::Find) => (console_interactor.writeln)(format!("head: #{}", ctx.index)),
             Ok(BFCMD::Move(x)) => ctx.index = x,
+            Ok(BFCMD::Stats) => (console_interactor.write_errln)("nothing is optimised, so there are no statistics".to_string()),
             Err(err) => (console_interactor.write_errln)(format!("{}\n...whilst parsing instruction", show_error("".to_string(), & show_info, err))),
         };
     } else {
@@ -103,6 +104,7 @@ pub async fn async_rep<
             Ok(BFCMD::Help) => (console_interactor.display_help)(),
             Ok(BFCMD::Find) => (console_interactor.writeln)(format!("head: #{}", ctx.index)),
             Ok(BFCMD::Move(x)) => ctx.index = x,
+            Ok(BFCMD::Stats) => (console_interactor.write_errln)("nothing is optimised, so there are no statistics".to_string()),
             Err(err) => (console_interactor.write_errln)(format!("{}\n...whilst parsing instruction", show_error("".to_string(), & show_info, err))),
         };
     } else {